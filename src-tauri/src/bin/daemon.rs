@@ -0,0 +1,215 @@
+//! Syncs the configured folders without the tray and windows, e.g. on build
+//! servers and in containers. Reads the same config.json as the app.
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use tokio::sync::Notify;
+
+use file_transfer::{
+    control::Controller,
+    synchronizer::{
+        self,
+        api::HttpBackend,
+        backend::{BackendResult, RemoteBackend, Tokens},
+        tls, EventSink,
+    },
+    types::{Config, Profile, Token},
+    CONFIG,
+};
+
+const TOKEN_EXPIRES: u64 = 15 * 60;
+
+struct Log;
+
+impl EventSink for Log {
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        match event {
+            "profile_state" => println!("connection of {}: {}", payload["name"], payload["state"]),
+            "transfer" if payload["state"] == "completed" => {
+                println!("{} completed: {}", payload["type"], payload["path"])
+            }
+            _ => {}
+        }
+    }
+}
+
+fn load_config(data_dir: &std::path::Path) -> Result<Config, String> {
+    let config_path = data_dir.join("config.json");
+    let config = std::fs::read_to_string(&config_path)
+        .map_err(|e| format!("can't read {}: {e}", config_path.display()))?;
+    let config: Config = serde_json::from_str(&config)
+        .map_err(|e| format!("invalid {}: {e}", config_path.display()))?;
+    if !config.is_configured || syncing(&config).is_empty() {
+        return Err("set up the server, folder and login in the app first".to_string());
+    }
+    for profile in syncing(&config) {
+        tls::connector(&profile).map_err(|e| format!("{}: {e}", profile.name))?;
+    }
+    Ok(config)
+}
+
+/// The active profiles with a login to sync with.
+fn syncing(config: &Config) -> Vec<Profile> {
+    config
+        .profiles()
+        .into_iter()
+        .filter(|profile| {
+            profile.active && profile.username.is_some() && profile.password.is_some()
+        })
+        .collect()
+}
+
+/// Lets `ft` wake the login loops up early.
+#[derive(Default)]
+struct Daemon {
+    wake: Notify,
+    logged_out: AtomicBool,
+}
+
+#[async_trait]
+impl Controller for Daemon {
+    async fn sync(&self) {
+        self.logged_out.store(false, Ordering::SeqCst);
+        self.wake.notify_waiters();
+    }
+
+    async fn set_paused(&self, paused: bool) {
+        if paused {
+            synchronizer::pause();
+        } else {
+            synchronizer::resume();
+        }
+        if let Err(e) = file_transfer::save_config(&file_transfer::data_dir()) {
+            println!("Failed to save the config: {e}");
+        }
+    }
+
+    async fn logout(&self) {
+        self.logged_out.store(true, Ordering::SeqCst);
+        {
+            let mut config = CONFIG.lock().unwrap();
+            for mut profile in config.profiles() {
+                profile.token.take();
+                profile.refresh_token.take();
+                config.set_profile(profile);
+            }
+        }
+        synchronizer::stop();
+        self.wake.notify_waiters();
+    }
+}
+
+/// Refreshes the session when possible, logs in again otherwise.
+async fn authenticate(profile: &Profile, refresh_token: Option<&str>) -> BackendResult<Tokens> {
+    let backend = HttpBackend::from_profile(profile);
+    if let Some(refresh_token) = refresh_token {
+        if let Ok(tokens) = backend.refresh_auth(refresh_token).await {
+            return Ok(tokens);
+        }
+    }
+    backend
+        .auth(
+            profile.username.as_deref().unwrap(),
+            profile.password.as_deref().unwrap(),
+        )
+        .await
+}
+
+/// Keeps the synchronizers of `profile` running, handing them a fresh token
+/// before the old one expires, like the app does. `ft sync` starts them over.
+/// After `ft logout` it waits for `ft sync` to log in again.
+async fn run(data_dir: PathBuf, daemon: Arc<Daemon>, profile: Profile) {
+    let name = profile.name;
+    let mut refresh_token = None;
+    let mut restart = true;
+    loop {
+        if daemon.logged_out.load(Ordering::SeqCst) {
+            refresh_token = None;
+            daemon.wake.notified().await;
+            continue;
+        }
+        let Some(profile) = CONFIG.lock().unwrap().profile(&name) else {
+            return;
+        };
+        let tokens = match authenticate(&profile, refresh_token.as_deref()).await {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                println!("Failed to log in to {name}: {e}");
+                refresh_token = None;
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(30)) => {},
+                    _ = daemon.wake.notified() => {},
+                }
+                continue;
+            }
+        };
+        refresh_token = Some(tokens.refresh_token.clone());
+        let mut profile = profile;
+        profile.token.replace(Token {
+            value: tokens.token.clone(),
+            created_at: SystemTime::now(),
+        });
+        profile.refresh_token.replace(Token {
+            value: tokens.refresh_token,
+            created_at: SystemTime::now(),
+        });
+        CONFIG.lock().unwrap().set_profile(profile.clone());
+        if restart || !synchronizer::set_token(&name, &tokens.token) {
+            synchronizer::start(
+                Arc::new(Log),
+                data_dir.clone(),
+                &name,
+                Arc::new(HttpBackend::from_profile(&profile)),
+            );
+        }
+        restart = tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(TOKEN_EXPIRES)) => false,
+            _ = daemon.wake.notified() => true,
+        };
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let data_dir = file_transfer::data_dir();
+    let config = match load_config(&data_dir) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let profiles = syncing(&config);
+    for profile in &profiles {
+        for pair in &profile.sync_pairs {
+            println!("Syncing {} with {}", pair.local_path, profile.server_url);
+        }
+    }
+    *CONFIG.lock().unwrap() = config;
+    if CONFIG.lock().unwrap().paused {
+        println!("Sync is paused, `ft resume` picks it up again");
+        synchronizer::pause();
+    }
+    for pair in CONFIG.lock().unwrap().all_pairs() {
+        let pair_dir = synchronizer::pair_data_dir(&data_dir, &pair.id);
+        synchronizer::clean_temp_dir(&pair_dir.join("temp"));
+    }
+
+    let daemon = Arc::new(Daemon::default());
+    #[cfg(unix)]
+    tokio::spawn(file_transfer::control::serve(
+        data_dir.join(file_transfer::control::SOCKET_FILE),
+        daemon.clone(),
+    ));
+    let runs = profiles
+        .into_iter()
+        .map(|profile| run(data_dir.clone(), daemon.clone(), profile));
+    tokio::select! {
+        _ = join_all(runs) => {},
+        _ = tokio::signal::ctrl_c() => synchronizer::shutdown().await,
+    }
+}
@@ -0,0 +1,92 @@
+//! Controls the running app or daemon from the command line.
+const USAGE: &str = "usage: ft <status|sync|pause|resume|transfers|logout>";
+
+#[cfg(unix)]
+#[tokio::main]
+async fn main() {
+    use file_transfer::control::{self, Command, Status, SOCKET_FILE};
+    use file_transfer::types::{ConnectionState, Transfer, TransferState};
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match args.as_slice() {
+        [name] => Command::parse(name),
+        _ => None,
+    };
+    let Some(command) = command else {
+        eprintln!("{USAGE}");
+        std::process::exit(2);
+    };
+    let socket_path = file_transfer::data_dir().join(SOCKET_FILE);
+    let reply = match control::send(&socket_path, command).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    match command {
+        Command::Status => {
+            let status: Status = serde_json::from_value(reply).unwrap();
+            let state = |connection| match connection {
+                _ if status.paused => "paused",
+                ConnectionState::Connecting => "connecting",
+                ConnectionState::Connected => "connected",
+                ConnectionState::Reconnecting => "reconnecting",
+                ConnectionState::Offline => "offline",
+            };
+            println!("state:     {}", state(status.connection));
+            for profile in &status.profiles {
+                if !profile.active {
+                    println!("profile:   {} (inactive)", profile.name);
+                    continue;
+                }
+                println!(
+                    "profile:   {} ({})",
+                    profile.name,
+                    state(profile.connection)
+                );
+                println!("  server:  {}", profile.server_url);
+                match &profile.username {
+                    Some(username) if profile.logged_in => println!("  user:    {username}"),
+                    _ => println!("  user:    logged out"),
+                }
+                for folder in &profile.folders {
+                    println!("  folder:  {} ({})", folder.path, state(folder.connection));
+                }
+            }
+            println!("transfers: {} active", status.active_transfers);
+        }
+        Command::Transfers => {
+            let transfers: Vec<Transfer> = serde_json::from_value(reply).unwrap();
+            for transfer in transfers {
+                let (state, reason) = match &transfer.state {
+                    TransferState::Queued => ("queued", String::new()),
+                    TransferState::Active => ("active", String::new()),
+                    TransferState::Retrying { attempt } => {
+                        ("retrying", format!(" (after attempt {attempt})"))
+                    }
+                    TransferState::Failed { reason } => ("failed", format!(" ({reason})")),
+                    TransferState::Cancelled => ("cancelled", String::new()),
+                    TransferState::Completed => ("completed", String::new()),
+                };
+                println!(
+                    "{:<8} {:<9} {:>3}% {}{reason}",
+                    serde_json::to_value(&transfer.r#type)
+                        .unwrap()
+                        .as_str()
+                        .unwrap(),
+                    state,
+                    transfer.progress,
+                    transfer.path
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("{USAGE}\nft needs Unix domain sockets, which this platform doesn't have");
+    std::process::exit(1);
+}
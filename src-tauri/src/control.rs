@@ -0,0 +1,205 @@
+//! Local control endpoint the `ft` command talks to. Requests and replies are
+//! JSON lines over a Unix domain socket in the data dir.
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::synchronizer::{self, CONNECTION_STATE, PAIR_STATES, TRANSFERS};
+use crate::types::{ConnectionState, Transfer};
+use crate::CONFIG;
+
+pub const SOCKET_FILE: &str = "control.sock";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Command {
+    Status,
+    Sync,
+    Pause,
+    Resume,
+    Transfers,
+    Logout,
+}
+
+impl Command {
+    pub fn parse(name: &str) -> Option<Self> {
+        serde_json::from_value(Value::String(name.to_string())).ok()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub connection: ConnectionState,
+    pub paused: bool,
+    pub profiles: Vec<ProfileStatus>,
+    pub active_transfers: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProfileStatus {
+    pub name: String,
+    pub server_url: String,
+    pub active: bool,
+    pub logged_in: bool,
+    pub username: Option<String>,
+    pub connection: ConnectionState,
+    pub folders: Vec<FolderStatus>,
+}
+
+/// A synced folder, offline until its synchronizer first connects.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FolderStatus {
+    pub path: String,
+    pub connection: ConnectionState,
+}
+
+pub type Reply = Result<Value, String>;
+
+/// What the app and the daemon do differently, the rest is answered here.
+#[async_trait]
+pub trait Controller: Send + Sync {
+    /// Restarts the synchronizer, like the sync button.
+    async fn sync(&self);
+    /// Pauses or resumes sync and remembers it across restarts.
+    async fn set_paused(&self, paused: bool);
+    async fn logout(&self);
+}
+
+fn status() -> Status {
+    let config = CONFIG.lock().unwrap().clone();
+    let states = PAIR_STATES.lock().unwrap().clone();
+    let profiles = config
+        .profiles()
+        .into_iter()
+        .map(|profile| ProfileStatus {
+            connection: synchronizer::profile_state(&profile),
+            folders: profile
+                .sync_pairs
+                .iter()
+                .map(|pair| FolderStatus {
+                    connection: states
+                        .get(&pair.id)
+                        .copied()
+                        .unwrap_or(ConnectionState::Offline),
+                    path: pair.local_path.clone(),
+                })
+                .collect(),
+            logged_in: profile.token.is_some(),
+            name: profile.name,
+            server_url: profile.server_url,
+            active: profile.active,
+            username: profile.username,
+        })
+        .collect();
+    Status {
+        connection: *CONNECTION_STATE.lock().unwrap(),
+        paused: synchronizer::is_paused(),
+        profiles,
+        active_transfers: TRANSFERS
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|transfer| !transfer.state.is_finished())
+            .count(),
+    }
+}
+
+async fn handle(controller: &dyn Controller, command: Command) -> Reply {
+    match command {
+        Command::Status => Ok(serde_json::to_value(status()).unwrap()),
+        Command::Transfers => {
+            let transfers: Vec<Transfer> = TRANSFERS.lock().unwrap().values().cloned().collect();
+            Ok(serde_json::to_value(transfers).unwrap())
+        }
+        Command::Sync => {
+            if synchronizer::is_paused() {
+                return Err("sync is paused, run `ft resume`".to_string());
+            }
+            controller.sync().await;
+            Ok(Value::Null)
+        }
+        Command::Pause => {
+            controller.set_paused(true).await;
+            Ok(Value::Null)
+        }
+        Command::Resume => {
+            controller.set_paused(false).await;
+            Ok(Value::Null)
+        }
+        Command::Logout => {
+            controller.logout().await;
+            Ok(Value::Null)
+        }
+    }
+}
+
+/// Answers commands until the process exits. Leaves the socket alone if
+/// another instance is already listening on it.
+#[cfg(unix)]
+pub async fn serve(socket_path: std::path::PathBuf, controller: std::sync::Arc<dyn Controller>) {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+
+    if UnixStream::connect(&socket_path).await.is_ok() {
+        println!("Another instance is listening on {}", socket_path.display());
+        return;
+    }
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = match UnixListener::bind(&socket_path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Failed to listen on {}: {e}", socket_path.display());
+            return;
+        }
+    };
+    // only the user running the client may control it
+    let _ = std::fs::set_permissions(&socket_path, std::fs::Permissions::from_mode(0o600));
+
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let controller = controller.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply = match serde_json::from_str::<Command>(&line) {
+                    Ok(command) => handle(controller.as_ref(), command).await,
+                    Err(e) => Err(format!("invalid command: {e}")),
+                };
+                let mut reply = serde_json::to_string(&reply).unwrap();
+                reply.push('\n');
+                if writer.write_all(reply.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Sends one command to the running app or daemon.
+#[cfg(unix)]
+pub async fn send(socket_path: &std::path::Path, command: Command) -> Reply {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    let stream = UnixStream::connect(socket_path)
+        .await
+        .map_err(|e| format!("can't reach the client at {}: {e}", socket_path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut request = serde_json::to_string(&command).unwrap();
+    request.push('\n');
+    writer
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    let reply = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("the client closed the connection")?;
+    serde_json::from_str(&reply).map_err(|e| format!("invalid reply: {e}"))?
+}
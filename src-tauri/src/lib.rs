@@ -0,0 +1,31 @@
+//! The sync engine, shared by the tray app and the headless daemon.
+pub mod control;
+pub mod synchronizer;
+pub mod types;
+
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+
+use types::Config;
+
+/// Must match the identifier in tauri.conf.json, the app keeps its data there.
+const IDENTIFIER: &str = "com.tauri.dev";
+
+pub static CONFIG: LazyLock<Mutex<Config>> = LazyLock::new(|| Mutex::new(Config::default()));
+
+/// The app's data dir, for the daemon and `ft` running next to it. Can be
+/// overridden with FILE_TRANSFER_DATA_DIR.
+pub fn data_dir() -> PathBuf {
+    std::env::var_os("FILE_TRANSFER_DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| dirs::data_dir().unwrap().join(IDENTIFIER))
+}
+
+/// Writes the current config to config.json in `data_dir`.
+pub fn save_config(data_dir: &Path) -> std::io::Result<()> {
+    let config = CONFIG.lock().unwrap().clone();
+    std::fs::write(
+        data_dir.join("config.json"),
+        serde_json::to_string_pretty(&config).unwrap(),
+    )
+}
@@ -1,63 +1,140 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-mod synchronizer;
 mod token;
-mod types;
 mod windows;
-use std::{
-    collections::HashMap,
-    path::Path,
-    sync::{LazyLock, Mutex},
-    time::SystemTime,
-};
+use std::{collections::HashMap, path::Path, sync::Arc, time::SystemTime};
 
-use reqwest::Client;
-use serde_json::json;
 use tauri::{
     AppHandle, CustomMenuItem, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu,
     SystemTrayMenuItem,
 };
 
-use crate::{
-    synchronizer::{IS_CONNECTED, TRANSFERS},
-    types::{Config, Token, TransferState},
+use file_transfer::{
+    control,
+    synchronizer::{
+        self,
+        api::HttpBackend,
+        backend::{BackendError, RemoteBackend},
+        journal, throttle, tls, EventSink, CONNECTION_STATE, IS_CONNECTED, PAIR_STATES, TRANSFERS,
+    },
+    types::{self, Config, ConnectionState, Profile, ProfileState, Token},
+    CONFIG,
 };
-static CONFIG: LazyLock<Mutex<Config>> = LazyLock::new(|| Mutex::new(Config::default()));
 
-fn create_tray_menu() -> SystemTrayMenu {
+/// A status item for each profile, which opens the settings, then the rest.
+fn create_tray_menu(profiles: &[Profile]) -> SystemTrayMenu {
     let quit = CustomMenuItem::new("quit".to_string(), "Quit");
     let show = CustomMenuItem::new("show".to_string(), "Show");
     let settings = CustomMenuItem::new("settings".to_string(), "Configuración");
-    SystemTrayMenu::new()
-        .add_item(show)
+    let pause_title = if synchronizer::is_paused() {
+        "Resume sync"
+    } else {
+        "Pause sync"
+    };
+    let pause = CustomMenuItem::new("pause".to_string(), pause_title);
+    let mut menu = SystemTrayMenu::new();
+    for profile in profiles {
+        let state = synchronizer::profile_state(profile);
+        let title = profile_tray_title(profile, state);
+        menu = menu.add_item(CustomMenuItem::new(profile_tray_id(&profile.name), title));
+    }
+    if !profiles.is_empty() {
+        menu = menu.add_native_item(SystemTrayMenuItem::Separator);
+    }
+    menu.add_item(show)
         .add_item(settings)
+        .add_item(pause)
         .add_native_item(SystemTrayMenuItem::Separator)
         .add_item(quit)
 }
 
+fn profile_tray_id(name: &str) -> String {
+    format!("profile:{name}")
+}
+
+/// "Client: connected", or why it isn't syncing.
+fn profile_tray_title(profile: &Profile, state: ConnectionState) -> String {
+    let status = if !profile.active {
+        "inactive"
+    } else if profile.token.is_none() {
+        "logged out"
+    } else if synchronizer::is_paused() {
+        "paused"
+    } else {
+        match state {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Reconnecting => "reconnecting",
+            ConnectionState::Offline => "offline",
+        }
+    };
+    format!("{}: {status}", profile.name)
+}
+
+/// Rebuilds the tray menu, for when profiles were added, removed or renamed,
+/// or logged in or out.
+fn update_tray(app: &AppHandle) {
+    let profiles = CONFIG.lock().unwrap().profiles();
+    let _ = app.tray_handle().set_menu(create_tray_menu(&profiles));
+}
+
+/// Sends the synchronizer's events to the windows, and keeps the profile
+/// items of the tray up to date.
+struct AppEvents(AppHandle);
+
+impl EventSink for AppEvents {
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        if event == "profile_state" {
+            let state: ProfileState = serde_json::from_value(payload.clone()).unwrap();
+            let profile = CONFIG.lock().unwrap().profile(&state.name);
+            if let Some(profile) = profile {
+                let title = profile_tray_title(&profile, state.state);
+                let item = self.0.tray_handle().get_item(&profile_tray_id(&state.name));
+                let _ = item.set_title(title);
+            }
+        }
+        EventSink::emit(&self.0, event, payload);
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let system_tray = SystemTray::new().with_menu(create_tray_menu());
+    let system_tray = SystemTray::new().with_menu(create_tray_menu(&[]));
     tauri::Builder::default()
         .setup(|app| {
             let app_dir = app.path_resolver().app_data_dir().unwrap();
-            let temp_dir = app_dir.join("temp");
             std::fs::create_dir_all(&app_dir).unwrap();
-            let _ = std::fs::remove_dir_all(&temp_dir);
+            #[cfg(unix)]
+            tokio::spawn(control::serve(
+                app_dir.join(control::SOCKET_FILE),
+                Arc::new(AppControl(app.handle())),
+            ));
             set_config(app.handle());
+            for pair in CONFIG.lock().unwrap().all_pairs() {
+                let pair_dir = synchronizer::pair_data_dir(&app_dir, &pair.id);
+                synchronizer::clean_temp_dir(&pair_dir.join("temp"));
+            }
+            if CONFIG.lock().unwrap().paused {
+                set_paused(&app.handle(), true);
+            }
+            update_tray(&app.handle());
             let config = CONFIG.lock().unwrap().clone();
+            let main_profile = config.main_profile();
             if !config.is_configured {
                 windows::open_initial_configuration_window(app.handle());
-            } else if config.username.is_none()
-                || config.password.is_none()
-                || config.token.is_none()
-                || config.refresh_token.is_none()
-            {
-                windows::open_login_window(app.handle());
+                return Ok(());
+            }
+            if main_profile.active && !main_profile.is_logged_in() {
+                windows::open_login_window(app.handle(), None);
             } else {
                 windows::open_main_window(app.handle());
-                synchronizer::start(app.handle());
-                tokio::spawn(token::watch_tokens(app.handle()));
+            }
+            // the other profiles sync while the main one logs in
+            for profile in config.profiles() {
+                if profile.active && profile.is_logged_in() {
+                    start_sync(app.handle(), &profile.name);
+                    tokio::spawn(token::watch_tokens(app.handle(), profile.name));
+                }
             }
             Ok(())
         })
@@ -74,7 +151,16 @@ async fn main() {
             get_config,
             open_folder,
             force_sync,
-            check_connection
+            check_connection,
+            get_connection_state,
+            get_pending_operations,
+            retry_transfer,
+            pause_sync,
+            resume_sync,
+            is_sync_paused,
+            get_remote_folders,
+            get_pair_states,
+            get_profile_states
         ])
         .system_tray(system_tray)
         .on_system_tray_event(|app_handle, event| match event {
@@ -83,7 +169,11 @@ async fn main() {
             }
             SystemTrayEvent::MenuItemClick { id, .. } => match id.as_str() {
                 "quit" => {
-                    std::process::exit(0);
+                    // lets transfers finish their current chunk first
+                    tauri::async_runtime::spawn(async {
+                        synchronizer::shutdown().await;
+                        std::process::exit(0);
+                    });
                 }
                 "show" => {
                     windows::open_main_window(app_handle.clone());
@@ -91,6 +181,12 @@ async fn main() {
                 "settings" => {
                     windows::open_config_window(app_handle.clone());
                 }
+                "pause" => {
+                    set_paused(app_handle, !synchronizer::is_paused());
+                }
+                id if id.starts_with("profile:") => {
+                    windows::open_config_window(app_handle.clone());
+                }
                 _ => (),
             },
             _ => (),
@@ -105,13 +201,34 @@ async fn main() {
         });
 }
 
+/// Runs `ft` commands the same way the windows do.
+struct AppControl(AppHandle);
+
+#[async_trait::async_trait]
+impl control::Controller for AppControl {
+    async fn sync(&self) {
+        let _ = force_sync(self.0.clone());
+    }
+
+    async fn set_paused(&self, paused: bool) {
+        set_paused(&self.0, paused);
+    }
+
+    async fn logout(&self) {
+        let profiles = CONFIG.lock().unwrap().profiles();
+        for profile in profiles {
+            logout(self.0.clone(), Some(profile.name)).await;
+        }
+    }
+}
+
 #[tauri::command]
 fn get_completed_transfers() -> Vec<types::Transfer> {
     TRANSFERS
         .lock()
         .unwrap()
         .values()
-        .filter(|transfer| transfer.state == TransferState::Completed)
+        .filter(|transfer| transfer.state.is_finished())
         .map(|transfer| transfer.clone())
         .collect()
 }
@@ -119,20 +236,53 @@ fn get_completed_transfers() -> Vec<types::Transfer> {
 #[tauri::command]
 async fn update_config(
     app: AppHandle,
-    config: Config,
+    mut config: Config,
     restart: bool,
 ) -> Result<(), HashMap<String, String>> {
+    // paused from the tray or `ft`, not from the settings
+    config.paused = synchronizer::is_paused();
+    let current = CONFIG.lock().unwrap().clone();
+    let renamed = renamed_profiles(&config, &current);
+    // logged in and out by `login` and `logout`, the settings may hold old tokens
+    for mut profile in config.profiles() {
+        let saved_name = renamed
+            .iter()
+            .find(|(_, new_name)| *new_name == profile.name)
+            .map_or(profile.name.as_str(), |(old_name, _)| old_name);
+        if let Some(saved) = current.profile(saved_name) {
+            profile.username = saved.username;
+            profile.password = saved.password;
+            profile.token = saved.token;
+            profile.refresh_token = saved.refresh_token;
+            config.set_profile(profile);
+        }
+    }
     let app_dir = app.path_resolver().app_data_dir().unwrap();
     let config_path = app_dir.join("config.json");
     let mut error_map = HashMap::new();
-    if config.server_url != CONFIG.lock().unwrap().server_url {
-        let client = Client::new();
-        let server = config.server_url.to_owned();
-        let resp = client.get(format!("{server}/actuator/health")).send().await;
-        if resp.is_err() || !resp.unwrap().status().is_success() {
-            error_map.insert("server".to_string(), "server not reachable".to_string());
+    let main_profile = config.main_profile();
+    if server_changed(&main_profile, &current) {
+        if let Err(e) = check_server(&main_profile).await {
+            error_map.insert("server".to_string(), e);
         }
     }
+    for profile in &config.profiles {
+        if server_changed(profile, &current) {
+            if let Err(e) = check_server(profile).await {
+                error_map.insert("profiles".to_string(), format!("{}: {e}", profile.name));
+            }
+        }
+    }
+    let profiles = config.profiles();
+    let mut names: Vec<&str> = profiles.iter().map(|p| p.name.trim()).collect();
+    names.sort();
+    names.dedup();
+    if names.contains(&"") || names.len() != profiles.len() {
+        error_map.insert(
+            "profiles".to_string(),
+            "every profile needs a name of its own".to_string(),
+        );
+    }
 
     if config.folder_path != CONFIG.lock().unwrap().folder_path {
         let folder_path = Path::new(&config.folder_path);
@@ -141,17 +291,92 @@ async fn update_config(
         }
     }
 
+    // checked against the other profiles' folders too
+    config.sync_pairs = Some(config.pairs());
+    let pairs = config.sync_pairs.iter_mut().flatten().chain(
+        config
+            .profiles
+            .iter_mut()
+            .flat_map(|profile| profile.sync_pairs.iter_mut()),
+    );
+    if let Err(e) = synchronizer::check_pairs(pairs, &current.all_pairs()) {
+        error_map.insert("folder".to_string(), e);
+    }
+
+    if let Err(e) = throttle::check_schedules(&config.bandwidth) {
+        error_map.insert("bandwidth".to_string(), e);
+    }
+
     if !error_map.is_empty() {
         return Err(error_map);
     }
     *CONFIG.lock().unwrap() = config.clone();
+    for (old_name, new_name) in &renamed {
+        synchronizer::rename_profile(old_name, new_name);
+        token::stop(old_name);
+    }
     if restart {
-        synchronizer::stop();
-        synchronizer::start(app);
+        restart_sync(&app);
+    } else {
+        for (_, new_name) in &renamed {
+            let profile = config.profile(new_name).unwrap();
+            if profile.active && profile.is_logged_in() {
+                start_sync(app.clone(), new_name);
+            }
+        }
+    }
+    if restart || !renamed.is_empty() {
+        // profiles may have been activated, deactivated or renamed
+        for profile in config.profiles() {
+            if !profile.active || !profile.is_logged_in() {
+                token::stop(&profile.name);
+            } else if !token::is_watching(&profile.name) {
+                tokio::spawn(token::watch_tokens(app.clone(), profile.name));
+            }
+        }
     }
     std::fs::write(config_path, serde_json::to_string_pretty(&config).unwrap()).unwrap();
+    update_tray(&app);
     Ok(())
 }
+
+/// The profiles renamed in `config`, old name first. A renamed profile keeps
+/// its place in the list, and neither name is in the other config.
+fn renamed_profiles(config: &Config, current: &Config) -> Vec<(String, String)> {
+    config
+        .profiles()
+        .into_iter()
+        .zip(current.profiles())
+        .filter(|(profile, saved)| {
+            profile.name != saved.name
+                && current.profile(&profile.name).is_none()
+                && config.profile(&saved.name).is_none()
+        })
+        .map(|(profile, saved)| (saved.name, profile.name))
+        .collect()
+}
+
+/// Whether the profile is new, or its server or certificate settings changed.
+fn server_changed(profile: &Profile, current: &Config) -> bool {
+    match current.profile(&profile.name) {
+        Some(saved) => {
+            profile.server_url != saved.server_url
+                || profile.ca_bundle != saved.ca_bundle
+                || profile.accept_invalid_certs != saved.accept_invalid_certs
+        }
+        None => true,
+    }
+}
+
+async fn check_server(profile: &Profile) -> Result<(), String> {
+    let client = tls::http_client(tls::connector(profile)?);
+    let server = profile.server_url.trim_end_matches('/');
+    let resp = client.get(format!("{server}/actuator/health")).send().await;
+    match resp {
+        Ok(resp) if resp.status().is_success() => Ok(()),
+        _ => Err("server not reachable".to_string()),
+    }
+}
 #[tauri::command]
 async fn save_initial_config(
     app: AppHandle,
@@ -162,7 +387,9 @@ async fn save_initial_config(
     let mut config = CONFIG.lock().unwrap().clone();
     let app_dir = app.path_resolver().app_data_dir().unwrap();
     let config_path = app_dir.join("config.json");
-    let client = Client::new();
+    let connector = tls::connector(&config.main_profile())
+        .map_err(|e| HashMap::from([("server".to_string(), e)]))?;
+    let client = tls::http_client(connector);
     let server_url = server_url.trim_end_matches('/').to_owned();
     let resp = client
         .get(format!("{server_url}/actuator/health"))
@@ -185,13 +412,47 @@ async fn save_initial_config(
     std::fs::write(config_path, serde_json::to_string_pretty(&config).unwrap()).unwrap();
     *CONFIG.lock().unwrap() = config;
     window.close().unwrap();
-    windows::open_login_window(app.clone());
+    windows::open_login_window(app.clone(), None);
     Ok(())
 }
 
+/// Saves what logging in and out and refreshing tokens change in a profile.
+fn save_profile(app: &AppHandle, profile: Profile) {
+    CONFIG.lock().unwrap().set_profile(profile);
+    let data_dir = app.path_resolver().app_data_dir().unwrap();
+    if let Err(e) = file_transfer::save_config(&data_dir) {
+        println!("Failed to save the config: {e}");
+    }
+    update_tray(app);
+}
+
+/// Starts the synchronizers of `profile` over, with its current token.
+fn start_sync(app: AppHandle, profile: &str) {
+    let data_dir = app.path_resolver().app_data_dir().unwrap();
+    let Some(saved) = CONFIG.lock().unwrap().profile(profile) else {
+        return;
+    };
+    let backend = Arc::new(HttpBackend::from_profile(&saved));
+    synchronizer::start(Arc::new(AppEvents(app)), data_dir, profile, backend);
+}
+
+/// Starts the synchronizers of every active profile logged in over, and stops
+/// those of the others.
+fn restart_sync(app: &AppHandle) {
+    synchronizer::stop();
+    let profiles = CONFIG.lock().unwrap().profiles();
+    for profile in profiles {
+        if profile.active && profile.is_logged_in() {
+            start_sync(app.clone(), &profile.name);
+        }
+    }
+}
+
 #[tauri::command]
 fn get_config() -> Config {
-    let config = CONFIG.lock().unwrap().clone();
+    let mut config = CONFIG.lock().unwrap().clone();
+    // the settings edit the list, `folder_path` becomes its first pair
+    config.sync_pairs = Some(config.pairs());
     config
 }
 
@@ -203,56 +464,48 @@ fn set_config(app: AppHandle) {
     *CONFIG.lock().unwrap() = config;
 }
 
+/// Logs in to `profile`, the main one when None, and starts syncing it.
 #[tauri::command]
-async fn login(app: AppHandle, username: String, password: String) -> Result<(), String> {
-    let server = CONFIG.lock().unwrap().server_url.to_owned();
+async fn login(
+    app: AppHandle,
+    profile: Option<String>,
+    username: String,
+    password: String,
+) -> Result<(), String> {
+    let name = profile.unwrap_or_else(|| CONFIG.lock().unwrap().profile_name.clone());
+    let Some(mut profile) = CONFIG.lock().unwrap().profile(&name) else {
+        return Err(format!("there is no profile {name}"));
+    };
     let login_window = app.get_window("Login").unwrap();
-    let client = Client::new();
-    let resp = client
-        .post(format!("{server}/users/auth/login"))
-        .json(&json!({"username": username, "password": password}))
-        .send()
+    let tokens = match HttpBackend::from_profile(&profile)
+        .auth(&username, &password)
         .await
-        .unwrap();
-    println!("status {}", resp.status());
-    if !resp.status().is_success() {
-        return Err("Username or password incorrect".to_string());
-    }
-
-    let token = resp
-        .headers()
-        .get("authorization")
-        .unwrap()
-        .to_str()
-        .unwrap();
-    let refresh_token = resp
-        .headers()
-        .get("x-refresh-token")
-        .unwrap()
-        .to_str()
-        .unwrap();
+    {
+        Ok(tokens) => tokens,
+        Err(BackendError::Transport(e)) => return Err(e),
+        Err(_) => return Err("Username or password incorrect".to_string()),
+    };
 
     let token = Token {
-        value: token.to_string(),
+        value: tokens.token,
         created_at: SystemTime::now(),
     };
     let refresh_token = Token {
-        value: refresh_token.to_string(),
+        value: tokens.refresh_token,
         created_at: SystemTime::now(),
     };
 
-    let config = {
-        let mut config = CONFIG.lock().unwrap();
-        config.username.replace(username);
-        config.password.replace(password);
-        config.token.replace(token.to_owned());
-        config.refresh_token.replace(refresh_token.to_owned());
-        config.clone()
-    };
-    let _ = update_config(app.clone(), config, true).await;
+    profile.username.replace(username);
+    profile.password.replace(password);
+    profile.token.replace(token);
+    profile.refresh_token.replace(refresh_token);
+    save_profile(&app, profile.clone());
     login_window.close().unwrap();
 
-    tokio::spawn(token::watch_tokens(app.clone()));
+    if profile.active {
+        start_sync(app.clone(), &name);
+        tokio::spawn(token::watch_tokens(app.clone(), name));
+    }
     windows::open_main_window(app);
     Ok(())
 }
@@ -268,25 +521,106 @@ fn open_folder(path: &str) -> Result<(), String> {
 
 #[tauri::command]
 fn force_sync(app: AppHandle) -> Result<(), String> {
-    synchronizer::stop();
-    synchronizer::start(app);
+    if synchronizer::is_paused() {
+        return Err("sync is paused".to_string());
+    }
+    restart_sync(&app);
     Ok(())
 }
+/// Logs out of `profile`, the main one when None, which then asks to log in
+/// again. The other profiles carry on.
 #[tauri::command]
-async fn logout(app: AppHandle) {
-    let config = {
-        let mut config = CONFIG.lock().unwrap();
-        config.token.take();
-        config.refresh_token.take();
-        config.clone()
+async fn logout(app: AppHandle, profile: Option<String>) {
+    let (name, saved, is_main) = {
+        let config = CONFIG.lock().unwrap();
+        let name = profile.unwrap_or_else(|| config.profile_name.clone());
+        let is_main = name == config.profile_name;
+        let saved = config.profile(&name);
+        (name, saved, is_main)
     };
-    let _ = update_config(app.clone(), config, false).await.unwrap();
-    synchronizer::stop();
-    token::stop();
-    windows::close_all(app.clone(), vec!["Login"]);
-    windows::open_login_window(app.clone());
+    synchronizer::stop_profile(&name);
+    token::stop(&name);
+    if let Some(mut profile) = saved {
+        profile.token.take();
+        profile.refresh_token.take();
+        save_profile(&app, profile);
+    }
+    if is_main {
+        windows::close_all(app.clone(), vec!["Login"]);
+        windows::open_login_window(app.clone(), None);
+    }
 }
 #[tauri::command]
 fn check_connection() -> bool {
     IS_CONNECTED.lock().unwrap().clone()
 }
+#[tauri::command]
+fn get_connection_state() -> ConnectionState {
+    *CONNECTION_STATE.lock().unwrap()
+}
+#[tauri::command]
+fn retry_transfer(path: String) -> Result<(), String> {
+    synchronizer::retry_transfer(&path)
+}
+#[tauri::command]
+fn get_pending_operations(app: AppHandle) -> Vec<journal::PendingOperation> {
+    journal::load_all(&app.path_resolver().app_data_dir().unwrap())
+}
+
+/// Pauses or resumes sync, saves it for the next start and updates the tray
+/// item and the windows.
+fn set_paused(app: &AppHandle, paused: bool) {
+    if paused {
+        synchronizer::pause();
+    } else {
+        synchronizer::resume();
+    }
+    let data_dir = app.path_resolver().app_data_dir().unwrap();
+    if let Err(e) = file_transfer::save_config(&data_dir) {
+        println!("Failed to save the config: {e}");
+    }
+    update_tray(app);
+    let _ = app.emit_all("paused", paused);
+}
+#[tauri::command]
+fn pause_sync(app: AppHandle) {
+    set_paused(&app, true);
+}
+#[tauri::command]
+fn resume_sync(app: AppHandle) {
+    set_paused(&app, false);
+}
+#[tauri::command]
+fn is_sync_paused() -> bool {
+    synchronizer::is_paused()
+}
+/// The folders on the server of `profile`, the main one when None.
+#[tauri::command]
+async fn get_remote_folders(
+    profile: Option<String>,
+    folder_id: Option<String>,
+) -> Result<Vec<types::RemoteFolder>, String> {
+    let profile = {
+        let config = CONFIG.lock().unwrap();
+        let name = profile.unwrap_or_else(|| config.profile_name.clone());
+        config
+            .profile(&name)
+            .ok_or_else(|| format!("there is no profile {name}"))?
+    };
+    let backend = HttpBackend::from_profile(&profile);
+    synchronizer::remote_folders(&backend, folder_id.as_deref()).await
+}
+#[tauri::command]
+fn get_pair_states() -> HashMap<String, ConnectionState> {
+    PAIR_STATES.lock().unwrap().clone()
+}
+#[tauri::command]
+fn get_profile_states() -> HashMap<String, ConnectionState> {
+    CONFIG
+        .lock()
+        .unwrap()
+        .profiles()
+        .iter()
+        .map(|profile| (profile.name.clone(), synchronizer::profile_state(profile)))
+        .collect()
+}
@@ -1,248 +1,1160 @@
 use crate::{
-    types::{SocketResponse, Transfer},
+    types::{
+        ConnectionState, PairError, PairState, Profile, ProfileState, RemoteFolder, SyncPair,
+        Transfer, TransferLimits, DEFAULT_PAIR_ID,
+    },
     CONFIG,
 };
-use futures_util::future::join_all;
-use futures_util::StreamExt;
+use futures_util::future::{join_all, BoxFuture};
+use futures_util::{FutureExt, StreamExt};
 use notify::{
     event::{AccessKind, AccessMode, CreateKind, ModifyKind, RemoveKind, RenameMode},
-    Event, EventKind, RecommendedWatcher, RecursiveMode, Result, Watcher,
+    Event, EventKind, RecursiveMode, Result, Watcher,
+};
+use serde::Serialize;
+use std::future::Future;
+use std::time::Duration;
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::LazyLock,
+    vec,
 };
-use std::{collections::HashMap, sync::LazyLock, vec};
 use std::{
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
-use tauri::Manager;
-use tokio_tungstenite::{self, connect_async};
-use tungstenite::{http::Uri, ClientRequestBuilder};
-mod api;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+pub mod api;
+pub mod backend;
 mod debouncer;
+#[cfg(test)]
+mod fake_server;
+mod filter;
 pub(crate) mod fstree;
+mod index;
+pub mod journal;
+mod scheduler;
+#[cfg(test)]
+mod tests;
+pub mod throttle;
+pub mod tls;
+mod transfer;
+
+use backend::{Delta, RemoteBackend, RemoteUpdate};
+use fstree::RemoteChange;
 
 pub static IS_CONNECTED: Mutex<bool> = Mutex::new(false);
+/// The best connection any sync pair has, see `PAIR_STATES` for each one.
+pub static CONNECTION_STATE: Mutex<ConnectionState> = Mutex::new(ConnectionState::Offline);
+/// The connection state of every sync pair started, by pair id.
+pub static PAIR_STATES: LazyLock<Mutex<HashMap<String, ConnectionState>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 pub static TRANSFERS: LazyLock<Mutex<HashMap<PathBuf, Transfer>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
-static WATCHER: Mutex<Option<RecommendedWatcher>> = Mutex::new(None);
-pub fn start(app: tauri::AppHandle) {
-    tokio::spawn(async move {
-        let config = CONFIG.lock().unwrap().clone();
-        let root_path = config.folder_path;
-
-        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Result<Event>>();
-        let mut watcher = notify::recommended_watcher(move |res| {
-            tx.send(res).unwrap();
-        })
-        .unwrap();
-        let root_path = PathBuf::from(root_path);
-        if !root_path.exists() {
-            return;
+/// The synchronizers last started by profile name, one per sync pair, see
+/// `start`.
+static CURRENT: LazyLock<Mutex<HashMap<String, Vec<SyncHandle>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Whether sync is paused, see `pause`.
+static PAUSED: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::Sender::new(false));
+/// The `start` of each profile put off by a pause, run by `resume`.
+static DEFERRED_START: LazyLock<Mutex<HashMap<String, StartArgs>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+type StartArgs = (Arc<dyn EventSink>, PathBuf, Arc<dyn RemoteBackend>);
+/// Holds transfers of every profile and pair back, so the limits it was made
+/// with are for the whole app, see `shared_scheduler`.
+static SCHEDULER: Mutex<Option<(TransferLimits, Arc<scheduler::Scheduler>)>> = Mutex::new(None);
+/// Failed transfers to start over by the root of the pair they belong to, see
+/// `retry_transfer`.
+static RETRIES: LazyLock<Mutex<HashMap<PathBuf, tokio::sync::mpsc::UnboundedSender<String>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Where the state of pairs other than the default one is kept, by pair id.
+const PAIRS_DIR: &str = "pairs";
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Failed connection attempts in a row before showing the client as offline.
+const OFFLINE_AFTER_FAILURES: u32 = 3;
+
+/// Receives what the UI listens to, "transfer" progress, "connection_state",
+/// "profile_state", "pair_state", "pair_error" and "is_connected".
+pub trait EventSink: Send + Sync {
+    fn emit(&self, event: &str, payload: serde_json::Value);
+}
+
+/// Stops the synchronizer of a sync pair.
+struct SyncHandle {
+    cancel: CancellationToken,
+    task: tokio::task::JoinHandle<()>,
+    /// The folder of its pair, where failed transfers are retried from.
+    root_path: Option<PathBuf>,
+    /// Logged in to the profile's server, shared by the profile's pairs.
+    backend: Arc<dyn RemoteBackend>,
+}
+
+impl SyncHandle {
+    /// Has its tasks stop, and failed transfers of the pair can't be retried
+    /// until it runs again.
+    fn stop(&self) {
+        self.cancel.cancel();
+        if let Some(root_path) = &self.root_path {
+            RETRIES.lock().unwrap().remove(root_path);
         }
-        let local_tree = Arc::new(Mutex::new(fstree::build_tree(&root_path).unwrap()));
-        let _root_path = root_path.clone();
-        let _local_tree = local_tree.clone();
-        let _app = app.app_handle().clone();
-        fstree::save_tree(&local_tree.lock().unwrap(), "tree.json").unwrap();
-        let socket_task = async move {
-            let local_tree = _local_tree.clone();
-            let root_path = _root_path.clone();
-            let app = _app.app_handle().clone();
-
-            loop {
-                let config = CONFIG.lock().unwrap().clone();
-                let token = config.token.as_ref();
-                if config.token.is_none() {
-                    break;
-                }
-                let token = token.unwrap().value.clone();
-
-                let socket_url = format!(
-                    "{}/websocket",
-                    config
-                        .server_url
-                        .replace("https://", "ws://")
-                        .replace("http://", "ws://")
-                );
-                let uri: Uri = socket_url.parse().unwrap();
-
-                let request = ClientRequestBuilder::new(uri).with_header("authorization", &token);
-                match connect_async(request).await {
-                    Ok((mut socket, _response)) => {
-                        println!("Connected to server");
-                        *IS_CONNECTED.lock().unwrap() = true;
-                        app.emit_all("is_connected", true).unwrap();
-                        while let Some(msg) = socket.next().await {
-                            match msg {
-                                Ok(tungstenite::Message::Text(text)) => {
-                                    handle_msg(
-                                        &local_tree,
-                                        &root_path,
-                                        &app,
-                                        tungstenite::Message::Text(text),
-                                    )
-                                    .await;
-                                }
-                                Ok(_) => {}
-                                Err(e) => {
-                                    println!("WebSocket error: {}", e);
+    }
+
+    /// Waits for its tasks to have stopped, once cancelled.
+    async fn stopped(self) {
+        let _ = self.task.await;
+    }
+}
+
+/// Everything a running synchronizer shares between the socket, the watcher
+/// and the transfers. There is one for each sync pair.
+#[derive(Clone)]
+pub(crate) struct SyncContext {
+    events: Arc<dyn EventSink>,
+    /// Name of the profile the pair belongs to, whose login it uses.
+    profile: String,
+    pair_id: String,
+    /// The pair's journal, index, upload sessions, partial downloads and
+    /// quarantined files live here.
+    data_dir: PathBuf,
+    backend: Arc<dyn RemoteBackend>,
+    root_path: PathBuf,
+    /// The remote folder synced, None for the whole remote tree.
+    remote_folder_id: Option<String>,
+    /// Its path on the server as of the last remote tree, "" for the root.
+    remote_root: Arc<Mutex<String>>,
+    tree: Arc<Mutex<fstree::Node>>,
+    rules: Arc<filter::IgnoreRules>,
+    /// Local changes waiting to reach the server.
+    journal: Arc<journal::Journal>,
+    /// Holds transfers back while the configured number already run.
+    scheduler: Arc<scheduler::Scheduler>,
+    /// The tree as last synced, the base local and remote changes are told from.
+    index: Arc<index::Index>,
+    /// Cancelled to stop, every task returns at its next safe point.
+    cancel: CancellationToken,
+}
+
+#[cfg(feature = "gui")]
+impl EventSink for tauri::AppHandle {
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        use tauri::Manager;
+        let _ = self.emit_all(event, payload);
+    }
+}
+
+impl SyncContext {
+    fn emit(&self, event: &str, payload: impl Serialize) {
+        self.events
+            .emit(event, serde_json::to_value(payload).unwrap());
+    }
+
+    /// Waits out a pause. False when the synchronizer is stopping instead.
+    async fn proceed(&self) -> bool {
+        tokio::select! {
+            _ = wait_while_paused() => !self.cancel.is_cancelled(),
+            _ = self.cancel.cancelled() => false,
+        }
+    }
+
+    /// `path` relative to the remote root rather than to the pair's folder.
+    fn remote_path(&self, path: &str) -> String {
+        let remote_root = self.remote_root.lock().unwrap();
+        if remote_root.is_empty() {
+            return path.to_string();
+        }
+        Path::new(remote_root.as_str())
+            .join(path)
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
+/// Where the state of `pair_id` is kept. The default pair keeps it right in
+/// `data_dir`, where it was before there could be several.
+pub fn pair_data_dir(data_dir: &Path, pair_id: &str) -> PathBuf {
+    if pair_id == DEFAULT_PAIR_ID {
+        return data_dir.to_path_buf();
+    }
+    data_dir.join(PAIRS_DIR).join(pair_id)
+}
+
+/// Starts syncing every pair of `profile`, each on its own, once the
+/// synchronizers that were running for it before, if any, have stopped. The
+/// other profiles carry on. `backend` is logged in to the profile's server.
+pub fn start(
+    events: Arc<dyn EventSink>,
+    data_dir: PathBuf,
+    profile: &str,
+    backend: Arc<dyn RemoteBackend>,
+) {
+    if is_paused() {
+        DEFERRED_START
+            .lock()
+            .unwrap()
+            .insert(profile.to_string(), (events, data_dir, backend));
+        return;
+    }
+    let config = CONFIG.lock().unwrap().clone();
+    let pairs = config
+        .profile(profile)
+        .map(|profile| profile.sync_pairs)
+        .unwrap_or_default();
+    let mut all = CURRENT.lock().unwrap();
+    let current = all.entry(profile.to_string()).or_default();
+    let previous = std::mem::take(current);
+    for handle in &previous {
+        handle.stop();
+    }
+    // two of them would apply the same changes side by side
+    let stopped = join_all(previous.into_iter().map(SyncHandle::stopped)).shared();
+    let scheduler = shared_scheduler(&config.transfers);
+    for pair in pairs {
+        let cancel = CancellationToken::new();
+        let root_path = Some(PathBuf::from(&pair.local_path));
+        let run = run(
+            events.clone(),
+            profile.to_string(),
+            pair_data_dir(&data_dir, &pair.id),
+            backend.clone(),
+            pair,
+            scheduler.clone(),
+            cancel.clone(),
+        );
+        let task = tokio::spawn(stopped.clone().then(|_| run));
+        current.push(SyncHandle {
+            cancel,
+            task,
+            root_path,
+            backend: backend.clone(),
+        });
+    }
+    if current.is_empty() {
+        // nothing to sync, `shutdown` still waits for the previous ones
+        current.push(SyncHandle {
+            cancel: CancellationToken::new(),
+            task: tokio::spawn(stopped.map(|_| ())),
+            root_path: None,
+            backend,
+        });
+    }
+}
+
+async fn run(
+    events: Arc<dyn EventSink>,
+    profile: String,
+    data_dir: PathBuf,
+    backend: Arc<dyn RemoteBackend>,
+    pair: SyncPair,
+    scheduler: Arc<scheduler::Scheduler>,
+    cancel: CancellationToken,
+) {
+    // runs once the previous synchronizers have stopped
+    if cancel.is_cancelled() {
+        return;
+    }
+    let config = CONFIG.lock().unwrap().clone();
+    let root_path = PathBuf::from(pair.local_path);
+    if !root_path.is_dir() {
+        let message = format!("{} doesn't exist or isn't a folder", root_path.display());
+        return pair_error(&events, &pair.id, message);
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Result<Event>>();
+    let mut watcher = match notify::recommended_watcher(move |res| {
+        let _ = tx.send(res);
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => return pair_error(&events, &pair.id, format!("Can't watch: {e}")),
+    };
+    if let Err(e) = std::fs::create_dir_all(&data_dir) {
+        let message = format!("Can't create {}: {e}", data_dir.display());
+        return pair_error(&events, &pair.id, message);
+    }
+    fstree::open_hash_cache(&root_path, &data_dir);
+    let rules = Arc::new(filter::IgnoreRules::load(
+        &root_path,
+        &config.ignore_patterns,
+        &pair.selective_sync,
+    ));
+    let local_tree = match fstree::build_tree(&root_path, &rules) {
+        Ok(tree) => tree,
+        Err(e) => {
+            let message = format!("Can't read {}: {e}", root_path.display());
+            return pair_error(&events, &pair.id, message);
+        }
+    };
+    // known before the first listing, so new files go into the right folder
+    *local_tree.id.lock().unwrap() = pair.remote_folder_id.clone();
+    let local_tree = Arc::new(Mutex::new(local_tree));
+    let ctx = SyncContext {
+        journal: Arc::new(journal::Journal::open(&data_dir)),
+        scheduler,
+        index: Arc::new(index::Index::open(&data_dir, &root_path)),
+        cancel: cancel.clone(),
+        events,
+        profile,
+        pair_id: pair.id,
+        data_dir,
+        backend,
+        root_path: root_path.clone(),
+        remote_folder_id: pair.remote_folder_id,
+        remote_root: Arc::new(Mutex::new(String::new())),
+        tree: local_tree,
+        rules,
+    };
+    let mut saved_tree = ctx.index.load();
+    if let Some(saved_tree) = &mut saved_tree {
+        drop_deselected(&ctx, saved_tree);
+    }
+    reconcile(&ctx, saved_tree).await;
+    transfer::resume_uploads(&ctx);
+    let socket_task = async {
+        // whichever way this task ends, stopped, paused or logged out
+        let _offline = OfflineOnDrop(&ctx);
+        let mut cursor = None;
+        let mut failures = 0;
+        let mut was_connected = false;
+        set_connection_state(&ctx, ConnectionState::Connecting);
+        'connect: loop {
+            if !ctx.proceed().await || !is_logged_in(&ctx.profile) {
+                break;
+            }
+            let connected = tokio::select! {
+                connected = ctx.backend.watch_tree() => connected,
+                _ = ctx.cancel.cancelled() => break,
+            };
+            match connected {
+                Ok(mut updates) => {
+                    println!("Connected to server");
+                    failures = 0;
+                    was_connected = true;
+                    set_connection_state(&ctx, ConnectionState::Connected);
+                    // local changes first, so the remote tree doesn't undo them
+                    journal::replay(&ctx).await;
+                    // catch up on what changed while disconnected
+                    catch_up(&ctx, &mut cursor).await;
+                    loop {
+                        let update = tokio::select! {
+                            update = updates.next() => update,
+                            _ = until_paused() => {
+                                // updates queue up meanwhile, the heartbeat
+                                // keeps the connection
+                                if !ctx.proceed().await {
+                                    break 'connect;
                                 }
+                                // restarts downloads the pause broke off
+                                catch_up(&ctx, &mut cursor).await;
+                                continue;
+                            }
+                            _ = ctx.cancel.cancelled() => break 'connect,
+                        };
+                        let Some(update) = update else {
+                            break;
+                        };
+                        match update {
+                            Ok(RemoteUpdate::Tree(snapshot)) => {
+                                handle_msg(&ctx, &snapshot.tree).await;
+                                cursor = snapshot.cursor;
+                            }
+                            Ok(RemoteUpdate::Delta(delta)) => {
+                                on_delta(&ctx, &mut cursor, delta).await
+                            }
+                            Err(e) => {
+                                println!("WebSocket error: {}", e);
                             }
                         }
-                        println!("Disconnected from server");
-                    }
-                    Err(e) => {
-                        *IS_CONNECTED.lock().unwrap() = false;
-                        app.emit_all("is_connected", false).unwrap();
-                        println!("Failed to connect: {}", e);
-                        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
                     }
+                    println!("Disconnected from server");
+                    set_connection_state(&ctx, ConnectionState::Reconnecting);
+                }
+                Err(e) => {
+                    failures += 1;
+                    println!("Failed to connect ({failures} in a row): {}", e);
+                    let state = if failures >= OFFLINE_AFTER_FAILURES {
+                        ConnectionState::Offline
+                    } else if was_connected {
+                        ConnectionState::Reconnecting
+                    } else {
+                        ConnectionState::Connecting
+                    };
+                    set_connection_state(&ctx, state);
                 }
             }
-        };
+            tokio::select! {
+                _ = tokio::time::sleep(backoff(failures)) => {},
+                _ = ctx.cancel.cancelled() => break,
+            }
+        }
+    };
 
-        let watcher_task = async move {
-            watcher
-                .watch(std::path::Path::new(&root_path), RecursiveMode::Recursive)
-                .unwrap();
-            WATCHER.lock().unwrap().replace(watcher);
-            let debouncer = debouncer::Debouncer::new(std::time::Duration::from_millis(1000));
-
-            while let Some(res) = rx.recv().await {
-                match res {
-                    Ok(event) => handle_event(
-                        app.app_handle().clone(),
-                        event,
-                        local_tree.clone(),
-                        root_path.clone(),
-                        &debouncer,
-                    ),
-                    Err(e) => println!("watch error: {:?}", e),
+    let watcher_task = async {
+        if let Err(e) = watcher.watch(&root_path, RecursiveMode::Recursive) {
+            let message = format!("Can't watch {}: {e}", root_path.display());
+            return pair_error(&ctx.events, &ctx.pair_id, message);
+        }
+        let debouncer = debouncer::Debouncer::new(std::time::Duration::from_millis(1000));
+        let dirty = Arc::new(Mutex::new(BTreeSet::new()));
+        loop {
+            let res = tokio::select! {
+                res = rx.recv() => res,
+                _ = ctx.cancel.cancelled() => break,
+            };
+            match res {
+                Some(Ok(event)) => handle_event(&ctx, event, &debouncer, &dirty),
+                Some(Err(e)) => println!("watch error: {:?}", e),
+                None => break,
+            }
+        }
+        drop(watcher);
+        // lets a push that already started finish
+        let _ = tokio::task::spawn_blocking(move || debouncer.stop()).await;
+    };
+    let journal_task = journal::run(&ctx);
+    let (retry_tx, mut retry_rx) = tokio::sync::mpsc::unbounded_channel();
+    RETRIES
+        .lock()
+        .unwrap()
+        .insert(ctx.root_path.clone(), retry_tx);
+    let retry_task = async {
+        loop {
+            let path = tokio::select! {
+                Some(path) = retry_rx.recv() => path,
+                _ = ctx.cancel.cancelled() => break,
+            };
+            transfer::retry(&ctx, &path).await;
+        }
+    };
+    // each stops at a safe point once one of them ends, stopped or logged out
+    tokio::join!(
+        cancel_when_done(&cancel, socket_task),
+        cancel_when_done(&cancel, watcher_task),
+        cancel_when_done(&cancel, journal_task),
+        cancel_when_done(&cancel, retry_task),
+    );
+    println!("Synchronizer of {} stopped", ctx.root_path.display());
+}
+
+/// Tells the UI why the pair isn't syncing.
+fn pair_error(events: &Arc<dyn EventSink>, pair_id: &str, message: String) {
+    println!("Sync pair {pair_id}: {message}");
+    let error = PairError {
+        id: pair_id.to_string(),
+        message,
+    };
+    events.emit("pair_error", serde_json::to_value(error).unwrap());
+}
+
+async fn cancel_when_done(cancel: &CancellationToken, task: impl Future<Output = ()>) {
+    task.await;
+    cancel.cancel();
+}
+
+/// How far along connecting a state is, the overall state is the best one.
+fn reach(state: ConnectionState) -> u8 {
+    match state {
+        ConnectionState::Offline => 0,
+        ConnectionState::Connecting => 1,
+        ConnectionState::Reconnecting => 2,
+        ConnectionState::Connected => 3,
+    }
+}
+
+/// Logged out profiles, or removed ones, stop trying to connect.
+fn is_logged_in(profile: &str) -> bool {
+    let config = CONFIG.lock().unwrap();
+    config
+        .profile(profile)
+        .is_some_and(|profile| profile.token.is_some())
+}
+
+/// The best connection any pair of the profile has, offline without pairs.
+pub fn profile_state(profile: &Profile) -> ConnectionState {
+    let states = PAIR_STATES.lock().unwrap();
+    profile
+        .sync_pairs
+        .iter()
+        .filter_map(|pair| states.get(&pair.id).copied())
+        .max_by_key(|state| reach(*state))
+        .unwrap_or(ConnectionState::Offline)
+}
+
+/// Records the state of the pair, and the ones it makes with the other pairs
+/// of its profile and with all of them.
+fn set_connection_state(ctx: &SyncContext, state: ConnectionState) {
+    let overall = {
+        let mut states = PAIR_STATES.lock().unwrap();
+        states.insert(ctx.pair_id.clone(), state);
+        states
+            .values()
+            .copied()
+            .max_by_key(|state| reach(*state))
+            .unwrap()
+    };
+    ctx.emit(
+        "pair_state",
+        PairState {
+            id: ctx.pair_id.clone(),
+            state,
+        },
+    );
+    let profile = CONFIG.lock().unwrap().profile(&ctx.profile);
+    if let Some(profile) = profile {
+        ctx.emit(
+            "profile_state",
+            ProfileState {
+                state: profile_state(&profile),
+                name: profile.name,
+            },
+        );
+    }
+    let connected = overall == ConnectionState::Connected;
+    *CONNECTION_STATE.lock().unwrap() = overall;
+    ctx.emit("connection_state", overall);
+    let was_connected = std::mem::replace(&mut *IS_CONNECTED.lock().unwrap(), connected);
+    if was_connected != connected {
+        ctx.emit("is_connected", connected);
+    }
+}
+
+struct OfflineOnDrop<'a>(&'a SyncContext);
+
+impl Drop for OfflineOnDrop<'_> {
+    fn drop(&mut self) {
+        set_connection_state(self.0, ConnectionState::Offline);
+    }
+}
+
+/// Exponential in the failures in a row, starting at a second and capped at
+/// a minute, then randomly cut by up to half so clients dropped together
+/// don't all come back at once.
+fn backoff(failures: u32) -> Duration {
+    let delay = (BACKOFF_BASE * 2u32.pow(failures.min(6))).min(BACKOFF_MAX);
+    let jitter = (uuid::Uuid::new_v4().as_u128() % 1000) as u32;
+    delay / 2 + delay / 2 * jitter / 1000
+}
+
+/// Catches up from `cursor` on the changes since, or on the whole tree when
+/// there is no cursor or the server no longer has changes that old.
+async fn catch_up(ctx: &SyncContext, cursor: &mut Option<u64>) {
+    if let Some(since) = *cursor {
+        match ctx.backend.changes_since(since).await {
+            Ok(Some(delta)) => match apply_delta(ctx, &delta).await {
+                Ok(()) => {
+                    *cursor = Some(delta.cursor);
+                    return;
                 }
+                Err(e) => println!("Failed to apply changes since {since}: {e}"),
+            },
+            Ok(None) => println!("Changes since {since} are gone, listing the whole tree"),
+            Err(e) => println!("Failed to get changes since {since}: {e}"),
+        }
+    }
+    match ctx.backend.list_tree().await {
+        Ok(snapshot) => {
+            handle_msg(ctx, &snapshot.tree).await;
+            *cursor = snapshot.cursor;
+        }
+        Err(e) => println!("Failed to list remote tree: {}", e),
+    }
+}
+
+async fn on_delta(ctx: &SyncContext, cursor: &mut Option<u64>, delta: Delta) {
+    let Some(current) = *cursor else {
+        return catch_up(ctx, cursor).await;
+    };
+    if delta.cursor <= current {
+        // already covered by the catch-up
+        return;
+    }
+    if delta.since != current {
+        println!("Missed changes between {current} and {}", delta.since);
+        return catch_up(ctx, cursor).await;
+    }
+    match apply_delta(ctx, &delta).await {
+        Ok(()) => *cursor = Some(delta.cursor),
+        Err(e) => {
+            println!("Failed to apply delta {}: {e}", delta.cursor);
+            *cursor = None;
+            catch_up(ctx, cursor).await;
+        }
+    }
+}
+
+/// Where a remote change is, seen from the pair.
+enum PairChange {
+    /// With paths relative to the pair's folder.
+    Inside(RemoteChange),
+    Outside,
+    /// It replaces or removes the pair's folder, or moves something in or out
+    /// of it, only the whole tree tells what the pair has now.
+    Relist,
+}
+
+fn pair_change(ctx: &SyncContext, change: RemoteChange) -> PairChange {
+    let root = ctx.remote_root.lock().unwrap().clone();
+    if ctx.remote_folder_id.is_none() || root.is_empty() {
+        return PairChange::Inside(change);
+    }
+    let inside = |path: &str| {
+        path.strip_prefix(root.as_str())
+            .and_then(|rest| rest.strip_prefix('/'))
+            .map(str::to_string)
+    };
+    let holds_root = |path: &str| Path::new(&root).starts_with(path);
+    let outside_unless_it_holds_root = |path: &str| {
+        if holds_root(path) {
+            PairChange::Relist
+        } else {
+            PairChange::Outside
+        }
+    };
+    match change {
+        RemoteChange::Added { id, path, node } => match inside(&path) {
+            Some(path) => PairChange::Inside(RemoteChange::Added { id, path, node }),
+            None => outside_unless_it_holds_root(&path),
+        },
+        RemoteChange::Modified { id, path, node } => match inside(&path) {
+            Some(path) => PairChange::Inside(RemoteChange::Modified { id, path, node }),
+            None => outside_unless_it_holds_root(&path),
+        },
+        RemoteChange::Deleted { id, path } => match inside(&path) {
+            Some(path) => PairChange::Inside(RemoteChange::Deleted { id, path }),
+            None => outside_unless_it_holds_root(&path),
+        },
+        RemoteChange::Moved { id, from, path } => {
+            if holds_root(&from) {
+                // the pair's folder moves along, what is in it stays the same
+                let moved = format!("{path}{}", &root[from.len()..]);
+                *ctx.remote_root.lock().unwrap() = moved;
+                return PairChange::Outside;
+            }
+            match (inside(&from), inside(&path)) {
+                (Some(from), Some(path)) => {
+                    PairChange::Inside(RemoteChange::Moved { id, from, path })
+                }
+                (None, None) => PairChange::Outside,
+                _ => PairChange::Relist,
+            }
+        }
+    }
+}
+
+/// Carries out each change of `delta` on just the part of the tree it touches.
+/// Err when only the whole remote tree can tell what changed.
+async fn apply_delta(ctx: &SyncContext, delta: &Delta) -> std::result::Result<(), String> {
+    let mut downloads: Downloads = vec![];
+    let mut result = Ok(());
+    for change in &delta.changes {
+        let change = match pair_change(ctx, change.clone()) {
+            PairChange::Inside(change) => change,
+            PairChange::Outside => continue,
+            PairChange::Relist => {
+                result = Err("the synced folder itself changed".to_string());
+                break;
             }
         };
-        tokio::select! {
-            _ = socket_task => {},
-            _ = watcher_task => {},
+        let paths = change.paths();
+        // a download there lands first, the change goes on from it
+        let overlapping = downloads.iter().any(|(downloading, _)| {
+            paths.iter().any(|path| {
+                Path::new(downloading).starts_with(path) || Path::new(path).starts_with(downloading)
+            })
+        });
+        if overlapping {
+            join_all(downloads.drain(..).map(|(_, download)| download)).await;
+        }
+        let base = ctx.index.part(&paths);
+        let mut remote = base.detached();
+        if let Err(e) = remote.apply_remote(&change) {
+            result = Err(e);
+            break;
         }
-    });
+        remote.prune(&ctx.rules);
+        let merge = {
+            let local = ctx.tree.lock().unwrap().part(&paths);
+            let mut pending = vec![];
+            for path in &paths {
+                pending.extend(ctx.index.pending(path).unwrap());
+            }
+            fstree::three_way_diff(&base, &local, &remote, &pending)
+        };
+        downloads.extend(apply_merge(ctx, merge, &remote).await);
+        let tree = ctx.tree.lock().unwrap();
+        let adopted = fstree::adopt_remote(&base, &tree, &remote);
+        ctx.index.settle(&tree, &adopted).unwrap();
+    }
+    join_all(downloads.into_iter().map(|(_, download)| download)).await;
+    result
 }
 
-async fn handle_msg(
-    local_tree: &Arc<Mutex<fstree::Node>>,
-    root_path: &PathBuf,
-    app: &tauri::AppHandle,
-    msg: tungstenite::Message,
-) {
-    let text = msg.to_string();
+/// The part of the remote tree the pair syncs, None when its folder is gone.
+fn pair_tree(ctx: &SyncContext, remote: &fstree::Node) -> Option<fstree::Node> {
+    let Some(folder_id) = &ctx.remote_folder_id else {
+        return Some(remote.detached());
+    };
+    let (path, folder) = remote.find_id("", folder_id)?;
+    *ctx.remote_root.lock().unwrap() = path;
+    Some(folder.detached())
+}
+
+async fn handle_msg(ctx: &SyncContext, remote: &fstree::Node) {
     println!("Received new tree");
-    let mut changes: Vec<fstree::Change> = Vec::new();
-    let resp: SocketResponse = serde_json::from_str(&text).unwrap();
-    let mut remote_tree = resp.data;
-    {
+    let Some(mut remote_tree) = pair_tree(ctx, remote) else {
+        println!(
+            "The remote folder of {} is gone, not syncing it",
+            ctx.root_path.display()
+        );
+        return;
+    };
+    let SyncContext {
+        tree: local_tree,
+        rules,
+        ..
+    } = ctx;
+    // ignored paths are neither downloaded nor compared
+    remote_tree.prune(rules);
+    let base = ctx.index.load().unwrap();
+    let merge = {
+        let pending = ctx.index.pending("").unwrap();
         let local = local_tree.lock().unwrap();
-        fstree::diff_trees("", Some(&local), Some(&remote_tree), &mut changes);
+        fstree::three_way_diff(&base, &local, &remote_tree, &pending)
+    };
+    let downloads = apply_merge(ctx, merge, &remote_tree).await;
+    join_all(downloads.into_iter().map(|(_, download)| download)).await;
+    let tree = local_tree.lock().unwrap();
+    let adopted = fstree::adopt_remote(&base, &tree, &remote_tree);
+    ctx.index.settle(&tree, &adopted).unwrap();
+}
+
+/// Downloads started by `apply_merge`, with the path each one writes to.
+type Downloads<'a> = Vec<(String, BoxFuture<'a, ()>)>;
+
+/// Carries out the remote side of `merge` locally, `remote` being the remote
+/// tree it was told from. The downloads are left for the caller to wait for.
+async fn apply_merge<'a>(
+    ctx: &'a SyncContext,
+    merge: fstree::Merge,
+    remote: &fstree::Node,
+) -> Downloads<'a> {
+    let SyncContext {
+        root_path,
+        tree: local_tree,
+        rules,
+        ..
+    } = ctx;
+    // local-only changes are left for the watcher to push
+    let mut changes = merge.remote;
+    let mut recreate = BTreeSet::new();
+    for conflict in merge.conflicts {
+        println!(
+            "conflict {:?} <-> {:?}: {}",
+            conflict.local.change_type, conflict.remote.change_type, conflict.remote.path,
+        );
+        if let Some(change) = resolve_conflict(ctx, conflict, &mut recreate) {
+            changes.push(change);
+        }
     }
-    let mut futures = vec![];
-    let changes = fstree::detect_renames(changes);
-    // println!("changes: {:?}", changes);
+    for folder in recreate {
+        recreate_folder(ctx, &folder).await;
+    }
+    let mut downloads: Downloads = vec![];
     for change in changes {
         println!(
             "remote nodeType:{:?} -> {:?}: {}",
             change.node_type, change.change_type, change.path,
         );
+        let id = change.id.lock().unwrap().clone();
         match change.change_type {
-            fstree::ChangeType::Added => match change.node_type {
-                fstree::NodeType::File => {
-                    futures.push(api::download(
-                        app.app_handle().clone(),
-                        root_path,
-                        change.path,
-                        change.id,
-                        change.hash.unwrap(),
-                        local_tree.clone(),
-                    ));
-                }
-                fstree::NodeType::Folder => {
-                    std::fs::create_dir_all(Path::new(root_path).join(&change.path)).unwrap();
-                    let node =
-                        fstree::build_node(&Path::new(root_path), &root_path.join(&change.path));
-                    local_tree.lock().unwrap().add_node(node.unwrap()).unwrap();
+            fstree::ChangeType::Added if change.node_type == fstree::NodeType::Folder => {
+                let local = root_path.join(&change.path);
+                std::fs::create_dir_all(&local).unwrap();
+                let node = fstree::build_node(root_path, &local, rules);
+                let mut tree = local_tree.lock().unwrap();
+                tree.add_node(node.unwrap()).unwrap();
+                if let Some(folder) = tree.find(&change.path) {
+                    *folder.id.lock().unwrap() = id;
                 }
-            },
-            fstree::ChangeType::Deleted => match change.node_type {
-                fstree::NodeType::File => {
-                    let _ = std::fs::remove_file(Path::new(root_path).join(&change.path));
-
-                    local_tree
-                        .lock()
-                        .unwrap()
-                        .delete_node(root_path.join(&change.path).to_str().unwrap())
-                        .unwrap();
-                }
-                fstree::NodeType::Folder => {
-                    let _ = std::fs::remove_dir_all(Path::new(root_path).join(&change.path))
-                        .map_err(|_| {
-                            println!("failed to delete {:#?}", change.path);
-                        });
-                    local_tree
-                        .lock()
-                        .unwrap()
-                        .delete_node(root_path.join(&change.path).to_str().unwrap())
-                        .unwrap();
+                // what is in it already is up to the watcher
+                ctx.index.settle(&tree, &[change.path]).unwrap();
+            }
+            fstree::ChangeType::Added | fstree::ChangeType::Modified => {
+                let size = remote.find(&change.path).map_or(0, |node| node.size);
+                let path = change.path.clone();
+                let download =
+                    transfer::download(ctx, change.path, change.id, change.hash.unwrap(), size);
+                downloads.push((path, download.boxed()));
+            }
+            fstree::ChangeType::Deleted => {
+                let local = root_path.join(&change.path);
+                let removed = match change.node_type {
+                    fstree::NodeType::File => std::fs::remove_file(&local),
+                    fstree::NodeType::Folder => std::fs::remove_dir_all(&local),
+                };
+                if removed.is_err() {
+                    println!("failed to delete {:#?}", change.path);
                 }
-            },
-            fstree::ChangeType::Renamed { from } => {
-                std::fs::rename(
-                    Path::new(root_path).join(&from),
-                    Path::new(root_path).join(&change.path),
-                )
-                .unwrap();
-
                 local_tree
                     .lock()
                     .unwrap()
-                    .rename_node(
-                        root_path.join(&from).to_str().unwrap(),
-                        root_path.join(&change.path).to_str().unwrap(),
-                    )
+                    .delete_node(local.to_str().unwrap())
+                    .unwrap();
+                ctx.index.remove(&change.path).unwrap();
+            }
+            fstree::ChangeType::Renamed { from } => {
+                let (old, new) = (root_path.join(&from), root_path.join(&change.path));
+                if let Err(e) = std::fs::rename(&old, &new) {
+                    println!("Failed to move {from} to {}: {e}", change.path);
+                    continue;
+                }
+                let mut tree = local_tree.lock().unwrap();
+                tree.rename_node(old.to_str().unwrap(), new.to_str().unwrap(), rules)
+                    .unwrap();
+                if let Some(node) = tree.find(&change.path) {
+                    *node.id.lock().unwrap() = id;
+                }
+                ctx.index
+                    .rename(&tree, &from, &change.path, index::Status::Synced)
                     .unwrap();
             }
-            fstree::ChangeType::Modified => futures.push(api::download(
-                app.app_handle().clone(),
-                root_path,
-                change.path,
-                change.id,
-                change.hash.unwrap(),
-                local_tree.clone(),
-            )),
         }
     }
-    join_all(futures).await;
-    let mut changes: Vec<fstree::Change> = Vec::new();
-    fstree::diff_trees(
-        "",
-        Some(&local_tree.lock().unwrap()),
-        Some(&remote_tree),
-        &mut changes,
-    );
-    remote_tree.path = Some(root_path.to_str().unwrap().to_string());
-    println!("changes: {:?}", changes);
-    if changes.is_empty() {
-        *local_tree.lock().unwrap() = remote_tree;
+    downloads
+}
+
+/// Returns the remote change to apply locally, if the remote side wins the
+/// conflict. Folders deleted remotely with a local edit inside go into
+/// `recreate`.
+fn resolve_conflict(
+    ctx: &SyncContext,
+    conflict: fstree::Conflict,
+    recreate: &mut BTreeSet<String>,
+) -> Option<fstree::Change> {
+    let fstree::Conflict { local, remote } = conflict;
+    let is_edit = |change: &fstree::Change| {
+        change.node_type == fstree::NodeType::File
+            && matches!(
+                change.change_type,
+                fstree::ChangeType::Added | fstree::ChangeType::Modified
+            )
+    };
+    if is_edit(&local) && is_edit(&remote) && local.path == remote.path {
+        keep_conflicted_copy(ctx, &local.path);
+        return Some(remote);
+    }
+    // an edit wins over a delete
+    if is_edit(&local) && remote.change_type == fstree::ChangeType::Deleted {
+        if local.path == remote.path {
+            // the remote file is gone, upload ours as a new one
+            local.id.lock().unwrap().take();
+            let upload = journal::Operation::Upload { path: local.path };
+            journal::enqueue(ctx, upload, None);
+        } else if remote.node_type == fstree::NodeType::Folder {
+            recreate.insert(remote.path);
+        }
+        return None;
+    }
+    Some(remote)
+}
+
+/// Uploads a folder deleted remotely again, with everything still in it
+/// locally, as new nodes.
+async fn recreate_folder(ctx: &SyncContext, path: &str) {
+    // what is queued there names nodes the server no longer has
+    journal::discard(ctx, path);
+    let mut changes = vec![];
+    {
+        let mut tree = ctx.tree.lock().unwrap();
+        let Some(folder) = tree.find_mut(path) else {
+            return;
+        };
+        folder.forget_ids();
+        fstree::diff_trees(path, None, Some(folder), &mut changes);
+    }
+    // each folder is created before what goes into it
+    changes.sort_by_key(|change| Path::new(&change.path).components().count());
+    push_changes(ctx, changes).await;
+}
+
+/// Moves the local version of `path` aside and uploads it, so the remote version
+/// can be downloaded in its place.
+fn keep_conflicted_copy(ctx: &SyncContext, path: &str) {
+    let SyncContext {
+        root_path,
+        tree: local_tree,
+        rules,
+        ..
+    } = ctx;
+    let username = CONFIG
+        .lock()
+        .unwrap()
+        .profile(&ctx.profile)
+        .and_then(|profile| profile.username)
+        .unwrap_or_default();
+    let copy_path = conflicted_copy_path(path, &username);
+    if std::fs::rename(root_path.join(path), root_path.join(&copy_path)).is_err() {
+        println!("failed to keep conflicted copy of {}", path);
+        return;
+    }
+    // the local version goes up as the copy instead
+    journal::discard(ctx, path);
+    let node = fstree::build_node(root_path, &root_path.join(&copy_path), rules).unwrap();
+    {
+        let mut tree = local_tree.lock().unwrap();
+        tree.delete_node(root_path.join(path).to_str().unwrap())
+            .unwrap();
+        tree.add_node(node).unwrap();
+        ctx.index.remove(path).unwrap();
+        ctx.index
+            .put(&tree, &copy_path, index::Status::Pending)
+            .unwrap();
+    }
+    let upload = journal::Operation::Upload { path: copy_path };
+    journal::enqueue(ctx, upload, None);
+}
 
-        fstree::save_tree(&local_tree.lock().unwrap(), "tree.json").unwrap();
+/// "docs/report.txt" -> "docs/report (conflicted copy, user, 2025-01-31).txt"
+fn conflicted_copy_path(path: &str, username: &str) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let date = chrono::Local::now().format("%Y-%m-%d");
+    let mut name = format!("{stem} (conflicted copy, {username}, {date})");
+    if let Some(extension) = path.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
     }
+    path.with_file_name(name).to_string_lossy().to_string()
 }
+
+/// Drops partial downloads nobody resumed for a week.
+pub fn clean_temp_dir(temp_dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(temp_dir) else {
+        return;
+    };
+    let max_age = std::time::Duration::from_secs(7 * 24 * 60 * 60);
+    for entry in entries.flatten() {
+        let stale = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| modified.elapsed().unwrap_or_default() > max_age)
+            .unwrap_or(false);
+        if stale {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// The scheduler all synchronizers share, a new one once the limits changed.
+/// Changing them restarts every profile, so none keeps the old one for long.
+fn shared_scheduler(limits: &TransferLimits) -> Arc<scheduler::Scheduler> {
+    let mut shared = SCHEDULER.lock().unwrap();
+    match &*shared {
+        Some((current, scheduler)) if current == limits => scheduler.clone(),
+        _ => {
+            let scheduler = Arc::new(scheduler::Scheduler::new(limits));
+            *shared = Some((limits.clone(), scheduler.clone()));
+            scheduler
+        }
+    }
+}
+
+/// Has the synchronizers of every profile stop, the next `start` of each
+/// waits for them.
 pub fn stop() {
-    let _ = WATCHER.lock().unwrap().take();
+    DEFERRED_START.lock().unwrap().clear();
+    for current in CURRENT.lock().unwrap().values() {
+        for handle in current {
+            handle.stop();
+        }
+    }
+}
+
+/// Has the synchronizers of `profile` stop, e.g. on logging out of it.
+pub fn stop_profile(profile: &str) {
+    DEFERRED_START.lock().unwrap().remove(profile);
+    if let Some(current) = CURRENT.lock().unwrap().get(profile) {
+        for handle in current {
+            handle.stop();
+        }
+    }
+}
+
+/// Has the synchronizers of a profile renamed from `old_name` stop, the next
+/// `start` of `new_name` waits for them.
+pub fn rename_profile(old_name: &str, new_name: &str) {
+    DEFERRED_START.lock().unwrap().remove(old_name);
+    let mut all = CURRENT.lock().unwrap();
+    if let Some(previous) = all.remove(old_name) {
+        for handle in &previous {
+            handle.stop();
+        }
+        all.entry(new_name.to_string())
+            .or_default()
+            .extend(previous);
+    }
+}
+
+/// Hands a refreshed token of `profile` to its running synchronizers, or to
+/// the `start` put off by a pause, without restarting them. False when none
+/// is running, the caller starts them then.
+pub fn set_token(profile: &str, token: &str) -> bool {
+    if let Some((_, _, backend)) = DEFERRED_START.lock().unwrap().get(profile) {
+        backend.set_token(token);
+        return true;
+    }
+    let all = CURRENT.lock().unwrap();
+    let running: Vec<&SyncHandle> = all
+        .get(profile)
+        .into_iter()
+        .flatten()
+        .filter(|handle| !handle.cancel.is_cancelled())
+        .collect();
+    for handle in &running {
+        handle.backend.set_token(token);
+    }
+    !running.is_empty()
+}
+
+/// Stops the synchronizers and waits for them, before exiting.
+pub async fn shutdown() {
+    stop();
+    let current = std::mem::take(&mut *CURRENT.lock().unwrap());
+    join_all(current.into_values().flatten().map(SyncHandle::stopped)).await;
+}
+
+/// The folders on the server, for choosing which ones to sync. Paths are
+/// relative to the folder `folder_id`, or to the root without one.
+pub async fn remote_folders(
+    backend: &dyn RemoteBackend,
+    folder_id: Option<&str>,
+) -> std::result::Result<Vec<RemoteFolder>, String> {
+    let snapshot = backend.list_tree().await.map_err(|e| e.to_string())?;
+    let Some(folder_id) = folder_id else {
+        return Ok(snapshot.tree.folders(""));
+    };
+    match snapshot.tree.find_id("", folder_id) {
+        Some((_, folder)) => Ok(folder.folders("")),
+        None => Err("the remote folder no longer exists".to_string()),
+    }
+}
+
+/// Checks the sync pairs of all profiles before saving them, giving the new
+/// ones an id. Folders already synced aren't checked again, they may be on a
+/// drive that is away.
+pub fn check_pairs<'a>(
+    pairs: impl IntoIterator<Item = &'a mut SyncPair>,
+    saved: &[SyncPair],
+) -> std::result::Result<(), String> {
+    let mut pairs: Vec<&mut SyncPair> = pairs.into_iter().collect();
+    for pair in pairs.iter_mut() {
+        if pair.id.is_empty() {
+            pair.id = uuid::Uuid::new_v4().to_string();
+        }
+        let known = saved
+            .iter()
+            .any(|saved| saved.local_path == pair.local_path);
+        if !known && !Path::new(&pair.local_path).is_dir() {
+            return Err(format!("{} is not a folder", pair.local_path));
+        }
+    }
+    for (i, pair) in pairs.iter().enumerate() {
+        for other in &pairs[i + 1..] {
+            let (a, b) = (Path::new(&pair.local_path), Path::new(&other.local_path));
+            // both would pick up the same changes
+            if a.starts_with(b) || b.starts_with(a) {
+                return Err(format!(
+                    "{} and {} overlap",
+                    pair.local_path, other.local_path
+                ));
+            }
+        }
+    }
+    Ok(())
 }
+
+/// Starts a failed transfer over, `path` as in its `Transfer`.
+pub fn retry_transfer(path: &str) -> std::result::Result<(), String> {
+    if !transfer::can_retry(path) {
+        return Err(format!("{path} has no failed transfer"));
+    }
+    RETRIES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(root, _)| Path::new(path).starts_with(root))
+        .and_then(|(_, retries)| retries.send(path.to_string()).ok())
+        .ok_or_else(|| "sync is not running".to_string())
+}
+
+/// Holds local changes, remote updates and transfers where they are until
+/// `resume`, keeping the connection and what was already transferred. Local
+/// changes are still noted in the journal, so none are missed. A `start`
+/// meanwhile waits for `resume` too. Kept in the config, the caller saves it.
+pub fn pause() {
+    PAUSED.send_replace(true);
+    CONFIG.lock().unwrap().paused = true;
+}
+
+/// Picks up where `pause` left off.
+pub fn resume() {
+    CONFIG.lock().unwrap().paused = false;
+    PAUSED.send_replace(false);
+    let deferred = std::mem::take(&mut *DEFERRED_START.lock().unwrap());
+    for (profile, (events, data_dir, backend)) in deferred {
+        start(events, data_dir, &profile, backend);
+    }
+}
+
+pub fn is_paused() -> bool {
+    *PAUSED.borrow()
+}
+
+/// Returns once sync isn't paused.
+async fn wait_while_paused() {
+    let _ = PAUSED.subscribe().wait_for(|paused| !paused).await;
+}
+
+/// Returns once sync is paused.
+async fn until_paused() {
+    let _ = PAUSED.subscribe().wait_for(|paused| *paused).await;
+}
+/// Notes a local change in the tree, and has the debouncer push what changed
+/// at the `dirty` paths once things settle.
 fn handle_event(
-    app: tauri::AppHandle,
+    ctx: &SyncContext,
     event: Event,
-    tree: Arc<Mutex<fstree::Node>>,
-    root_path: PathBuf,
     debouncer: &debouncer::Debouncer,
+    dirty: &Arc<Mutex<BTreeSet<PathBuf>>>,
 ) {
+    let SyncContext {
+        root_path,
+        tree,
+        rules,
+        ..
+    } = ctx;
+    let is_write = matches!(
+        event.kind,
+        EventKind::Create(_)
+            | EventKind::Modify(_)
+            | EventKind::Remove(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+    );
+    if is_write && event.paths.iter().any(|path| rules.is_ignore_file(path)) {
+        reload_ignore_rules(ctx);
+        // what it no longer ignores can be anywhere
+        dirty.lock().unwrap().insert(PathBuf::new());
+    }
+    if event
+        .paths
+        .iter()
+        .all(|path| rules.is_ignored(path, path.is_dir()))
+    {
+        return;
+    }
     match event.kind {
         EventKind::Remove(RemoveKind::File)
         | EventKind::Remove(RemoveKind::Folder)
@@ -250,13 +1162,13 @@ fn handle_event(
             let _ = tree
                 .lock()
                 .unwrap()
-                .delete_node(&event.paths[0].to_str().unwrap())
+                .delete_node(event.paths[0].to_str().unwrap())
                 .map_err(|_| println!("failed to delete {:#?}", event.paths[0].to_str().unwrap()));
         }
         EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
             tree.lock()
                 .unwrap()
-                .delete_node(&event.paths[0].to_str().unwrap())
+                .delete_node(event.paths[0].to_str().unwrap())
                 .unwrap();
         }
         EventKind::Modify(ModifyKind::Name(RenameMode::To))
@@ -275,67 +1187,196 @@ fn handle_event(
                     _ => return,
                 }
             }
-            let node = fstree::build_node(&root_path, path);
-
-            if node.is_ok() {
-                tree.lock().unwrap().add_node(node.unwrap()).unwrap();
+            if let Ok(node) = fstree::build_node(root_path, path, rules) {
+                tree.lock().unwrap().add_node(node).unwrap();
             }
         }
 
         _ => {}
     }
 
+    for path in &event.paths {
+        if let Ok(relative) = path.strip_prefix(root_path) {
+            dirty.lock().unwrap().insert(relative.to_path_buf());
+        }
+    }
+
+    // the debouncer runs on its own thread, outside the runtime
+    let runtime = tokio::runtime::Handle::current();
+    let ctx = ctx.clone();
+    let dirty = dirty.clone();
     debouncer.call(move || {
+        let dirty = std::mem::take(&mut *dirty.lock().unwrap());
+        let mut roots: Vec<String> = dirty.iter().map(|path| diff_root(&ctx, path)).collect();
+        roots.dedup();
+        let saved_tree = ctx.index.part(&roots);
         let mut changes: Vec<fstree::Change> = Vec::new();
-        let saved_tree = std::fs::read_to_string("tree.json").unwrap();
-        let tree2: fstree::Node = serde_json::from_str(&saved_tree).unwrap();
-
-        fstree::diff_trees("", Some(&tree2), Some(&tree.lock().unwrap()), &mut changes);
+        fstree::diff_trees(
+            "",
+            Some(&saved_tree),
+            Some(&ctx.tree.lock().unwrap().part(&roots)),
+            &mut changes,
+        );
 
         let changes = fstree::detect_renames(changes);
-        // println!("changes: {:?}", changes);
-        for change in changes.clone() {
-            println!(
-                "local nodeType:{:?} -> {:?}: {}",
-                change.node_type, change.change_type, change.path,
-            );
-
-            match change.change_type {
-                fstree::ChangeType::Added => match change.node_type {
-                    fstree::NodeType::File => api::upload(
-                        app.clone(),
-                        change.id,
-                        change.parent_id,
-                        &root_path,
-                        &change.path,
-                        tree.clone(),
-                    ),
+        runtime.block_on(push_changes(&ctx, changes));
+    })
+}
+
+/// Where to look for what changed at `path`: the topmost folder above it the
+/// index doesn't have yet, so a new folder goes up along with what is in it.
+fn diff_root(ctx: &SyncContext, path: &Path) -> String {
+    let mut ancestors: Vec<&Path> = path.ancestors().collect();
+    ancestors.reverse();
+    for ancestor in ancestors {
+        let ancestor = ancestor.to_string_lossy();
+        if ancestor.is_empty() {
+            continue;
+        }
+        if !ctx.index.contains(&ancestor).unwrap() {
+            return ancestor.to_string();
+        }
+    }
+    path.to_string_lossy().to_string()
+}
+
+/// Applies an edited `.syncignore`. What it ignores now is left alone on both
+/// sides, what it no longer ignores gets synced.
+fn reload_ignore_rules(ctx: &SyncContext) {
+    println!("Reloading {}", filter::IGNORE_FILE);
+    ctx.rules.reload();
+    let mut fresh = match fstree::build_tree(&ctx.root_path, &ctx.rules) {
+        Ok(fresh) => fresh,
+        Err(e) => return println!("Failed to scan {}: {e}", ctx.root_path.display()),
+    };
+    let mut tree = ctx.tree.lock().unwrap();
+    let ignored = tree.topmost("", &|path, is_dir| ctx.rules.is_ignored(path, is_dir));
+    for path in ignored {
+        println!("No longer syncing {path}");
+        journal::discard(ctx, &path);
+        ctx.index.remove(&path).unwrap();
+    }
+    fresh.restore_ids(&tree);
+    *tree = fresh;
+}
+
+/// Removes the local copies of what was taken out of selective sync, unless it
+/// holds changes that aren't uploaded yet, from `saved_tree` too.
+fn drop_deselected(ctx: &SyncContext, saved_tree: &mut fstree::Node) {
+    let deselected = saved_tree.topmost("", &|path, is_dir| ctx.rules.is_deselected(path, is_dir));
+    for path in deselected {
+        if ctx.index.has_pending(&path).unwrap() {
+            println!("Keeping {path}, it has changes not uploaded yet");
+            continue;
+        }
+        println!("Removing {path}, it is no longer synced");
+        let local = ctx.root_path.join(&path);
+        let removed = if local.is_dir() {
+            std::fs::remove_dir_all(&local)
+        } else {
+            std::fs::remove_file(&local)
+        };
+        match removed {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                println!("Failed to remove {}: {e}", local.display())
+            }
+            _ => {
+                ctx.index.remove(&path).unwrap();
+                saved_tree.delete_node(local.to_str().unwrap()).unwrap();
+            }
+        }
+    }
+}
+
+/// Uploads changes made while the app was closed by diffing the last saved tree
+/// against a fresh scan of the folder. The first time, the folder is taken as
+/// it is.
+async fn reconcile(ctx: &SyncContext, saved_tree: Option<fstree::Node>) {
+    let tree = &ctx.tree;
+    let Some(saved_tree) = saved_tree else {
+        let tree = tree.lock().unwrap();
+        ctx.index.put(&tree, "", index::Status::Synced).unwrap();
+        return;
+    };
+    tree.lock().unwrap().restore_ids(&saved_tree);
+
+    let mut changes: Vec<fstree::Change> = Vec::new();
+    fstree::diff_trees(
+        "",
+        Some(&saved_tree),
+        Some(&tree.lock().unwrap()),
+        &mut changes,
+    );
+    let changes = fstree::detect_renames(changes);
+    push_changes(ctx, changes).await;
+}
+
+/// Queues the changes in the journal, which sends them on as soon as the
+/// server is reachable, and records them in the index. Uploads go last, where
+/// the folders they need exist and they can run side by side.
+async fn push_changes(ctx: &SyncContext, changes: Vec<fstree::Change>) {
+    let SyncContext {
+        root_path,
+        tree,
+        rules,
+        ..
+    } = ctx;
+    let mut uploads = vec![];
+    for change in changes.clone() {
+        println!(
+            "local nodeType:{:?} -> {:?}: {}",
+            change.node_type, change.change_type, change.path,
+        );
+        let id = change.id.lock().unwrap().clone();
+        let path = change.path.clone();
+        match change.change_type {
+            fstree::ChangeType::Added | fstree::ChangeType::Modified => {
+                ctx.index
+                    .put(&tree.lock().unwrap(), &path, index::Status::Pending)
+                    .unwrap();
+                match change.node_type {
+                    fstree::NodeType::File => {
+                        uploads.push((journal::Operation::Upload { path }, id))
+                    }
                     fstree::NodeType::Folder => {
-                        api::create_folder(&change.path, change.id, change.parent_id)
+                        journal::enqueue(ctx, journal::Operation::Mkdir { path }, id.as_deref())
                     }
-                },
-                fstree::ChangeType::Deleted => api::delete(change.id, &change.path),
-                fstree::ChangeType::Renamed { from } => {
-                    let node =
-                        fstree::build_node(&Path::new(&root_path), &root_path.join(&change.path))
-                            .unwrap();
-
-                    api::rename(change.id.clone(), change.parent_id, &from, &change.path);
-                    *node.id.lock().unwrap() = change.id.lock().unwrap().clone();
-                    tree.lock().unwrap().add_node(node).unwrap();
                 }
-                fstree::ChangeType::Modified => api::upload(
-                    app.clone(),
-                    change.id,
-                    change.parent_id,
-                    &root_path,
-                    &change.path,
-                    tree.clone(),
-                ),
+            }
+            fstree::ChangeType::Deleted => {
+                ctx.index.remove(&path).unwrap();
+                match id {
+                    Some(id) => {
+                        let op = journal::Operation::Delete {
+                            id: id.clone(),
+                            path,
+                        };
+                        journal::enqueue(ctx, op, Some(&id));
+                    }
+                    None => journal::discard(ctx, &path),
+                }
+            }
+            fstree::ChangeType::Renamed { from } => {
+                let mut node =
+                    fstree::build_node(root_path, &root_path.join(&change.path), rules).unwrap();
+                // what is inside moved along and keeps its ids
+                let saved = ctx.index.part(std::slice::from_ref(&from));
+                if let Some(saved) = saved.find(&from) {
+                    node.restore_ids(saved);
+                }
+                *node.id.lock().unwrap() = id.clone();
+                let mut tree = tree.lock().unwrap();
+                tree.add_node(node).unwrap();
+                ctx.index
+                    .rename(&tree, &from, &path, index::Status::Pending)
+                    .unwrap();
+                drop(tree);
+                let op = journal::Operation::Rename { from, path };
+                journal::enqueue(ctx, op, id.as_deref());
             }
         }
-        if !changes.is_empty() {
-            fstree::save_tree(&tree.lock().unwrap(), "tree.json").unwrap();
-        }
-    })
+    }
+    for (op, id) in uploads {
+        journal::enqueue(ctx, op, id.as_deref());
+    }
 }
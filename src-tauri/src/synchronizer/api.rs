@@ -1,335 +1,406 @@
-use futures_util::StreamExt;
-use futures_util::TryStreamExt;
-use reqwest::blocking::multipart::Part;
-use reqwest::blocking::Client;
+use async_trait::async_trait;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt, TryStreamExt};
+use native_tls::TlsConnector;
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, StatusCode};
 use serde_json::json;
-use std::sync::{Arc, Mutex};
-use std::{fs, io::Write, path::PathBuf};
-use tauri::async_runtime::block_on;
-use tauri::Manager;
-use tokio_util::io::ReaderStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+use tungstenite::{http::Uri, ClientRequestBuilder, Message};
 
-use crate::synchronizer::{fstree, TRANSFERS};
-use crate::types::{Transfer, TransferState, TransferType};
-use crate::CONFIG;
-pub fn rename(
-    id: Arc<Mutex<Option<String>>>,
-    parent_id: Arc<Mutex<Option<String>>>,
-    path: &str,
-    destination: &str,
-) {
-    let id = id.lock().unwrap().clone();
-    if id.is_none() {
-        return;
-    }
-    let id = id.unwrap();
-    let parent_id = parent_id.lock().unwrap().clone();
-    let client = Client::new();
-    let config = CONFIG.lock().unwrap();
-    let server = config.server_url.to_owned();
-    let token = config.token.as_ref().unwrap().value.clone();
-    let name = std::path::Path::new(destination)
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
-    let _ = client
-        .put(format!("{server}/files/{id}"))
-        .json(&json!({
-            "path": path,
-            "name": name,
-            "destination": destination,
-            "parentId": parent_id.unwrap_or_default()
-        }))
-        .header("authorization", token)
-        .send()
-        .map_err(|_| (println!("Failed to rename {path}")));
+use crate::synchronizer::backend::{
+    BackendError, BackendResult, Delta, Download, NewUpload, RemoteBackend, RemoteUpdate, Snapshot,
+    Tokens, UpdateStream, UploadBody,
+};
+use crate::synchronizer::{throttle, tls};
+use crate::types::{Profile, SocketResponse, TransferType};
+
+/// The file-transfer server, over its REST endpoints and websocket.
+pub struct HttpBackend {
+    client: Client,
+    tls: TlsConnector,
+    server_url: String,
+    /// Swapped for a fresh one while the synchronizers run, see `set_token`.
+    token: Mutex<Option<String>>,
+    ping_interval: Duration,
+    pong_timeout: Duration,
 }
 
-pub fn create_folder(
-    path: &str,
-    id: Arc<Mutex<Option<String>>>,
-    parent_id: Arc<Mutex<Option<String>>>,
-) {
-    let client = Client::new();
-    let config = CONFIG.lock().unwrap();
-    let server = config.server_url.to_owned();
-    let token = config.token.as_ref().unwrap().value.clone();
-    let parent_id = parent_id.lock().unwrap().clone().unwrap();
-    let name = std::path::Path::new(path)
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
-    let json_string =
-        json!({"name": name, "parentFolderId": parent_id, "isFolder": true,"contentType":"folder", "size": 0}).to_string();
-    let part = Part::text(json_string)
-        .mime_str("application/json")
-        .unwrap()
-        .file_name("request.json");
-    let form = reqwest::blocking::multipart::Form::new().part("request", part);
+impl HttpBackend {
+    /// Validates certificates against the system roots only.
+    pub fn new(server_url: &str, token: Option<String>) -> Self {
+        Self::with_tls(server_url, token, TlsConnector::new().unwrap())
+    }
+
+    pub fn with_tls(server_url: &str, token: Option<String>, tls: TlsConnector) -> Self {
+        Self {
+            client: tls::http_client(tls.clone()),
+            tls,
+            server_url: server_url.trim_end_matches('/').to_string(),
+            token: Mutex::new(token),
+            ping_interval: Duration::from_secs(15),
+            pong_timeout: Duration::from_secs(10),
+        }
+    }
 
-    let res = client
-        .post(format!("{server}/files"))
-        .header("authorization", token)
-        .multipart(form)
-        .send()
-        .map_err(|_| (println!("Failed to create folder {path}")));
+    /// Pings the websocket every `interval` and gives up on it when nothing,
+    /// pong or otherwise, arrives within `timeout` after a ping.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.ping_interval = interval;
+        self.pong_timeout = timeout;
+        self
+    }
+
+    /// Uses the profile's server, token and certificate settings. Settings that
+    /// fail to load fall back to the system roots (the config window rejects
+    /// them anyway).
+    pub fn from_profile(profile: &Profile) -> Self {
+        let tls = tls::connector(profile).unwrap_or_else(|e| {
+            println!("Ignoring TLS settings: {e}");
+            TlsConnector::new().unwrap()
+        });
+        Self::with_tls(
+            &profile.server_url,
+            profile.token.as_ref().map(|token| token.value.clone()),
+            tls,
+        )
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.server_url)
+    }
 
-    if res.is_err() || !res.as_ref().unwrap().status().is_success() {
-        return;
+    fn token(&self) -> String {
+        self.token.lock().unwrap().clone().unwrap_or_default()
     }
-    let resp: serde_json::Value = res.unwrap().json().unwrap();
-    id.lock()
-        .unwrap()
-        .replace(resp["id"].as_str().unwrap().to_string());
 }
 
-pub fn delete(id: Arc<Mutex<Option<String>>>, path: &str) {
-    let id = id.lock().unwrap().clone();
-    if id.is_none() {
-        return;
+fn check(resp: reqwest::Result<reqwest::Response>) -> BackendResult<reqwest::Response> {
+    let resp = resp.map_err(transport)?;
+    match resp.status() {
+        status if status.is_success() => Ok(resp),
+        StatusCode::NOT_FOUND => Err(BackendError::NotFound),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(BackendError::Unauthorized),
+        status => Err(BackendError::Status(status.as_u16())),
     }
-    let id = id.unwrap();
-    let client = Client::new();
-    let config = CONFIG.lock().unwrap();
-    let server = config.server_url.to_owned();
-    let token = config.token.as_ref().unwrap().value.clone();
-    let _ = client
-        .delete(format!("{server}/files/{id}"))
-        .json(&json!({
-            "path": path
-        }))
-        .header("authorization", token)
-        .send()
-        .map_err(|_| (println!("Failed to delete {path}")));
 }
 
-pub fn upload(
-    app: tauri::AppHandle,
-    id: Arc<Mutex<Option<String>>>,
-    parent_id: Arc<Mutex<Option<String>>>,
-    root_path: &PathBuf,
-    destination: &str,
-    tree: Arc<Mutex<fstree::Node>>,
-) {
-    let file_id = id.lock().unwrap().clone();
-    let parent_id = parent_id.lock().unwrap().clone();
+fn transport(e: impl std::fmt::Display) -> BackendError {
+    BackendError::Transport(e.to_string())
+}
 
-    let config = CONFIG.lock().unwrap().clone();
-    let server = config.server_url.to_owned();
-    let token = config.token.as_ref().unwrap().value.clone();
-    let absolute_path = root_path.join(destination);
-    println!("Uploading {:?}", absolute_path);
+fn header(resp: &reqwest::Response, name: &str) -> Option<String> {
+    resp.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
 
-    let destination = destination.to_string();
-    let window = app.get_window("main");
-    std::thread::spawn(move || {
-        block_on(async move {
-            let client = reqwest::Client::new();
-            let file = tokio::fs::File::open(&absolute_path).await;
-            if file.is_err() {
-                println!(
-                    "Failed to open file: can't upload {}",
-                    absolute_path.display()
-                );
-                return;
-            }
-            let file = file.unwrap();
-            let file_size = file.metadata().await.unwrap().len();
-            let file_name = absolute_path.file_name().unwrap().to_str().unwrap();
-            let _destination = destination.clone();
-            let _window = window.clone();
-            let stream = ReaderStream::new(file);
-            let mut total = 0;
-            let byte_stream = stream.inspect_ok(move |chunk| {
-                total += chunk.len();
-                let progress = ((total as f64 / file_size as f64) * 100.0) as u8;
-                let transfer = Transfer {
-                    progress: progress as u32,
-                    state: TransferState::Active,
-                    r#type: TransferType::Upload,
-                    path: _destination.clone(),
-                };
-                if let Some(ref window) = _window {
-                    window.emit("transfer", &transfer).unwrap()
-                };
-                TRANSFERS
-                    .lock()
-                    .unwrap()
-                    .insert(_destination.clone().into(), transfer);
-            });
-            let _destination = destination.clone();
-            let _window = window.clone();
-            let body = reqwest::Body::wrap_stream(byte_stream);
-            TRANSFERS.lock().unwrap().insert(
-                destination.clone().into(),
-                Transfer {
-                    progress: 0,
-                    state: TransferState::Active,
-                    r#type: TransferType::Upload,
-                    path: destination.clone(),
-                },
-            );
-            let file_name_encoded: String = urlencoding::encode(&file_name).to_string();
-            println!("{:?}", file_id);
-            let resp = client
-                .post(format!("{server}/upload"))
-                .header("Content-Type", "application/octet-stream")
-                .header("fileName", file_name_encoded)
-                .header("Content-Length", file_size.to_string())
-                .header("authorization", token)
-                .header("elementId", file_id.unwrap_or_default())
-                .header("parentId", parent_id.unwrap_or_default())
-                .body(body);
-            let resp = resp.send().await;
-            // Mark as completed
-            let resp: serde_json::Value = resp.unwrap().json().await.unwrap();
-            let transfer = Transfer {
-                progress: 100,
-                state: TransferState::Completed,
-                r#type: TransferType::Upload,
-                path: destination.clone(),
-            };
-            if let Some(window) = window {
-                window.emit("transfer", &transfer).unwrap()
-            };
-            TRANSFERS
-                .lock()
-                .unwrap()
-                .insert(destination.clone().into(), transfer);
-            id.lock()
-                .unwrap()
-                .replace(resp["id"].as_str().unwrap().to_string());
-            println!("id is {}", id.lock().unwrap().clone().unwrap());
-            fstree::save_tree(&tree.lock().unwrap(), "tree.json").unwrap();
-        });
-    });
+fn tokens(resp: &reqwest::Response) -> BackendResult<Tokens> {
+    Ok(Tokens {
+        token: header(resp, "authorization").ok_or(BackendError::Unauthorized)?,
+        refresh_token: header(resp, "x-refresh-token").ok_or(BackendError::Unauthorized)?,
+    })
+}
+
+async fn json_id(resp: reqwest::Response, key: &str) -> BackendResult<String> {
+    let resp: serde_json::Value = resp.json().await.map_err(transport)?;
+    resp[key]
+        .as_str()
+        .map(|id| id.to_string())
+        .ok_or(BackendError::Transport(format!("missing {key}")))
 }
 
-pub async fn download(
-    app: tauri::AppHandle,
-    root_path: &PathBuf,
-    path: String,
-    id: Arc<Mutex<Option<String>>>,
-    hash: String,
-    local_tree: Arc<Mutex<fstree::Node>>,
-) {
-    let id = id.lock().unwrap().clone();
-    let app_dir = app.path_resolver().app_data_dir().unwrap();
-    let temp_dir = app_dir.join("temp");
-    fs::create_dir_all(&temp_dir).unwrap();
-    let mut bytes = path.as_bytes().to_vec();
-    bytes.extend(hash.as_bytes());
-    let temp_name = fstree::hash_bytes(&bytes);
-    let temp_file_path = temp_dir.join(temp_name);
-    println!("Downloading {} to {}", path, temp_file_path.display());
-    if id.is_none() || temp_file_path.exists() {
-        return;
+fn remote_update(text: &str) -> BackendResult<RemoteUpdate> {
+    let resp: SocketResponse = serde_json::from_str(text).map_err(transport)?;
+    if resp.r#type == "delta" {
+        let delta = serde_json::from_value(resp.data).map_err(transport)?;
+        return Ok(RemoteUpdate::Delta(delta));
     }
-    let config = CONFIG.lock().unwrap().clone();
-    let server = config.server_url.to_owned();
-    let token = config.token.as_ref().unwrap().value.clone();
-    let full_path = root_path.join(&path);
-    let destination = full_path.clone();
-    let window = app.get_window("main");
-    let client = reqwest::Client::new();
+    let tree = serde_json::from_value(resp.data).map_err(transport)?;
+    Ok(RemoteUpdate::Tree(Snapshot {
+        tree,
+        cursor: resp.cursor,
+    }))
+}
 
-    let id = id.unwrap();
-    let response = client
-        .get(format!("{server}/files/{id}/download"))
-        .header("authorization", token)
-        .send()
-        .await;
-    let resp = match response {
-        Ok(r) if r.status().is_success() => r,
-        _ => {
-            let _ = fs::remove_file(&temp_file_path)
-                .map_err(|e| println!("Failed to remove temp file: {}", e));
-            return;
-        } // early return on error or non-2xx
-    };
+/// Start offset of a 206 response, from `Content-Range: bytes start-end/total`.
+fn content_range_start(resp: &reqwest::Response) -> Option<u64> {
+    let range = resp.headers().get("content-range")?.to_str().ok()?;
+    let range = range.strip_prefix("bytes ")?;
+    range.split('-').next()?.parse().ok()
+}
 
-    let total_size = match resp.content_length() {
-        Some(size) => size,
-        None => {
-            let _ = fs::remove_file(&temp_file_path)
-                .map_err(|e| println!("Failed to remove temp file: {}", e));
-            return;
-        }
-    };
+#[async_trait]
+impl RemoteBackend for HttpBackend {
+    async fn auth(&self, username: &str, password: &str) -> BackendResult<Tokens> {
+        let resp = check(
+            self.client
+                .post(self.url("/users/auth/login"))
+                .json(&json!({"username": username, "password": password}))
+                .send()
+                .await,
+        )?;
+        tokens(&resp)
+    }
 
-    TRANSFERS.lock().unwrap().insert(
-        destination.clone(),
-        Transfer {
-            progress: 0,
-            state: TransferState::Active,
-            r#type: TransferType::Download,
-            path: destination.to_string_lossy().to_string(),
-        },
-    );
+    async fn refresh_auth(&self, refresh_token: &str) -> BackendResult<Tokens> {
+        let resp = check(
+            self.client
+                .post(self.url("/users/auth/keep-alive"))
+                .header("x-refresh-token", refresh_token)
+                .send()
+                .await,
+        )?;
+        tokens(&resp)
+    }
 
-    // Ensure parent directories exist
-    if let Some(parent) = destination.parent() {
-        fs::create_dir_all(parent).unwrap();
+    fn set_token(&self, token: &str) {
+        *self.token.lock().unwrap() = Some(token.to_string());
     }
 
-    let mut file = fs::File::create(&temp_file_path).unwrap();
-    let mut downloaded: u64 = 0;
-    let mut stream = resp.bytes_stream();
+    async fn list_tree(&self) -> BackendResult<Snapshot> {
+        let resp = check(
+            self.client
+                .get(self.url("/files"))
+                .header("authorization", self.token())
+                .send()
+                .await,
+        )?;
+        let cursor = header(&resp, "x-cursor").and_then(|cursor| cursor.parse().ok());
+        let tree = resp.json().await.map_err(transport)?;
+        Ok(Snapshot { tree, cursor })
+    }
 
-    while let Some(chunk_result) = stream.next().await {
-        let chunk = match chunk_result {
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("Error downloading chunk: {e}");
-                let _ = fs::remove_file(&temp_file_path)
-                    .map_err(|e| println!("Failed to remove temp file: {}", e));
-                return;
+    async fn changes_since(&self, cursor: u64) -> BackendResult<Option<Delta>> {
+        let resp = self
+            .client
+            .get(self.url("/files/changes"))
+            .query(&[("cursor", cursor)])
+            .header("authorization", self.token())
+            .send()
+            .await;
+        if let Ok(resp) = &resp {
+            if resp.status() == StatusCode::GONE {
+                return Ok(None);
             }
-        };
-        file.write_all(&chunk).unwrap();
-        downloaded += chunk.len() as u64;
+        }
+        let resp = check(resp)?;
+        resp.json().await.map(Some).map_err(transport)
+    }
+
+    async fn watch_tree(&self) -> BackendResult<UpdateStream> {
+        let socket_url = self
+            .url("/websocket")
+            .replace("https://", "wss://")
+            .replace("http://", "ws://");
+        let uri: Uri = socket_url.parse().map_err(transport)?;
+        let request = ClientRequestBuilder::new(uri).with_header("authorization", self.token());
+        let connector = Connector::NativeTls(self.tls.clone());
+        let (socket, _response) =
+            connect_async_tls_with_config(request, None, false, Some(connector))
+                .await
+                .map_err(transport)?;
+        let (mut sink, mut stream) = socket.split();
+        let (tx, mut rx) = mpsc::channel(16);
+        let (interval, timeout) = (self.ping_interval, self.pong_timeout);
+        // a connection that silently died (sleep, NAT timeout, pulled cable)
+        // never errors on its own, so the heartbeat ends it instead
+        tokio::spawn(async move {
+            let mut pings = tokio::time::interval_at(Instant::now() + interval, interval);
+            let mut last_seen = Instant::now();
+            loop {
+                tokio::select! {
+                    _ = pings.tick() => {
+                        if sink.send(Message::Ping(Default::default())).await.is_err() {
+                            return;
+                        }
+                    }
+                    _ = tokio::time::sleep_until(last_seen + interval + timeout) => {
+                        let timed_out = BackendError::Transport("heartbeat timed out".to_string());
+                        let _ = tx.send(Err(timed_out)).await;
+                        return;
+                    }
+                    msg = stream.next() => {
+                        last_seen = Instant::now();
+                        let update = match msg {
+                            None => return,
+                            Some(Ok(Message::Text(text))) => remote_update(&text),
+                            Some(Ok(_)) => continue,
+                            Some(Err(e)) => Err(transport(e)),
+                        };
+                        if tx.send(update).await.is_err() {
+                            return;
+                        }
+                    }
+                    _ = tx.closed() => return,
+                }
+            }
+        });
+        let updates = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx));
+        Ok(updates.boxed())
+    }
 
-        let progress = (downloaded as f64 / total_size as f64) * 100.0;
+    async fn create_folder(&self, name: &str, parent_id: &str) -> BackendResult<String> {
+        let json_string =
+            json!({"name": name, "parentFolderId": parent_id, "isFolder": true,"contentType":"folder", "size": 0}).to_string();
+        let part = Part::text(json_string)
+            .mime_str("application/json")
+            .unwrap()
+            .file_name("request.json");
+        let form = Form::new().part("request", part);
+        let resp = check(
+            self.client
+                .post(self.url("/files"))
+                .header("authorization", self.token())
+                .multipart(form)
+                .send()
+                .await,
+        )?;
+        json_id(resp, "id").await
+    }
 
-        let transfer = Transfer {
-            progress: progress as u32,
-            state: TransferState::Active,
-            r#type: TransferType::Download,
-            path: destination.to_string_lossy().to_string(),
-        };
-        if let Some(ref window) = window {
-            window.emit("transfer", &transfer).unwrap();
-        }
-        TRANSFERS
-            .lock()
+    async fn rename(
+        &self,
+        id: &str,
+        parent_id: Option<&str>,
+        path: &str,
+        destination: &str,
+    ) -> BackendResult<()> {
+        let name = std::path::Path::new(destination)
+            .file_name()
             .unwrap()
-            .insert(destination.clone(), transfer);
+            .to_str()
+            .unwrap()
+            .to_string();
+        check(
+            self.client
+                .put(self.url(&format!("/files/{id}")))
+                .json(&json!({
+                    "path": path,
+                    "name": name,
+                    "destination": destination,
+                    "parentId": parent_id.unwrap_or_default()
+                }))
+                .header("authorization", self.token())
+                .send()
+                .await,
+        )?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str, path: &str) -> BackendResult<()> {
+        check(
+            self.client
+                .delete(self.url(&format!("/files/{id}")))
+                .json(&json!({
+                    "path": path
+                }))
+                .header("authorization", self.token())
+                .send()
+                .await,
+        )?;
+        Ok(())
     }
 
-    let _ = fs::copy(&temp_file_path, &destination)
-        .map_err(|e| println!("Failed to Move from temp dir: {}", e));
-    let _ =
-        fs::remove_file(&temp_file_path).map_err(|e| println!("Failed to remove temp file: {}", e));
-    // Add to local tree
-    if let Ok(Some(node)) = fstree::build_node(&root_path, &destination).map(Some) {
-        local_tree.lock().unwrap().add_node(node).unwrap();
-        fstree::save_tree(&local_tree.lock().unwrap(), "tree.json").unwrap();
+    async fn start_upload(&self, upload: &NewUpload) -> BackendResult<String> {
+        let resp = check(
+            self.client
+                .post(self.url("/upload/sessions"))
+                .header("authorization", self.token())
+                .json(&json!({
+                    "fileName": upload.file_name,
+                    "size": upload.size,
+                    "hash": upload.hash,
+                    "chunkSize": upload.chunk_size,
+                    "elementId": upload.element_id.clone().unwrap_or_default(),
+                    "parentId": upload.parent_id.clone().unwrap_or_default()
+                }))
+                .send()
+                .await,
+        )?;
+        json_id(resp, "sessionId").await
+    }
+
+    async fn upload_chunk(
+        &self,
+        session_id: &str,
+        index: u64,
+        hash: &str,
+        len: u64,
+        body: UploadBody,
+    ) -> BackendResult<()> {
+        check(
+            self.client
+                .put(self.url(&format!("/upload/sessions/{session_id}/chunks/{index}")))
+                .header("Content-Type", "application/octet-stream")
+                .header("Content-Length", len.to_string())
+                .header("authorization", self.token())
+                .header("x-chunk-hash", hash)
+                .body(reqwest::Body::wrap_stream(throttle::limit(
+                    body,
+                    TransferType::Upload,
+                )))
+                .send()
+                .await,
+        )?;
+        Ok(())
     }
 
-    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+    async fn finish_upload(&self, session_id: &str) -> BackendResult<String> {
+        let resp = check(
+            self.client
+                .post(self.url(&format!("/upload/sessions/{session_id}/complete")))
+                .header("authorization", self.token())
+                .send()
+                .await,
+        )?;
+        json_id(resp, "id").await
+    }
 
-    let transfer = Transfer {
-        progress: 100,
-        state: TransferState::Completed,
-        r#type: TransferType::Download,
-        path: destination.to_string_lossy().to_string(),
-    };
-    if let Some(window) = window {
-        window.emit("transfer", &transfer).unwrap()
-    };
-    TRANSFERS.lock().unwrap().insert(destination, transfer);
+    async fn download(&self, id: &str, offset: u64, etag: Option<&str>) -> BackendResult<Download> {
+        let request = || {
+            self.client
+                .get(self.url(&format!("/files/{id}/download")))
+                .header("authorization", self.token())
+        };
+        let ranged = offset > 0 && etag.is_some();
+        let first = match etag.filter(|_| ranged) {
+            Some(etag) => request()
+                .header("Range", format!("bytes={offset}-"))
+                .header("If-Range", etag),
+            None => request(),
+        };
+        let mut resp = check(first.send().await)?;
+        // a 200 means the server ignored the range or the file changed
+        let resumed = ranged
+            && resp.status() == StatusCode::PARTIAL_CONTENT
+            && content_range_start(&resp) == Some(offset);
+        if !resumed && resp.status() == StatusCode::PARTIAL_CONTENT {
+            // some other part of the file, start over without a range
+            println!("Download of {id} returned the wrong range, fetching the whole file");
+            resp = check(request().send().await)?;
+        }
+        // anything but the requested range or the whole file can't be written out as the file
+        if !resumed && resp.status() != StatusCode::OK {
+            return Err(BackendError::Status(resp.status().as_u16()));
+        }
+        let size = resp.content_length().ok_or(BackendError::Transport(
+            "missing content length".to_string(),
+        ))?;
+        let etag = header(&resp, "etag");
+        let body = throttle::limit(resp.bytes_stream(), TransferType::Download)
+            .map_err(transport)
+            .boxed();
+        Ok(Download {
+            resumed,
+            size,
+            etag,
+            body,
+        })
+    }
 }
@@ -0,0 +1,127 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream::BoxStream;
+use serde::Deserialize;
+use std::fmt;
+
+use crate::synchronizer::fstree;
+
+#[derive(Debug)]
+pub enum BackendError {
+    /// The file, folder or upload session doesn't exist (anymore).
+    NotFound,
+    Unauthorized,
+    Status(u16),
+    Transport(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::NotFound => write!(f, "not found"),
+            BackendError::Unauthorized => write!(f, "unauthorized"),
+            BackendError::Status(status) => write!(f, "unexpected status {status}"),
+            BackendError::Transport(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+pub type BackendResult<T> = Result<T, BackendError>;
+pub type ByteStream = BoxStream<'static, BackendResult<Bytes>>;
+pub type UploadBody = BoxStream<'static, std::io::Result<Bytes>>;
+pub type UpdateStream = BoxStream<'static, BackendResult<RemoteUpdate>>;
+
+/// The whole remote tree, with the cursor of the last change it includes when
+/// the server supports deltas.
+pub struct Snapshot {
+    pub tree: fstree::Node,
+    pub cursor: Option<u64>,
+}
+
+/// The changes between two cursors, cursors only ever grow.
+#[derive(Deserialize, Debug)]
+pub struct Delta {
+    pub since: u64,
+    pub cursor: u64,
+    pub changes: Vec<fstree::RemoteChange>,
+}
+
+pub enum RemoteUpdate {
+    Tree(Snapshot),
+    Delta(Delta),
+}
+
+pub struct Tokens {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+pub struct NewUpload {
+    pub file_name: String,
+    pub size: u64,
+    pub hash: String,
+    pub chunk_size: u64,
+    pub element_id: Option<String>,
+    pub parent_id: Option<String>,
+}
+
+pub struct Download {
+    /// The body starts at the requested offset instead of the beginning of the file.
+    pub resumed: bool,
+    /// Length of the body.
+    pub size: u64,
+    pub etag: Option<String>,
+    pub body: ByteStream,
+}
+
+/// The server the synchronizer mirrors the local folder to.
+#[async_trait]
+pub trait RemoteBackend: Send + Sync {
+    async fn auth(&self, username: &str, password: &str) -> BackendResult<Tokens>;
+
+    async fn refresh_auth(&self, refresh_token: &str) -> BackendResult<Tokens>;
+
+    /// Makes the next requests with `token`, e.g. once it was refreshed.
+    fn set_token(&self, token: &str);
+
+    async fn list_tree(&self) -> BackendResult<Snapshot>;
+
+    /// Returns `None` when the server no longer keeps changes that old.
+    async fn changes_since(&self, cursor: u64) -> BackendResult<Option<Delta>>;
+
+    /// Yields a delta, or the whole remote tree, every time it changes.
+    async fn watch_tree(&self) -> BackendResult<UpdateStream>;
+
+    /// Returns the id of the new folder.
+    async fn create_folder(&self, name: &str, parent_id: &str) -> BackendResult<String>;
+
+    async fn rename(
+        &self,
+        id: &str,
+        parent_id: Option<&str>,
+        path: &str,
+        destination: &str,
+    ) -> BackendResult<()>;
+
+    async fn delete(&self, id: &str, path: &str) -> BackendResult<()>;
+
+    /// Opens a chunked upload session and returns its id.
+    async fn start_upload(&self, upload: &NewUpload) -> BackendResult<String>;
+
+    async fn upload_chunk(
+        &self,
+        session_id: &str,
+        index: u64,
+        hash: &str,
+        len: u64,
+        body: UploadBody,
+    ) -> BackendResult<()>;
+
+    /// Returns the id of the uploaded file.
+    async fn finish_upload(&self, session_id: &str) -> BackendResult<String>;
+
+    /// Downloads from `offset` if `etag` still matches the file, from the start otherwise.
+    async fn download(&self, id: &str, offset: u64, etag: Option<&str>) -> BackendResult<Download>;
+}
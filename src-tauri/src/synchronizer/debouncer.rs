@@ -4,12 +4,14 @@ use std::time::{Duration, Instant};
 
 pub struct Debouncer {
     inner: Arc<(Mutex<State>, Condvar)>,
+    worker: Option<thread::JoinHandle<()>>,
 }
 
 struct State {
     last_call: Instant,
     updated: bool,
     task: Option<Box<dyn FnOnce() + Send>>,
+    stopped: bool,
 }
 
 impl Debouncer {
@@ -19,13 +21,14 @@ impl Debouncer {
                 last_call: Instant::now(),
                 updated: false,
                 task: None,
+                stopped: false,
             }),
             Condvar::new(),
         ));
 
         let inner_clone = Arc::clone(&inner);
 
-        thread::spawn(move || {
+        let worker = thread::spawn(move || {
             let (lock, cvar) = &*inner_clone;
             let mut last_scheduled = Instant::now();
 
@@ -36,6 +39,10 @@ impl Debouncer {
                 let result = cvar.wait_timeout(state, delay).unwrap();
                 state = result.0;
 
+                if state.stopped {
+                    return;
+                }
+
                 // If updated recently, restart timeout
                 if state.updated {
                     state.updated = false;
@@ -53,7 +60,10 @@ impl Debouncer {
             }
         });
 
-        Self { inner }
+        Self {
+            inner,
+            worker: Some(worker),
+        }
     }
 
     pub fn call<F>(&self, task: F)
@@ -67,4 +77,27 @@ impl Debouncer {
         state.task = Some(Box::new(task));
         cvar.notify_one(); // wake up worker
     }
+
+    /// Drops the pending task and waits for one that is already running.
+    pub fn stop(mut self) {
+        self.halt();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+
+    fn halt(&self) {
+        let (lock, cvar) = &*self.inner;
+        let mut state = lock.lock().unwrap();
+        state.stopped = true;
+        state.task = None;
+        cvar.notify_one();
+    }
+}
+
+/// Drops a pending task instead of running it after its synchronizer stopped.
+impl Drop for Debouncer {
+    fn drop(&mut self) {
+        self.halt();
+    }
 }
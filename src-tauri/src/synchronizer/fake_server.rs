@@ -0,0 +1,863 @@
+//! A stand-in for the file-transfer server that keeps everything in memory,
+//! close enough to the real endpoints to run the synchronizer against it.
+use axum::{
+    body::Bytes,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, Multipart, Path, Query, State,
+    },
+    http::{HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    serve::Listener,
+    Json, Router,
+};
+use futures_util::FutureExt;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+
+use crate::synchronizer::fstree;
+
+pub const USERNAME: &str = "user";
+pub const PASSWORD: &str = "password";
+/// The CA bundle that trusts `start_tls` servers.
+pub const CERTIFICATE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/src/synchronizer/testdata/server.pem"
+);
+const ROOT_ID: &str = "root";
+/// Deltas kept for catching up, older cursors get the whole tree.
+const CHANGE_LOG_LEN: usize = 100;
+
+struct Entry {
+    name: String,
+    parent_id: Option<String>,
+    is_folder: bool,
+    content: Vec<u8>,
+}
+
+struct UploadSession {
+    file_name: String,
+    size: u64,
+    hash: String,
+    element_id: String,
+    parent_id: String,
+    chunks: BTreeMap<u64, Vec<u8>>,
+}
+
+/// What an entry looked like at the last cursor, to work out the next delta.
+struct Seen {
+    name: String,
+    parent_id: Option<String>,
+    path: String,
+    hash: String,
+}
+
+struct Files {
+    entries: HashMap<String, Entry>,
+    sessions: HashMap<String, UploadSession>,
+    next_id: u64,
+    token: Option<String>,
+    refresh_token: Option<String>,
+    cursor: u64,
+    seen: HashMap<String, Seen>,
+    change_log: VecDeque<Value>,
+}
+
+impl Files {
+    fn new_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{prefix}-{}", self.next_id)
+    }
+
+    fn children(&self, id: &str) -> Vec<(&String, &Entry)> {
+        let mut children: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.parent_id.as_deref() == Some(id))
+            .collect();
+        children.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        children
+    }
+
+    fn child(&self, parent_id: &str, name: &str) -> Option<String> {
+        self.children(parent_id)
+            .into_iter()
+            .find(|(_, entry)| entry.name == name)
+            .map(|(id, _)| id.clone())
+    }
+
+    /// `path` is relative to the root, "" being the root itself.
+    fn find(&self, path: &str) -> Option<String> {
+        let mut id = ROOT_ID.to_string();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            id = self.child(&id, name)?;
+        }
+        Some(id)
+    }
+
+    fn insert(&mut self, parent_id: &str, name: &str, is_folder: bool, content: Vec<u8>) -> String {
+        if let Some(id) = self.child(parent_id, name) {
+            self.entries.get_mut(&id).unwrap().content = content;
+            return id;
+        }
+        let id = self.new_id(if is_folder { "folder" } else { "file" });
+        self.entries.insert(
+            id.clone(),
+            Entry {
+                name: name.to_string(),
+                parent_id: Some(parent_id.to_string()),
+                is_folder,
+                content,
+            },
+        );
+        id
+    }
+
+    fn remove(&mut self, id: &str) {
+        let children: Vec<String> = self
+            .children(id)
+            .into_iter()
+            .map(|(id, _)| id.clone())
+            .collect();
+        for child in children {
+            self.remove(&child);
+        }
+        self.entries.remove(id);
+    }
+
+    fn path(&self, id: &str) -> String {
+        let entry = &self.entries[id];
+        match entry.parent_id.as_deref() {
+            None => String::new(),
+            Some(ROOT_ID) => entry.name.clone(),
+            Some(parent_id) => format!("{}/{}", self.path(parent_id), entry.name),
+        }
+    }
+
+    fn seen(&self) -> HashMap<String, Seen> {
+        self.entries
+            .iter()
+            .map(|(id, entry)| {
+                let seen = Seen {
+                    name: entry.name.clone(),
+                    parent_id: entry.parent_id.clone(),
+                    path: self.path(id),
+                    hash: fstree::hash_bytes(&entry.content),
+                };
+                (id.clone(), seen)
+            })
+            .collect()
+    }
+
+    /// Logs what changed since the last cursor as a delta. Deletions use the
+    /// old paths and come first, then moves, then additions and modifications
+    /// with the new paths; the fake server changes one thing at a time so that
+    /// order always applies cleanly.
+    fn record_changes(&mut self) -> Option<Value> {
+        let now = self.seen();
+        let before = std::mem::replace(&mut self.seen, now);
+        let now = &self.seen;
+        let mut changes = vec![];
+        for (id, old) in &before {
+            // only the topmost entry of a deleted folder
+            let parent_kept = old.parent_id.as_ref().is_none_or(|p| now.contains_key(p));
+            if !now.contains_key(id) && parent_kept {
+                changes.push(json!({"op": "deleted", "id": id, "path": old.path}));
+            }
+        }
+        for (id, old) in &before {
+            match now.get(id) {
+                Some(new) if new.name != old.name || new.parent_id != old.parent_id => changes
+                    .push(json!({"op": "moved", "id": id, "from": old.path, "path": new.path})),
+                _ => {}
+            }
+        }
+        for (id, new) in now {
+            // only the topmost entry of an added folder, its node has the rest
+            let parent_existed = new
+                .parent_id
+                .as_ref()
+                .is_none_or(|p| before.contains_key(p));
+            let op = match before.get(id) {
+                None if parent_existed => "added",
+                Some(old) if old.hash != new.hash && !self.entries[id].is_folder => "modified",
+                _ => continue,
+            };
+            let node = self.node(id, &new.path);
+            changes.push(json!({"op": op, "id": id, "path": new.path, "node": node}));
+        }
+        if changes.is_empty() {
+            return None;
+        }
+        self.cursor += 1;
+        let delta = json!({"since": self.cursor - 1, "cursor": self.cursor, "changes": changes});
+        self.change_log.push_back(delta.clone());
+        if self.change_log.len() > CHANGE_LOG_LEN {
+            self.change_log.pop_front();
+        }
+        Some(delta)
+    }
+
+    /// All changes after `cursor` as one delta, `None` once they've been dropped.
+    fn changes_since(&self, cursor: u64) -> Option<Value> {
+        let oldest = self
+            .change_log
+            .front()
+            .map_or(self.cursor, |delta| delta["since"].as_u64().unwrap());
+        if cursor < oldest || cursor > self.cursor {
+            return None;
+        }
+        let changes: Vec<Value> = self
+            .change_log
+            .iter()
+            .filter(|delta| delta["since"].as_u64().unwrap() >= cursor)
+            .flat_map(|delta| delta["changes"].as_array().unwrap().clone())
+            .collect();
+        Some(json!({"since": cursor, "cursor": self.cursor, "changes": changes}))
+    }
+
+    /// The tree in the shape the client's `fstree::Node` deserializes from.
+    fn node(&self, id: &str, path: &str) -> Value {
+        let entry = &self.entries[id];
+        if !entry.is_folder {
+            return json!({
+                "type": "file",
+                "hash": fstree::hash_bytes(&entry.content),
+                "path": path,
+                "id": id,
+                "parent_id": entry.parent_id,
+                "size": entry.content.len(),
+                "mtime": 0,
+            });
+        }
+        let mut content = serde_json::Map::new();
+        let mut hash_input = String::new();
+        let mut size = 0;
+        for (child_id, child) in self.children(id) {
+            let child_path = if path.is_empty() {
+                child.name.clone()
+            } else {
+                format!("{path}/{}", child.name)
+            };
+            let node = self.node(child_id, &child_path);
+            hash_input.push_str(node["hash"].as_str().unwrap());
+            size += node["size"].as_u64().unwrap();
+            content.insert(child.name.clone(), node);
+        }
+        json!({
+            "type": "folder",
+            "hash": fstree::hash_bytes(hash_input.as_bytes()),
+            "content": content,
+            "path": path,
+            "id": id,
+            "parent_id": entry.parent_id,
+            "size": size,
+            "mtime": 0,
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Shared {
+    files: Arc<Mutex<Files>>,
+    changes: broadcast::Sender<Value>,
+    online: watch::Sender<bool>,
+    frozen: watch::Sender<bool>,
+    listings: Arc<AtomicUsize>,
+    rejecting_uploads: Arc<AtomicBool>,
+    /// Chunks from this index on are refused as if the server were down.
+    failing_chunks: Arc<Mutex<Option<u64>>>,
+    chunks_received: Arc<AtomicUsize>,
+    /// Ranged downloads get the whole file back as a 206 starting at 0.
+    misplacing_ranges: Arc<AtomicBool>,
+}
+
+impl Shared {
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let token = headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        let files = self.files.lock().unwrap();
+        match (token, &files.token) {
+            (Some(token), Some(expected)) if token == expected => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+
+    fn changed(&self) {
+        let delta = self.files.lock().unwrap().record_changes();
+        if let Some(delta) = delta {
+            let _ = self.changes.send(delta);
+        }
+    }
+}
+
+pub struct FakeServer {
+    pub url: String,
+    shared: Shared,
+}
+
+impl FakeServer {
+    pub async fn start() -> Self {
+        Self::start_with(None).await
+    }
+
+    /// Like `start`, over HTTPS with the self-signed certificate at `CERTIFICATE`.
+    pub async fn start_tls() -> Self {
+        let identity = native_tls::Identity::from_pkcs8(
+            include_bytes!("testdata/server.pem"),
+            include_bytes!("testdata/server.key"),
+        )
+        .unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        Self::start_with(Some(acceptor.into())).await
+    }
+
+    async fn start_with(tls: Option<tokio_native_tls::TlsAcceptor>) -> Self {
+        let mut entries = HashMap::new();
+        entries.insert(
+            ROOT_ID.to_string(),
+            Entry {
+                name: String::new(),
+                parent_id: None,
+                is_folder: true,
+                content: Vec::new(),
+            },
+        );
+        let shared = Shared {
+            files: Arc::new(Mutex::new(Files {
+                entries,
+                sessions: HashMap::new(),
+                next_id: 0,
+                token: None,
+                refresh_token: None,
+                cursor: 0,
+                seen: HashMap::new(),
+                change_log: VecDeque::new(),
+            })),
+            changes: broadcast::channel(16).0,
+            online: watch::channel(true).0,
+            frozen: watch::channel(false).0,
+            listings: Arc::new(AtomicUsize::new(0)),
+            rejecting_uploads: Arc::new(AtomicBool::new(false)),
+            failing_chunks: Arc::new(Mutex::new(None)),
+            chunks_received: Arc::new(AtomicUsize::new(0)),
+            misplacing_ranges: Arc::new(AtomicBool::new(false)),
+        };
+        {
+            let mut files = shared.files.lock().unwrap();
+            files.seen = files.seen();
+        }
+        let router = Router::new()
+            .route("/actuator/health", get(|| async { "UP" }))
+            .route("/users/auth/login", post(login))
+            .route("/users/auth/keep-alive", post(keep_alive))
+            .route("/files", get(list).post(create_folder))
+            .route("/files/changes", get(changes))
+            .route("/files/{id}", put(rename).delete(delete))
+            .route("/files/{id}/download", get(download))
+            .route("/upload/sessions", post(start_upload))
+            // whole chunks, larger than the default limit
+            .route(
+                "/upload/sessions/{id}/chunks/{index}",
+                put(upload_chunk).layer(DefaultBodyLimit::disable()),
+            )
+            .route("/upload/sessions/{id}/complete", post(finish_upload))
+            .route("/websocket", get(websocket))
+            .layer(middleware::from_fn_with_state(
+                shared.clone(),
+                refuse_offline,
+            ))
+            .with_state(shared.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let url = match tls {
+            Some(acceptor) => {
+                let listener = TlsListener { listener, acceptor };
+                tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+                format!("https://{address}")
+            }
+            None => {
+                tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+                format!("http://{address}")
+            }
+        };
+        Self { url, shared }
+    }
+
+    /// Creates or overwrites a file as another client would, creating missing folders.
+    pub fn write(&self, path: &str, content: &[u8]) {
+        {
+            let mut files = self.shared.files.lock().unwrap();
+            let (folder, name) = path.rsplit_once('/').unwrap_or(("", path));
+            let mut parent_id = ROOT_ID.to_string();
+            for folder in folder.split('/').filter(|name| !name.is_empty()) {
+                parent_id = files.insert(&parent_id, folder, true, Vec::new());
+            }
+            files.insert(&parent_id, name, false, content.to_vec());
+        }
+        self.shared.changed();
+    }
+
+    pub fn remove(&self, path: &str) {
+        {
+            let mut files = self.shared.files.lock().unwrap();
+            let id = files.find(path).unwrap();
+            files.remove(&id);
+        }
+        self.shared.changed();
+    }
+
+    /// Renames within the same folder.
+    pub fn rename(&self, path: &str, name: &str) {
+        {
+            let mut files = self.shared.files.lock().unwrap();
+            let id = files.find(path).unwrap();
+            files.entries.get_mut(&id).unwrap().name = name.to_string();
+        }
+        self.shared.changed();
+    }
+
+    /// Drops the websockets and refuses every request until `go_online`, so
+    /// changes made meanwhile only reach the client by catching up, and the
+    /// client's own changes have to wait.
+    pub fn go_offline(&self) {
+        self.shared.online.send_replace(false);
+    }
+
+    pub fn go_online(&self) {
+        self.shared.online.send_replace(true);
+    }
+
+    /// Stops answering on the websockets, pings included, without closing
+    /// them, like a connection that silently died. `thaw` closes them.
+    pub fn freeze(&self) {
+        self.shared.frozen.send_replace(true);
+    }
+
+    pub fn thaw(&self) {
+        self.shared.frozen.send_replace(false);
+    }
+
+    /// Refuses new upload sessions as too large while set.
+    pub fn reject_uploads(&self, reject: bool) {
+        self.shared
+            .rejecting_uploads
+            .store(reject, Ordering::SeqCst);
+    }
+
+    /// Answers chunks from `index` on with 503 while set.
+    pub fn fail_chunks_from(&self, index: Option<u64>) {
+        *self.shared.failing_chunks.lock().unwrap() = index;
+    }
+
+    /// How many chunks were accepted.
+    pub fn chunks_received(&self) -> usize {
+        self.shared.chunks_received.load(Ordering::SeqCst)
+    }
+
+    /// Answers range requests with the wrong part of the file while set.
+    pub fn misplace_ranges(&self, misplace: bool) {
+        self.shared
+            .misplacing_ranges
+            .store(misplace, Ordering::SeqCst);
+    }
+
+    /// Forgets the change log, so clients have to list the whole tree again.
+    pub fn forget_changes(&self) {
+        self.shared.files.lock().unwrap().change_log.clear();
+    }
+
+    /// How many times the whole tree has been listed.
+    pub fn listings(&self) -> usize {
+        self.shared.listings.load(Ordering::SeqCst)
+    }
+
+    pub fn read(&self, path: &str) -> Option<Vec<u8>> {
+        let files = self.shared.files.lock().unwrap();
+        let entry = &files.entries[&files.find(path)?];
+        (!entry.is_folder).then(|| entry.content.clone())
+    }
+
+    pub fn is_folder(&self, path: &str) -> bool {
+        let files = self.shared.files.lock().unwrap();
+        files
+            .find(path)
+            .is_some_and(|id| files.entries[&id].is_folder)
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.shared.files.lock().unwrap().find(path).is_some()
+    }
+
+    pub fn id(&self, path: &str) -> Option<String> {
+        self.shared.files.lock().unwrap().find(path)
+    }
+}
+
+/// Hands out connections once their handshake is done, dropping the ones that
+/// fail it, like a client refusing the certificate.
+struct TlsListener {
+    listener: TcpListener,
+    acceptor: tokio_native_tls::TlsAcceptor,
+}
+
+impl Listener for TlsListener {
+    type Io = tokio_native_tls::TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            let (stream, address) = Listener::accept(&mut self.listener).await;
+            if let Ok(stream) = self.acceptor.accept(stream).await {
+                return (stream, address);
+            }
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+fn issue_tokens(files: &mut Files) -> Response {
+    let token = files.new_id("token");
+    let refresh_token = files.new_id("refresh");
+    files.token = Some(token.clone());
+    files.refresh_token = Some(refresh_token.clone());
+    (
+        StatusCode::OK,
+        [("authorization", token), ("x-refresh-token", refresh_token)],
+    )
+        .into_response()
+}
+
+async fn login(State(shared): State<Shared>, Json(body): Json<Value>) -> Response {
+    if body["username"] != USERNAME || body["password"] != PASSWORD {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    issue_tokens(&mut shared.files.lock().unwrap())
+}
+
+async fn keep_alive(State(shared): State<Shared>, headers: HeaderMap) -> Response {
+    let mut files = shared.files.lock().unwrap();
+    let refresh_token = headers
+        .get("x-refresh-token")
+        .and_then(|value| value.to_str().ok());
+    if refresh_token.is_none() || refresh_token != files.refresh_token.as_deref() {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    issue_tokens(&mut files)
+}
+
+async fn list(State(shared): State<Shared>, headers: HeaderMap) -> Response {
+    if let Err(status) = shared.authorize(&headers) {
+        return status.into_response();
+    }
+    shared.listings.fetch_add(1, Ordering::SeqCst);
+    let (tree, cursor) = {
+        let files = shared.files.lock().unwrap();
+        (files.node(ROOT_ID, ""), files.cursor)
+    };
+    ([("x-cursor", cursor.to_string())], Json(tree)).into_response()
+}
+
+async fn changes(
+    State(shared): State<Shared>,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, u64>>,
+) -> Response {
+    if let Err(status) = shared.authorize(&headers) {
+        return status.into_response();
+    }
+    let Some(cursor) = query.get("cursor") else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    match shared.files.lock().unwrap().changes_since(*cursor) {
+        Some(delta) => Json(delta).into_response(),
+        None => StatusCode::GONE.into_response(),
+    }
+}
+
+fn parent_or_root(files: &Files, parent_id: &str) -> Option<String> {
+    if parent_id.is_empty() {
+        return Some(ROOT_ID.to_string());
+    }
+    files
+        .entries
+        .get(parent_id)
+        .filter(|entry| entry.is_folder)
+        .map(|_| parent_id.to_string())
+}
+
+async fn create_folder(
+    State(shared): State<Shared>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if let Err(status) = shared.authorize(&headers) {
+        return status.into_response();
+    }
+    let Ok(Some(field)) = multipart.next_field().await else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let request: Value = serde_json::from_str(&field.text().await.unwrap()).unwrap();
+    let id = {
+        let mut files = shared.files.lock().unwrap();
+        let parent_id = request["parentFolderId"].as_str().unwrap_or_default();
+        let Some(parent_id) = parent_or_root(&files, parent_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let name = request["name"].as_str().unwrap();
+        files.insert(&parent_id, name, true, Vec::new())
+    };
+    shared.changed();
+    Json(json!({ "id": id })).into_response()
+}
+
+async fn rename(
+    State(shared): State<Shared>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    if let Err(status) = shared.authorize(&headers) {
+        return status.into_response();
+    }
+    {
+        let mut files = shared.files.lock().unwrap();
+        let parent_id = body["parentId"].as_str().unwrap_or_default();
+        let parent_id = match parent_id {
+            "" => None,
+            parent_id => match parent_or_root(&files, parent_id) {
+                Some(parent_id) => Some(parent_id),
+                None => return StatusCode::NOT_FOUND.into_response(),
+            },
+        };
+        let Some(entry) = files.entries.get_mut(&id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        entry.name = body["name"].as_str().unwrap().to_string();
+        if parent_id.is_some() {
+            entry.parent_id = parent_id;
+        }
+    }
+    shared.changed();
+    StatusCode::OK.into_response()
+}
+
+async fn delete(
+    State(shared): State<Shared>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = shared.authorize(&headers) {
+        return status.into_response();
+    }
+    {
+        let mut files = shared.files.lock().unwrap();
+        if id == ROOT_ID || !files.entries.contains_key(&id) {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        files.remove(&id);
+    }
+    shared.changed();
+    StatusCode::OK.into_response()
+}
+
+async fn start_upload(
+    State(shared): State<Shared>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    if let Err(status) = shared.authorize(&headers) {
+        return status.into_response();
+    }
+    if shared.rejecting_uploads.load(Ordering::SeqCst) {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+    let mut files = shared.files.lock().unwrap();
+    let session_id = files.new_id("session");
+    files.sessions.insert(
+        session_id.clone(),
+        UploadSession {
+            file_name: body["fileName"].as_str().unwrap().to_string(),
+            size: body["size"].as_u64().unwrap(),
+            hash: body["hash"].as_str().unwrap().to_string(),
+            element_id: body["elementId"].as_str().unwrap_or_default().to_string(),
+            parent_id: body["parentId"].as_str().unwrap_or_default().to_string(),
+            chunks: BTreeMap::new(),
+        },
+    );
+    Json(json!({ "sessionId": session_id })).into_response()
+}
+
+async fn upload_chunk(
+    State(shared): State<Shared>,
+    Path((session_id, index)): Path<(String, u64)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Err(status) = shared.authorize(&headers) {
+        return status.into_response();
+    }
+    let hash = headers
+        .get("x-chunk-hash")
+        .and_then(|value| value.to_str().ok());
+    if hash != Some(fstree::hash_bytes(&body).as_str()) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    if shared
+        .failing_chunks
+        .lock()
+        .unwrap()
+        .is_some_and(|from| index >= from)
+    {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let mut files = shared.files.lock().unwrap();
+    let Some(session) = files.sessions.get_mut(&session_id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    session.chunks.insert(index, body.to_vec());
+    shared.chunks_received.fetch_add(1, Ordering::SeqCst);
+    StatusCode::OK.into_response()
+}
+
+async fn finish_upload(
+    State(shared): State<Shared>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = shared.authorize(&headers) {
+        return status.into_response();
+    }
+    let id = {
+        let mut files = shared.files.lock().unwrap();
+        let Some(session) = files.sessions.remove(&session_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let content: Vec<u8> = session.chunks.into_values().flatten().collect();
+        if content.len() as u64 != session.size || fstree::hash_bytes(&content) != session.hash {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        match files.entries.get_mut(&session.element_id) {
+            Some(entry) if !entry.is_folder => {
+                entry.content = content;
+                session.element_id
+            }
+            _ => {
+                let Some(parent_id) = parent_or_root(&files, &session.parent_id) else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                files.insert(&parent_id, &session.file_name, false, content)
+            }
+        }
+    };
+    shared.changed();
+    Json(json!({ "id": id })).into_response()
+}
+
+async fn download(
+    State(shared): State<Shared>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = shared.authorize(&headers) {
+        return status.into_response();
+    }
+    let content = match shared.files.lock().unwrap().entries.get(&id) {
+        Some(entry) if !entry.is_folder => entry.content.clone(),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let etag = format!("\"{}\"", fstree::hash_bytes(&content));
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let start = header("range")
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.strip_suffix('-'))
+        .and_then(|start| start.parse::<usize>().ok())
+        .filter(|start| *start < content.len());
+    let fresh = header("if-range").is_none_or(|if_range| if_range == etag);
+    let start = match start {
+        Some(_) if shared.misplacing_ranges.load(Ordering::SeqCst) => Some(0),
+        start => start,
+    };
+    match start {
+        Some(start) if fresh => {
+            let range = format!("bytes {start}-{}/{}", content.len() - 1, content.len());
+            (
+                StatusCode::PARTIAL_CONTENT,
+                [("etag", etag), ("content-range", range)],
+                content[start..].to_vec(),
+            )
+                .into_response()
+        }
+        _ => (StatusCode::OK, [("etag", etag)], content).into_response(),
+    }
+}
+
+async fn websocket(
+    State(shared): State<Shared>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if let Err(status) = shared.authorize(&headers) {
+        return status.into_response();
+    }
+    ws.on_upgrade(move |socket| push_deltas(shared, socket))
+}
+
+async fn refuse_offline(
+    State(shared): State<Shared>,
+    request: Request<axum::body::Body>,
+    next: Next,
+) -> Response {
+    if !*shared.online.borrow() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    next.run(request).await
+}
+
+/// Sends a delta after every change.
+async fn push_deltas(shared: Shared, mut socket: WebSocket) {
+    let mut changes = shared.changes.subscribe();
+    let mut online = shared.online.subscribe();
+    let mut frozen = shared.frozen.subscribe();
+    loop {
+        tokio::select! {
+            _ = frozen.wait_for(|frozen| *frozen).map(|_| ()) => {
+                let _ = frozen.wait_for(|frozen| !frozen).await;
+                return;
+            }
+            msg = socket.recv() => {
+                if !matches!(msg, Some(Ok(_))) {
+                    return;
+                }
+            }
+            _ = online.wait_for(|online| !online).map(|_| ()) => return,
+            delta = changes.recv() => {
+                let delta = match delta {
+                    Ok(delta) => delta,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let msg = json!({
+                    "message": "files changed",
+                    "data": delta,
+                    "timestamp": 0,
+                    "type": "delta",
+                });
+                if socket.send(Message::Text(msg.to_string().into())).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::types::SelectiveSync;
+
+pub const IGNORE_FILE: &str = ".syncignore";
+/// Suffix of downloads being moved into place, never synced.
+pub const DOWNLOAD_SUFFIX: &str = ".ft-download";
+
+/// Gitignore-style rules from the `.syncignore` at the sync root plus the
+/// global patterns from the config, and the folders left out of selective sync.
+pub struct IgnoreRules {
+    root_path: PathBuf,
+    patterns: Vec<String>,
+    /// Built again when the `.syncignore` changes, see `reload`.
+    gitignore: RwLock<Gitignore>,
+    included: Vec<PathBuf>,
+    excluded: Vec<PathBuf>,
+}
+
+fn folders(paths: &[String]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .map(|path| path.trim_matches('/'))
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn build(root_path: &Path, patterns: &[String]) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(root_path);
+    builder
+        .add_line(None, &format!("*{DOWNLOAD_SUFFIX}"))
+        .unwrap();
+    for pattern in patterns {
+        if let Err(e) = builder.add_line(None, pattern) {
+            println!("invalid ignore pattern {pattern}: {e}");
+        }
+    }
+    let ignore_file = root_path.join(IGNORE_FILE);
+    if ignore_file.exists() {
+        if let Some(e) = builder.add(&ignore_file) {
+            println!("failed to read {}: {e}", ignore_file.display());
+        }
+    }
+    builder.build().unwrap_or_else(|e| {
+        println!("failed to build ignore rules: {e}");
+        Gitignore::empty()
+    })
+}
+
+impl IgnoreRules {
+    pub fn load(root_path: &Path, patterns: &[String], selection: &SelectiveSync) -> Self {
+        Self {
+            root_path: root_path.to_path_buf(),
+            patterns: patterns.to_vec(),
+            gitignore: RwLock::new(build(root_path, patterns)),
+            included: folders(&selection.included),
+            excluded: folders(&selection.excluded),
+        }
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            root_path: PathBuf::new(),
+            patterns: vec![],
+            gitignore: RwLock::new(Gitignore::empty()),
+            included: vec![],
+            excluded: vec![],
+        }
+    }
+
+    /// Reads the `.syncignore` again.
+    pub fn reload(&self) {
+        *self.gitignore.write().unwrap() = build(&self.root_path, &self.patterns);
+    }
+
+    /// Whether `path` is the `.syncignore` these rules come from.
+    pub fn is_ignore_file(&self, path: &Path) -> bool {
+        path == self.root_path.join(IGNORE_FILE)
+    }
+
+    /// `path` is relative to the sync root, or absolute under it.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let relative = path.strip_prefix(&self.root_path).unwrap_or(path);
+        if relative.as_os_str().is_empty() || relative.has_root() {
+            return false;
+        }
+        if self.is_deselected(relative, is_dir) {
+            return true;
+        }
+        self.gitignore
+            .read()
+            .unwrap()
+            .matched_path_or_any_parents(relative, is_dir)
+            .is_ignore()
+    }
+
+    /// Whether selective sync leaves out `path`, relative to the sync root.
+    /// Folders on the way to an included folder stay, without the rest of
+    /// what is in them.
+    pub fn is_deselected(&self, path: &Path, is_dir: bool) -> bool {
+        let deepest = |folders: &[PathBuf]| {
+            folders
+                .iter()
+                .filter(|folder| path.starts_with(folder))
+                .map(|folder| folder.components().count())
+                .max()
+        };
+        let Some(excluded) = deepest(&self.excluded) else {
+            return false;
+        };
+        if deepest(&self.included).is_some_and(|included| included > excluded) {
+            return false;
+        }
+        !(is_dir && self.included.iter().any(|folder| folder.starts_with(path)))
+    }
+}
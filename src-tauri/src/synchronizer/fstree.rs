@@ -1,9 +1,14 @@
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::UNIX_EPOCH;
+
+use crate::synchronizer::filter::IgnoreRules;
+use crate::types::RemoteFolder;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -22,43 +27,194 @@ pub struct Node {
     pub path: Option<String>, // relative from root
     pub id: Arc<Mutex<Option<String>>>,
     pub parent_id: Arc<Mutex<Option<String>>>,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub mtime: u64, // milliseconds since epoch
+}
+
+/// One entry of a remote delta, paths relative to the root.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum RemoteChange {
+    /// `node` carries the whole subtree of an added folder.
+    Added {
+        id: String,
+        path: String,
+        node: Node,
+    },
+    Modified {
+        id: String,
+        path: String,
+        node: Node,
+    },
+    Deleted {
+        id: String,
+        path: String,
+    },
+    Moved {
+        id: String,
+        from: String,
+        path: String,
+    },
+}
+
+impl RemoteChange {
+    /// The paths it changes, both ends of a move.
+    pub fn paths(&self) -> Vec<String> {
+        match self {
+            RemoteChange::Added { path, .. }
+            | RemoteChange::Modified { path, .. }
+            | RemoteChange::Deleted { path, .. } => vec![path.clone()],
+            RemoteChange::Moved { from, path, .. } => vec![from.clone(), path.clone()],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedHash {
+    size: u64,
+    mtime: u64,
+    inode: u64,
+    hash: String,
+}
+
+/// Kept in the data dir of each sync pair.
+const HASH_CACHE_FILE: &str = "hash_cache.json";
+
+/// The hashes of the files of a sync root, by path relative to it.
+struct HashCache {
+    file: PathBuf,
+    entries: HashMap<PathBuf, CachedHash>,
 }
 
+/// The hash cache of each sync root, see `open_hash_cache`.
+static HASH_CACHES: LazyLock<Mutex<HashMap<PathBuf, HashCache>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
 pub fn hash_bytes(bytes: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(bytes);
     format!("{:x}", hasher.finalize())
 }
 
-pub fn build_tree(path: &Path) -> std::io::Result<Node> {
+pub fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn mtime(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+/// Loads the hash cache of `root_path` from `data_dir`, where
+/// `save_hash_cache` writes it. Files of roots without one are always hashed.
+pub fn open_hash_cache(root_path: &Path, data_dir: &Path) {
+    let file = data_dir.join(HASH_CACHE_FILE);
+    let entries = fs::read_to_string(&file)
+        .ok()
+        .and_then(|cache| serde_json::from_str(&cache).ok())
+        .unwrap_or_default();
+    HASH_CACHES
+        .lock()
+        .unwrap()
+        .insert(root_path.to_path_buf(), HashCache { file, entries });
+}
+
+/// Hashes a file, reusing the cached hash if its size, mtime and inode are
+/// unchanged. `relative` is where the file is under its sync root.
+fn cached_hash_file(
+    path: &Path,
+    relative: &Path,
+    metadata: &fs::Metadata,
+) -> std::io::Result<String> {
+    let (size, mtime, inode) = (metadata.len(), mtime(metadata), inode(metadata));
+    let root_path = path.ancestors().nth(relative.components().count());
+    let cached = |caches: &HashMap<PathBuf, HashCache>| {
+        let cached = caches.get(root_path?)?.entries.get(relative)?;
+        let unchanged = cached.size == size && cached.mtime == mtime && cached.inode == inode;
+        unchanged.then(|| cached.hash.clone())
+    };
+    if let Some(hash) = cached(&HASH_CACHES.lock().unwrap()) {
+        return Ok(hash);
+    }
+    let hash = hash_file(path)?;
+    let mut caches = HASH_CACHES.lock().unwrap();
+    if let Some(cache) = root_path.and_then(|root_path| caches.get_mut(root_path)) {
+        let cached = CachedHash {
+            size,
+            mtime,
+            inode,
+            hash: hash.clone(),
+        };
+        cache.entries.insert(relative.to_path_buf(), cached);
+    }
+    Ok(hash)
+}
+
+/// Forgets files under `root_path` that no longer exist and writes its cache
+/// to disk.
+pub fn save_hash_cache(root_path: &Path) -> std::io::Result<()> {
+    let mut caches = HASH_CACHES.lock().unwrap();
+    let Some(cache) = caches.get_mut(root_path) else {
+        return Ok(());
+    };
+    cache
+        .entries
+        .retain(|relative, _| root_path.join(relative).exists());
+    let json = serde_json::to_string(&cache.entries)?;
+    fs::write(&cache.file, json)?;
+    Ok(())
+}
+
+pub fn build_tree(path: &Path, rules: &IgnoreRules) -> std::io::Result<Node> {
     let relative = std::path::Path::new("");
-    let mut root = _build_tree(path, relative)?;
+    let mut root = _build_tree(path, relative, rules)?;
     root.path = Some(path.to_string_lossy().to_string());
+    save_hash_cache(path)?;
     Ok(root)
 }
-pub fn build_node(base_path: &Path, file_path: &Path) -> std::io::Result<Node> {
+pub fn build_node(
+    base_path: &Path,
+    file_path: &Path,
+    rules: &IgnoreRules,
+) -> std::io::Result<Node> {
     let relative = file_path
         .strip_prefix(base_path)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "File not under base path"))?;
-    _build_tree(file_path, relative)
+        .map_err(|_| std::io::Error::other("File not under base path"))?;
+    if rules.is_ignored(relative, file_path.is_dir()) {
+        return Err(std::io::Error::other("File is ignored"));
+    }
+    _build_tree(file_path, relative, rules)
 }
 
-fn _build_tree(path: &Path, relative: &Path) -> std::io::Result<Node> {
+fn _build_tree(path: &Path, relative: &Path, rules: &IgnoreRules) -> std::io::Result<Node> {
     if path.is_file() {
-        let data = match fs::read(path) {
-            Ok(data) => data,
-            Err(_) => {
-                return Ok(Node {
-                    node_type: NodeType::File,
-                    hash: "".to_string(),
-                    content: None,
-                    path: Some(relative.to_string_lossy().to_string()),
-                    id: Arc::new(Mutex::new(None)),
-                    parent_id: Arc::new(Mutex::new(None)),
-                })
-            }
+        // unreadable files (e.g. locked) get an empty hash and are picked up on the next change
+        let (hash, size, mtime) = match fs::metadata(path) {
+            Ok(metadata) => (
+                cached_hash_file(path, relative, &metadata).unwrap_or_default(),
+                metadata.len(),
+                mtime(&metadata),
+            ),
+            Err(_) => ("".to_string(), 0, 0),
         };
-        let hash = hash_bytes(&data);
 
         Ok(Node {
             node_type: NodeType::File,
@@ -67,6 +223,8 @@ fn _build_tree(path: &Path, relative: &Path) -> std::io::Result<Node> {
             path: Some(relative.to_string_lossy().to_string()),
             id: Arc::new(Mutex::new(None)),
             parent_id: Arc::new(Mutex::new(None)),
+            size,
+            mtime,
         })
     } else if path.is_dir() {
         let mut children: BTreeMap<String, Node> = BTreeMap::new();
@@ -76,17 +234,21 @@ fn _build_tree(path: &Path, relative: &Path) -> std::io::Result<Node> {
             let name = entry.file_name();
             let full_path = entry.path();
             let rel_path = relative.join(&name);
+            if rules.is_ignored(&rel_path, entry.file_type()?.is_dir()) {
+                continue;
+            }
 
-            let node = _build_tree(&full_path, &rel_path)?;
+            let node = _build_tree(&full_path, &rel_path, rules)?;
             children.insert(name.to_string_lossy().to_string(), node);
         }
 
         let mut hash_input = String::new();
-        for (_, child) in &children {
+        for child in children.values() {
             hash_input.push_str(&child.hash);
         }
 
         let folder_hash = hash_bytes(hash_input.as_bytes());
+        let size = children.values().map(|child| child.size).sum();
 
         Ok(Node {
             node_type: NodeType::Folder,
@@ -95,13 +257,12 @@ fn _build_tree(path: &Path, relative: &Path) -> std::io::Result<Node> {
             path: Some(relative.to_string_lossy().to_string()),
             id: Arc::new(Mutex::new(None)),
             parent_id: Arc::new(Mutex::new(None)),
+            size,
+            mtime: fs::metadata(path).map_or(0, |metadata| mtime(&metadata)),
         })
     } else {
         println!("Unsupported file type: {}", path.display());
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Unsupported file type",
-        ))
+        Err(std::io::Error::other("Unsupported file type"))
     }
 }
 
@@ -141,7 +302,7 @@ pub fn diff_trees(
                     hash: Some(node_2.hash.clone()),
                 });
             }
-            if node_1.hash == node_2.hash {}
+
             if node_1.hash != node_2.hash
                 && node_1.node_type == NodeType::File
                 && node_2.node_type == NodeType::File
@@ -256,6 +417,22 @@ pub fn detect_renames(mut changes: Vec<Change>) -> Vec<Change> {
         final_changes.push(add);
     }
 
+    // what is in a renamed folder moved along with it
+    let renamed_folders: Vec<String> = final_changes
+        .iter()
+        .filter(|change| {
+            change.node_type == NodeType::Folder
+                && matches!(change.change_type, ChangeType::Renamed { .. })
+        })
+        .map(|change| change.path.clone())
+        .collect();
+    final_changes.retain(|change| {
+        change.change_type != ChangeType::Added
+            || !renamed_folders
+                .iter()
+                .any(|folder| Path::new(&change.path).starts_with(folder) && change.path != *folder)
+    });
+
     // Remaining unmatched deletions,real deletions
     final_changes.extend(deleted);
 
@@ -265,13 +442,174 @@ pub fn detect_renames(mut changes: Vec<Change>) -> Vec<Change> {
     final_changes
 }
 
-impl Node {
-    /// High-level convenience: pass in a node with a known relative path
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub local: Change,
+    pub remote: Change,
+}
+
+/// Changes since the last synced tree, split by the side that made them.
+#[derive(Debug, Default)]
+pub struct Merge {
+    pub local: Vec<Change>,
+    pub remote: Vec<Change>,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Splits what changed since `base` into local and remote changes and the
+/// conflicts between them. `pending` are the files with a local edit still
+/// waiting in the journal, which `base` already has the way they are locally:
+/// they count as edited, and remote edits to them don't count, the upload wins.
+pub fn three_way_diff(base: &Node, local: &Node, remote: &Node, pending: &[String]) -> Merge {
+    let mut local_changes = Vec::new();
+    diff_trees("", Some(base), Some(local), &mut local_changes);
+    let mut local_changes = detect_renames(local_changes);
+    for path in pending {
+        let changed = local_changes
+            .iter()
+            .any(|change| change_paths(change).contains(&path.as_str()));
+        let Some(node) = local.find(path) else {
+            continue;
+        };
+        if changed || node.node_type != NodeType::File {
+            continue;
+        }
+        local_changes.push(Change {
+            id: node.id.clone(),
+            parent_id: node.parent_id.clone(),
+            node_type: NodeType::File,
+            path: path.clone(),
+            change_type: ChangeType::Modified,
+            hash: Some(node.hash.clone()),
+        });
+    }
+
+    let mut remote_changes = Vec::new();
+    diff_trees("", Some(base), Some(remote), &mut remote_changes);
+    // nodes without an id were never uploaded, missing remotely is not a remote delete
+    remote_changes.retain(|change| {
+        change.change_type != ChangeType::Deleted || change.id.lock().unwrap().is_some()
+    });
+    remote_changes.retain(|change| {
+        let is_edit = matches!(change.change_type, ChangeType::Added | ChangeType::Modified);
+        !(is_edit && pending.contains(&change.path))
+            || local.find(&change.path).map(|node| &node.hash) == change.hash.as_ref()
+    });
+    let remote_changes = detect_renames(remote_changes);
+
+    let mut merge = Merge::default();
+    let mut matched = vec![false; remote_changes.len()];
+    for local_change in local_changes {
+        let mut overlapped = false;
+        for (i, remote_change) in remote_changes.iter().enumerate() {
+            if !overlaps(&local_change, remote_change) {
+                continue;
+            }
+            overlapped = true;
+            matched[i] = true;
+            if !same_outcome(&local_change, remote_change) {
+                merge.conflicts.push(Conflict {
+                    local: local_change.clone(),
+                    remote: remote_change.clone(),
+                });
+            }
+        }
+        if !overlapped {
+            merge.local.push(local_change);
+        }
+    }
+    for (remote_change, matched) in remote_changes.into_iter().zip(matched) {
+        if !matched {
+            merge.remote.push(remote_change);
+        }
+    }
+    merge
+}
+
+fn change_paths(change: &Change) -> Vec<&str> {
+    match &change.change_type {
+        ChangeType::Renamed { from } => vec![change.path.as_str(), from.as_str()],
+        _ => vec![change.path.as_str()],
+    }
+}
+
+/// Two changes overlap when they touch the same path, or when one removes or
+/// moves a folder the other changes something inside of.
+fn overlaps(a: &Change, b: &Change) -> bool {
+    let is_inside =
+        |path: &str, folder: &str| path != folder && Path::new(path).starts_with(Path::new(folder));
+    let moves_folder = |change: &Change| {
+        change.node_type == NodeType::Folder
+            && matches!(
+                change.change_type,
+                ChangeType::Deleted | ChangeType::Renamed { .. }
+            )
+    };
+    for a_path in change_paths(a) {
+        for b_path in change_paths(b) {
+            if a_path == b_path
+                || (moves_folder(a) && is_inside(b_path, a_path))
+                || (moves_folder(b) && is_inside(a_path, b_path))
+            {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// The paths where `local` already is what `remote` has, but `base` doesn't
+/// say so yet, like what both sides added or changed the same way. The local
+/// nodes there take the remote ids.
+pub fn adopt_remote(base: &Node, local: &Node, remote: &Node) -> Vec<String> {
+    let mut adopted = vec![];
+    for row in remote.rows("") {
+        // the bare folders of a part
+        let Some(id) = row.id else {
+            continue;
+        };
+        let Some(node) = local.find(&row.path) else {
+            continue;
+        };
+        let is_file = row.node_type == NodeType::File;
+        if node.node_type != row.node_type || (is_file && node.hash != row.hash) {
+            continue;
+        }
+        let recorded = base.find(&row.path).is_some_and(|saved| {
+            saved.id.lock().unwrap().as_ref() == Some(&id) && (!is_file || saved.hash == row.hash)
+        });
+        if recorded {
+            continue;
+        }
+        *node.id.lock().unwrap() = Some(id);
+        adopted.push(row.path);
+    }
+    adopted
+}
+
+/// Both sides already made the same change, nothing left to sync.
+fn same_outcome(a: &Change, b: &Change) -> bool {
+    if a.path != b.path || a.node_type != b.node_type {
+        return false;
+    }
+    match (&a.change_type, &b.change_type) {
+        (ChangeType::Deleted, ChangeType::Deleted) => true,
+        (ChangeType::Renamed { from: a_from }, ChangeType::Renamed { from: b_from }) => {
+            a_from == b_from
+        }
+        (ChangeType::Added | ChangeType::Modified, ChangeType::Added | ChangeType::Modified) => {
+            a.node_type == NodeType::Folder || a.hash == b.hash
+        }
+        _ => false,
+    }
+}
 
+impl Node {
+    /// Removes the node at `file_path`, absolute under the root.
     pub fn delete_node(&mut self, file_path: &str) -> Result<(), String> {
         let relative = Path::new(file_path)
             .strip_prefix(self.path.as_ref().unwrap())
-            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "File not under base path"))
+            .map_err(|_| std::io::Error::other("File not under base path"))
             .unwrap();
         let parts: Vec<String> = Path::new(relative)
             .components()
@@ -336,9 +674,14 @@ impl Node {
                 if parts.len() == 1 {
                     // Insert the new node
                     new_node.parent_id = self.id.clone();
-                    new_node.id = children
-                        .get(key)
-                        .map_or_else(|| new_node.id, |n| n.id.clone());
+                    if let Some(existing) = children.get(key) {
+                        // keeps the ids of what is replaced, and what is in it
+                        new_node.restore_ids(existing);
+                        new_node.id = existing.id.clone();
+                        for child in new_node.content.iter_mut().flat_map(|c| c.values_mut()) {
+                            child.parent_id = new_node.id.clone();
+                        }
+                    }
                     children.insert(key.clone(), new_node);
                 } else {
                     // Recurse into subfolder
@@ -354,6 +697,8 @@ impl Node {
                         ),
                         id: Arc::new(Mutex::new(None)),
                         parent_id: Arc::new(Mutex::new(None)),
+                        size: 0,
+                        mtime: 0,
                     });
 
                     if child.node_type != NodeType::Folder {
@@ -371,36 +716,372 @@ impl Node {
         }
     }
 
-    pub fn rename_node(&mut self, old_path: &str, new_path: &str) -> Result<(), String> {
+    pub fn rename_node(
+        &mut self,
+        old_path: &str,
+        new_path: &str,
+        rules: &IgnoreRules,
+    ) -> Result<(), String> {
+        let root_path = PathBuf::from(self.path.as_ref().unwrap());
+        let old_relative = Path::new(old_path)
+            .strip_prefix(&root_path)
+            .map_err(|_| format!("'{old_path}' is not under the root"))?;
+        // what moved keeps its ids
+        let previous = self.find(&old_relative.to_string_lossy()).cloned();
         self.delete_node(old_path)?;
-        let new_node = build_node(
-            std::path::Path::new(self.path.as_ref().unwrap()),
-            std::path::Path::new(new_path),
-        )
-        .unwrap();
+        let mut new_node =
+            build_node(&root_path, Path::new(new_path), rules).map_err(|e| e.to_string())?;
+        if let Some(previous) = &previous {
+            new_node.restore_ids(previous);
+        }
         self.add_node(new_node)?;
         Ok(())
     }
+    pub fn find(&self, rel_path: &str) -> Option<&Node> {
+        let mut node = self;
+        for part in Path::new(rel_path).components() {
+            let key = part.as_os_str().to_string_lossy();
+            node = node.content.as_ref()?.get(key.as_ref())?;
+        }
+        Some(node)
+    }
+
+    pub fn find_mut(&mut self, rel_path: &str) -> Option<&mut Node> {
+        let mut node = self;
+        for part in Path::new(rel_path).components() {
+            let key = part.as_os_str().to_string_lossy();
+            node = node.content.as_mut()?.get_mut(key.as_ref())?;
+        }
+        Some(node)
+    }
+
+    /// Clears the ids of the node and everything in it, for nodes the server
+    /// doesn't have anymore, with each child linked to its folder's id again.
+    pub fn forget_ids(&mut self) {
+        self.id.lock().unwrap().take();
+        for child in self
+            .content
+            .iter_mut()
+            .flat_map(|children| children.values_mut())
+        {
+            child.parent_id = self.id.clone();
+            child.forget_ids();
+        }
+    }
+
+    /// Copies ids from a previously saved tree onto the matching paths of this one,
+    /// linking each child's parent id to its folder's id like `add_node` does.
+    pub fn restore_ids(&mut self, previous: &Node) {
+        if self.node_type != previous.node_type {
+            return;
+        }
+        *self.id.lock().unwrap() = previous.id.lock().unwrap().clone();
+        if let (Some(children), Some(previous_children)) = (&mut self.content, &previous.content) {
+            for (name, child) in children.iter_mut() {
+                child.parent_id = self.id.clone();
+                if let Some(previous_child) = previous_children.get(name) {
+                    child.restore_ids(previous_child);
+                }
+            }
+        }
+    }
+    /// Applies a delta entry to a copy of the remote tree. What the server sends
+    /// replaces what was at its path, ids included.
+    pub fn apply_remote(&mut self, change: &RemoteChange) -> Result<(), String> {
+        match change {
+            RemoteChange::Added { id, path, node } | RemoteChange::Modified { id, path, node } => {
+                let mut node = node.detached();
+                node.path = Some(path.clone());
+                *node.id.lock().unwrap() = Some(id.clone());
+                let _ = self._delete_path(&path_parts(path));
+                self._add_path(&path_parts(path), node)
+            }
+            RemoteChange::Deleted { path, .. } => self._delete_path(&path_parts(path)),
+            RemoteChange::Moved { id, from, path } => {
+                let mut node = self
+                    .find(from)
+                    .ok_or_else(|| format!("'{from}' not found"))?
+                    .detached();
+                self._delete_path(&path_parts(from))?;
+                node.path = Some(path.clone());
+                *node.id.lock().unwrap() = Some(id.clone());
+                let _ = self._delete_path(&path_parts(path));
+                self._add_path(&path_parts(path), node)
+            }
+        }
+    }
+
+    /// This tree cut down to the nodes at `paths` and bare folders above them,
+    /// for merging a part of it on its own. The nodes share their ids with
+    /// this tree.
+    pub fn part(&self, paths: &[String]) -> Node {
+        let mut part = bare_folder(self.path.clone().unwrap_or_default());
+        part.id = self.id.clone();
+        for path in paths {
+            let parts = path_parts(path);
+            let Some((_, above)) = parts.split_last() else {
+                return self.clone();
+            };
+            part._folders(above);
+            if let Some(node) = self.find(path) {
+                part._insert(&parts, Path::new(""), node.clone());
+            }
+        }
+        part.rehash();
+        part
+    }
+
+    /// Makes sure there are folders down `parts`, bare ones where there are none.
+    fn _folders(&mut self, parts: &[String]) {
+        let mut node = self;
+        let mut relative = PathBuf::new();
+        for key in parts {
+            relative.push(key);
+            let parent_id = node.id.clone();
+            let Some(children) = &mut node.content else {
+                return;
+            };
+            node = children.entry(key.clone()).or_insert_with(|| {
+                let mut folder = bare_folder(relative.to_string_lossy().to_string());
+                folder.parent_id = parent_id;
+                folder
+            });
+        }
+    }
+
+    /// A deep copy that shares no ids with this tree.
+    pub fn detached(&self) -> Node {
+        serde_json::from_value(serde_json::to_value(self).unwrap()).unwrap()
+    }
+    /// Drops ignored nodes, e.g. from a remote tree before comparing it with the local one.
+    pub fn prune(&mut self, rules: &IgnoreRules) {
+        self._prune(Path::new(""), rules);
+    }
+
+    fn _prune(&mut self, relative: &Path, rules: &IgnoreRules) {
+        if let Some(children) = &mut self.content {
+            children.retain(|name, child| {
+                !rules.is_ignored(&relative.join(name), child.node_type == NodeType::Folder)
+            });
+            for (name, child) in children.iter_mut() {
+                child._prune(&relative.join(name), rules);
+            }
+            self.recalculate_hash().unwrap();
+        }
+    }
     fn recalculate_hash(&mut self) -> std::io::Result<()> {
         if self.node_type != NodeType::Folder || self.content.is_none() {
             return Ok(());
         }
         let mut hash_input = String::new();
-        for (_, child) in self.content.as_mut().unwrap() {
+        for child in self.content.as_mut().unwrap().values_mut() {
             hash_input.push_str(&child.hash);
         }
         self.hash = hash_bytes(hash_input.as_bytes());
+        self.size = self
+            .content
+            .as_ref()
+            .unwrap()
+            .values()
+            .map(|child| child.size)
+            .sum();
         Ok(())
     }
 }
 
-pub fn save_tree(node: &Node, path: &str) -> std::io::Result<()> {
-    let json = serde_json::to_string_pretty(node)?;
-    fs::write(path, json)?;
-    Ok(())
+/// An empty folder without an id, for one that is only there to hold others.
+fn bare_folder(path: String) -> Node {
+    Node {
+        node_type: NodeType::Folder,
+        hash: "".to_string(),
+        content: Some(BTreeMap::new()),
+        path: Some(path),
+        id: Arc::new(Mutex::new(None)),
+        parent_id: Arc::new(Mutex::new(None)),
+        size: 0,
+        mtime: 0,
+    }
+}
+
+fn path_parts(path: &str) -> Vec<String> {
+    Path::new(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect()
+}
+
+/// A node on its own, as the index stores it.
+pub struct Row {
+    /// Relative from root, empty for the root itself.
+    pub path: String,
+    pub node_type: NodeType,
+    pub id: Option<String>,
+    pub parent_id: Option<String>,
+    pub hash: String,
+    pub size: u64,
+    pub mtime: u64,
+}
+
+impl Node {
+    /// The node at `path`, relative from root, without its children.
+    pub fn row(&self, path: &str) -> Row {
+        Row {
+            path: path.to_string(),
+            node_type: self.node_type.clone(),
+            id: self.id.lock().unwrap().clone(),
+            parent_id: self.parent_id.lock().unwrap().clone(),
+            hash: self.hash.clone(),
+            size: self.size,
+            mtime: self.mtime,
+        }
+    }
+
+    /// The node at `path` and everything under it. Paths come from where the
+    /// nodes are, nodes moved as a whole keep their old `path`.
+    pub fn rows(&self, path: &str) -> Vec<Row> {
+        let mut rows = vec![self.row(path)];
+        for (name, child) in self.content.iter().flatten() {
+            let child_path = Path::new(path).join(name).to_string_lossy().to_string();
+            rows.extend(child.rows(&child_path));
+        }
+        rows
+    }
+
+    /// Puts a tree rooted at `root_path` back together, folders missing a row
+    /// included, and hashes the folders again.
+    pub fn from_rows(root_path: &str, mut rows: Vec<Row>) -> Node {
+        let mut root = Node {
+            node_type: NodeType::Folder,
+            hash: "".to_string(),
+            content: Some(BTreeMap::new()),
+            path: Some(root_path.to_string()),
+            id: Arc::new(Mutex::new(None)),
+            parent_id: Arc::new(Mutex::new(None)),
+            size: 0,
+            mtime: 0,
+        };
+        // folders before what is in them
+        rows.sort_by_cached_key(|row| path_parts(&row.path).len());
+        for row in rows {
+            if row.path.is_empty() {
+                *root.id.lock().unwrap() = row.id;
+                root.mtime = row.mtime;
+                continue;
+            }
+            let node = Node {
+                content: (row.node_type == NodeType::Folder).then(BTreeMap::new),
+                node_type: row.node_type,
+                hash: row.hash,
+                path: Some(row.path.clone()),
+                id: Arc::new(Mutex::new(row.id)),
+                parent_id: Arc::new(Mutex::new(None)),
+                size: row.size,
+                mtime: row.mtime,
+            };
+            root._insert(&path_parts(&row.path), Path::new(""), node);
+        }
+        root.rehash();
+        root
+    }
+
+    /// Like `_add_path`, leaving the hashes to `rehash`.
+    fn _insert(&mut self, parts: &[String], relative: &Path, mut node: Node) {
+        let Some(children) = &mut self.content else {
+            return;
+        };
+        let key = &parts[0];
+        if parts.len() == 1 {
+            node.parent_id = self.id.clone();
+            children.insert(key.clone(), node);
+            return;
+        }
+        let relative = relative.join(key);
+        let child = children.entry(key.clone()).or_insert_with(|| Node {
+            node_type: NodeType::Folder,
+            hash: "".to_string(),
+            content: Some(BTreeMap::new()),
+            path: Some(relative.to_string_lossy().to_string()),
+            id: Arc::new(Mutex::new(None)),
+            parent_id: self.id.clone(),
+            size: 0,
+            mtime: 0,
+        });
+        child._insert(&parts[1..], &relative, node);
+    }
+
+    /// Paths of the nodes under this one that `matches`, leaving out those
+    /// inside a node that matches already.
+    pub fn topmost(&self, path: &str, matches: &impl Fn(&Path, bool) -> bool) -> Vec<String> {
+        let mut found = vec![];
+        for (name, child) in self.content.iter().flatten() {
+            let child_path = Path::new(path).join(name);
+            let child_path_str = child_path.to_string_lossy().to_string();
+            if matches(&child_path, child.node_type == NodeType::Folder) {
+                found.push(child_path_str);
+            } else {
+                found.extend(child.topmost(&child_path_str, matches));
+            }
+        }
+        found
+    }
+
+    /// The node with the given id and its path, this one being at `path`.
+    pub fn find_id(&self, path: &str, id: &str) -> Option<(String, &Node)> {
+        if self.id.lock().unwrap().as_deref() == Some(id) {
+            return Some((path.to_string(), self));
+        }
+        self.content.iter().flatten().find_map(|(name, child)| {
+            let child_path = if path.is_empty() {
+                name.clone()
+            } else {
+                format!("{path}/{name}")
+            };
+            child.find_id(&child_path, id)
+        })
+    }
+
+    /// The folders in this one, with theirs, `path` being where it is.
+    pub fn folders(&self, path: &str) -> Vec<RemoteFolder> {
+        self.content
+            .iter()
+            .flatten()
+            .filter(|(_, child)| child.node_type == NodeType::Folder)
+            .map(|(name, child)| {
+                let child_path = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{path}/{name}")
+                };
+                RemoteFolder {
+                    id: child.id.lock().unwrap().clone().unwrap_or_default(),
+                    name: name.clone(),
+                    children: child.folders(&child_path),
+                    path: child_path,
+                }
+            })
+            .collect()
+    }
+
+    fn rehash(&mut self) {
+        for child in self
+            .content
+            .iter_mut()
+            .flat_map(|children| children.values_mut())
+        {
+            child.rehash();
+        }
+        self.recalculate_hash().unwrap();
+    }
+}
+
+/// Reads a tree saved as JSON, the way it was kept before the index.
+pub fn read_tree(path: &Path) -> std::io::Result<Node> {
+    let json = fs::read_to_string(path)?;
+    let node = serde_json::from_str(&json)?;
+    Ok(node)
 }
 
-fn sort_changes(changes: &mut Vec<Change>) {
+fn sort_changes(changes: &mut [Change]) {
     changes.sort_by(|a, b| {
         // 1. Folder before file
         let node_type_cmp = match (&a.node_type, &b.node_type) {
@@ -0,0 +1,416 @@
+//! The last synced state of a sync root, kept in an SQLite database under the
+//! app data directory with one row per node. A change updates the rows it
+//! touches in one transaction instead of writing the whole tree out again.
+use rusqlite::{params, Connection, Transaction};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::Mutex;
+
+use crate::synchronizer::fstree::{self, Node, NodeType, Row};
+
+const INDEX_DIR: &str = "index";
+/// Where the tree was saved before the index, relative to the working directory.
+const LEGACY_TREE_FILE: &str = "tree.json";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS nodes (
+        path TEXT PRIMARY KEY,
+        type TEXT NOT NULL,
+        id TEXT,
+        parent_id TEXT,
+        hash TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        mtime INTEGER NOT NULL,
+        status TEXT NOT NULL
+    );
+";
+
+/// Whether the server has a node the way the index has it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Status {
+    Synced,
+    /// A local change to it is waiting in the journal.
+    Pending,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Synced => "synced",
+            Status::Pending => "pending",
+        }
+    }
+}
+
+/// Writes go through the one connection, so they never interleave.
+pub(crate) struct Index {
+    conn: Mutex<Connection>,
+    root_path: String,
+}
+
+/// Opens the database, making sure it is a sound one.
+fn connect(file: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(file)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    // a transaction is on disk once it commits
+    conn.pragma_update(None, "synchronous", "FULL")?;
+    conn.execute_batch(SCHEMA)?;
+    let check: String = conn.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+    if check != "ok" {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CORRUPT),
+            Some(check),
+        ));
+    }
+    Ok(conn)
+}
+
+/// Removes a database along with its write-ahead log.
+fn remove_database(file: &Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        let mut name = file.as_os_str().to_owned();
+        name.push(suffix);
+        let _ = fs::remove_file(PathBuf::from(name));
+    }
+}
+
+/// The bounds of the paths under `path`, which sort right after it and its
+/// separator.
+fn descendants(path: &str) -> (String, String) {
+    let after_separator = char::from(MAIN_SEPARATOR as u8 + 1);
+    (
+        format!("{path}{MAIN_SEPARATOR}"),
+        format!("{path}{after_separator}"),
+    )
+}
+
+fn remove_subtree(tx: &Transaction, path: &str) -> rusqlite::Result<()> {
+    let (from, to) = descendants(path);
+    tx.execute(
+        "DELETE FROM nodes WHERE ?1 = '' OR path = ?1 OR (path >= ?2 AND path < ?3)",
+        params![path, from, to],
+    )?;
+    Ok(())
+}
+
+fn type_name(node_type: &NodeType) -> &'static str {
+    match node_type {
+        NodeType::File => "file",
+        NodeType::Folder => "folder",
+    }
+}
+
+fn read_rows(
+    conn: &Connection,
+    query: &str,
+    params: impl rusqlite::Params,
+) -> rusqlite::Result<Vec<Row>> {
+    let mut statement = conn.prepare_cached(query)?;
+    let rows = statement
+        .query_map(params, |row| {
+            let node_type: String = row.get(1)?;
+            Ok(Row {
+                path: row.get(0)?,
+                node_type: match node_type.as_str() {
+                    "folder" => NodeType::Folder,
+                    _ => NodeType::File,
+                },
+                id: row.get(2)?,
+                parent_id: row.get(3)?,
+                hash: row.get(4)?,
+                size: row.get::<_, i64>(5)? as u64,
+                mtime: row.get::<_, i64>(6)? as u64,
+            })
+        })?
+        .collect();
+    rows
+}
+
+/// Writes `row`, over the one at its path if there is one.
+fn upsert(tx: &Transaction, row: &Row, status: Status) -> rusqlite::Result<()> {
+    tx.prepare_cached(
+        "INSERT OR REPLACE INTO nodes (path, type, id, parent_id, hash, size, mtime, status)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?
+    .execute(params![
+        row.path,
+        type_name(&row.node_type),
+        row.id,
+        row.parent_id,
+        row.hash,
+        row.size as i64,
+        row.mtime as i64,
+        status.as_str(),
+    ])?;
+    Ok(())
+}
+
+impl Index {
+    /// Opens the index of `root_path`. A damaged one is replaced by the copy
+    /// taken the last time it opened fine, or started over without one.
+    pub fn open(data_dir: &Path, root_path: &Path) -> Self {
+        let root = root_path.to_string_lossy().to_string();
+        let dir = data_dir.join(INDEX_DIR);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join(format!("{}.db", fstree::hash_bytes(root.as_bytes())));
+        let backup = file.with_extension("db.bak");
+        let conn = match connect(&file) {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Can't use {} ({e}), restoring the backup", file.display());
+                remove_database(&file);
+                let restored = fs::copy(&backup, &file)
+                    .map_err(|e| e.to_string())
+                    .and_then(|_| connect(&file).map_err(|e| e.to_string()));
+                restored.unwrap_or_else(|e| {
+                    println!("No usable backup ({e}), starting over");
+                    remove_database(&file);
+                    connect(&file).unwrap()
+                })
+            }
+        };
+        let _ = fs::remove_file(&backup);
+        if let Err(e) = conn.execute("VACUUM INTO ?1", [backup.to_string_lossy()]) {
+            println!("Failed to back up {}: {e}", file.display());
+        }
+        let index = Self {
+            conn: Mutex::new(conn),
+            root_path: root,
+        };
+        index.import(data_dir);
+        index
+    }
+
+    /// Takes over a tree saved as JSON by an older version, if there is one for
+    /// this root and the index is still empty.
+    fn import(&self, data_dir: &Path) {
+        let is_empty = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT NOT EXISTS (SELECT 1 FROM nodes)", [], |row| {
+                row.get(0)
+            })
+            .unwrap_or(false);
+        if !is_empty {
+            return;
+        }
+        let saved = data_dir.join("trees").join(format!(
+            "{}.json",
+            fstree::hash_bytes(self.root_path.as_bytes())
+        ));
+        let candidates = [
+            saved.clone(),
+            saved.with_extension("json.bak"),
+            PathBuf::from(LEGACY_TREE_FILE),
+        ];
+        for candidate in candidates {
+            let Ok(tree) = fstree::read_tree(&candidate) else {
+                continue;
+            };
+            if tree.path.as_deref() != Some(self.root_path.as_str()) {
+                continue;
+            }
+            match self.replace(&tree) {
+                Ok(()) => println!("Imported {} into the index", candidate.display()),
+                Err(e) => println!("Failed to import {}: {e}", candidate.display()),
+            }
+            return;
+        }
+    }
+
+    /// The tree as last synced, if anything was.
+    pub fn load(&self) -> Option<Node> {
+        let conn = self.conn.lock().unwrap();
+        let rows = read_rows(
+            &conn,
+            "SELECT path, type, id, parent_id, hash, size, mtime FROM nodes",
+            [],
+        )
+        .unwrap();
+        if rows.is_empty() {
+            return None;
+        }
+        Some(Node::from_rows(&self.root_path, rows))
+    }
+
+    /// The tree as last synced at and under each of `paths`, see `Node::part`.
+    pub fn part(&self, paths: &[String]) -> Node {
+        let conn = self.conn.lock().unwrap();
+        let mut rows = vec![];
+        for path in paths {
+            let (from, to) = descendants(path);
+            rows.extend(
+                read_rows(
+                    &conn,
+                    "SELECT path, type, id, parent_id, hash, size, mtime FROM nodes
+                     WHERE ?1 = '' OR path = ?1 OR (path >= ?2 AND path < ?3)",
+                    params![path, from, to],
+                )
+                .unwrap(),
+            );
+        }
+        Node::from_rows(&self.root_path, rows).part(paths)
+    }
+
+    /// Writes out all of `tree`, for when local and remote agree. Nodes still
+    /// waiting on the journal stay pending.
+    pub fn replace(&self, tree: &Node) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let pending: HashSet<String> = tx
+            .prepare("SELECT path FROM nodes WHERE status = ?1")?
+            .query_map([Status::Pending.as_str()], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        tx.execute("DELETE FROM nodes", [])?;
+        for row in tree.rows("") {
+            let status = if pending.contains(&row.path) {
+                Status::Pending
+            } else {
+                Status::Synced
+            };
+            upsert(&tx, &row, status)?;
+        }
+        tx.commit()
+    }
+
+    /// Writes the node at `path` in `tree` with everything under it, and the
+    /// folders above it that aren't in the index yet.
+    pub fn put(&self, tree: &Node, path: &str, status: Status) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        remove_subtree(&tx, path)?;
+        if let Some(node) = tree.find(path) {
+            for row in node.rows(path) {
+                upsert(&tx, &row, status)?;
+            }
+            for ancestor in Path::new(path).ancestors().skip(1) {
+                let ancestor = ancestor.to_string_lossy();
+                let known: bool = tx.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM nodes WHERE path = ?1)",
+                    [&ancestor],
+                    |row| row.get(0),
+                )?;
+                if known {
+                    break;
+                }
+                if let Some(folder) = tree.find(&ancestor) {
+                    upsert(&tx, &folder.row(&ancestor), Status::Synced)?;
+                }
+            }
+        }
+        tx.commit()
+    }
+
+    /// Marks the node at `path` synced, with the id the server gave it.
+    pub fn set_synced(&self, tree: &Node, path: &str) -> rusqlite::Result<()> {
+        let Some(node) = tree.find(path) else {
+            return Ok(());
+        };
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        upsert(&tx, &node.row(path), Status::Synced)?;
+        tx.commit()
+    }
+
+    /// Writes the nodes at `paths` the way they are in `tree`, keeping the
+    /// status of those already in the index. New ones are synced.
+    pub fn settle(&self, tree: &Node, paths: &[String]) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for path in paths {
+            let Some(node) = tree.find(path) else {
+                continue;
+            };
+            let row = node.row(path);
+            tx.prepare_cached(
+                "INSERT INTO nodes (path, type, id, parent_id, hash, size, mtime, status)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (path) DO UPDATE SET type = excluded.type, id = excluded.id,
+                 parent_id = excluded.parent_id, hash = excluded.hash, size = excluded.size,
+                 mtime = excluded.mtime",
+            )?
+            .execute(params![
+                row.path,
+                type_name(&row.node_type),
+                row.id,
+                row.parent_id,
+                row.hash,
+                row.size as i64,
+                row.mtime as i64,
+                Status::Synced.as_str(),
+            ])?;
+        }
+        tx.commit()
+    }
+
+    /// Whether there is a node at `path`.
+    pub fn contains(&self, path: &str) -> rusqlite::Result<bool> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM nodes WHERE path = ?1)",
+            [path],
+            |row| row.get(0),
+        )
+    }
+
+    /// Whether a local change at `path` or under it waits for the journal.
+    pub fn has_pending(&self, path: &str) -> rusqlite::Result<bool> {
+        let (from, to) = descendants(path);
+        self.conn.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM nodes WHERE status = ?4
+             AND (path = ?1 OR (path >= ?2 AND path < ?3)))",
+            params![path, from, to, Status::Pending.as_str()],
+            |row| row.get(0),
+        )
+    }
+
+    /// The paths at `path` or under it with a local change waiting for the
+    /// journal.
+    pub fn pending(&self, path: &str) -> rusqlite::Result<Vec<String>> {
+        let (from, to) = descendants(path);
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT path FROM nodes WHERE status = ?4
+             AND (?1 = '' OR path = ?1 OR (path >= ?2 AND path < ?3))",
+        )?;
+        let paths = statement
+            .query_map(params![path, from, to, Status::Pending.as_str()], |row| {
+                row.get(0)
+            })?
+            .collect();
+        paths
+    }
+
+    /// Removes the node at `path` and everything under it.
+    pub fn remove(&self, path: &str) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        remove_subtree(&tx, path)?;
+        tx.commit()
+    }
+
+    /// Moves the node at `from` and everything under it to `to`, and writes
+    /// the node as it is in `tree` there.
+    pub fn rename(
+        &self,
+        tree: &Node,
+        from: &str,
+        to: &str,
+        status: Status,
+    ) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        remove_subtree(&tx, to)?;
+        let (lower, upper) = descendants(from);
+        tx.execute(
+            "UPDATE nodes SET path = ?2 || substr(path, length(?1) + 1)
+             WHERE path = ?1 OR (path >= ?3 AND path < ?4)",
+            params![from, to, lower, upper],
+        )?;
+        if let Some(node) = tree.find(to) {
+            upsert(&tx, &node.row(to), status)?;
+        }
+        tx.commit()
+    }
+}
@@ -0,0 +1,490 @@
+//! Remote operations waiting to be applied, kept in the data dir so changes
+//! made while offline, or before a crash, still reach the server. Operations
+//! name local paths and look the ids up when they run, since an earlier
+//! operation may be the one that creates them.
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::synchronizer::backend::BackendError;
+use crate::synchronizer::transfer::{self, TransferError};
+use crate::synchronizer::{pair_data_dir, SyncContext};
+use crate::CONFIG;
+
+pub const JOURNAL_FILE: &str = "journal.json";
+/// How long to wait before retrying after a failure, without a reconnect or a
+/// new local change to trigger it sooner.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Mkdir {
+        path: String,
+    },
+    Upload {
+        path: String,
+    },
+    Rename {
+        from: String,
+        path: String,
+    },
+    /// The node is gone locally, so the id is kept here.
+    Delete {
+        id: String,
+        path: String,
+    },
+}
+
+impl Operation {
+    fn path(&self) -> &str {
+        match self {
+            Operation::Mkdir { path }
+            | Operation::Upload { path }
+            | Operation::Rename { path, .. }
+            | Operation::Delete { path, .. } => path,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub op: Operation,
+}
+
+/// A queued operation as the UI lists it, with the sync pair it belongs to.
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingOperation {
+    pub pair: String,
+    #[serde(flatten)]
+    pub entry: Entry,
+}
+
+/// What each pair last had queued, by pair id, so they show together.
+static PENDING: LazyLock<Mutex<BTreeMap<String, Vec<Entry>>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+pub(crate) struct Journal {
+    file: PathBuf,
+    state: Mutex<State>,
+    replaying: AtomicBool,
+    wake: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    entries: Vec<Entry>,
+    next_seq: u64,
+    /// The entries being applied, left alone by coalescing since the server
+    /// may already have them.
+    in_flight: Vec<u64>,
+}
+
+/// The pending operations in `data_dir`, oldest first.
+pub fn load(data_dir: &Path) -> Vec<Entry> {
+    std::fs::read_to_string(data_dir.join(JOURNAL_FILE))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// The pending operations of the pairs of all profiles, under the app's
+/// `data_dir`.
+pub fn load_all(data_dir: &Path) -> Vec<PendingOperation> {
+    let pairs = CONFIG.lock().unwrap().all_pairs();
+    pairs
+        .into_iter()
+        .flat_map(|pair| {
+            load(&pair_data_dir(data_dir, &pair.id))
+                .into_iter()
+                .map(move |entry| PendingOperation {
+                    pair: pair.id.clone(),
+                    entry,
+                })
+        })
+        .collect()
+}
+
+/// Where `path` ends up when `from` is renamed to `to`, if it is `from` or
+/// inside it.
+fn moved(path: &str, from: &str, to: &str) -> Option<String> {
+    let rest = Path::new(path).strip_prefix(from).ok()?;
+    Some(Path::new(to).join(rest).to_string_lossy().to_string())
+}
+
+fn is_inside(path: &str, folder: &str) -> bool {
+    Path::new(path).starts_with(folder)
+}
+
+impl State {
+    /// Appends `op`, folding it into what is already waiting: repeated
+    /// uploads run once, creations not sent yet move with renames and vanish
+    /// with deletes, and renames of the same node chain into one.
+    fn push(&mut self, op: Operation, remote_id: Option<&str>) -> Vec<Entry> {
+        let in_flight = self.in_flight.clone();
+        let waiting = |entry: &&mut Entry| !in_flight.contains(&entry.seq);
+        match &op {
+            Operation::Mkdir { .. } | Operation::Upload { .. } => {
+                let queued = |entry: &Entry| !in_flight.contains(&entry.seq) && entry.op == op;
+                if self.entries.iter().any(queued) {
+                    return vec![];
+                }
+            }
+            Operation::Rename { from, path } => {
+                let mut chained = false;
+                for entry in self.entries.iter_mut().filter(waiting) {
+                    match &mut entry.op {
+                        Operation::Mkdir { path: p } | Operation::Upload { path: p } => {
+                            if let Some(new_path) = moved(p, from, path) {
+                                *p = new_path;
+                            }
+                        }
+                        Operation::Rename { path: p, .. } => {
+                            chained |= p == from;
+                            if let Some(new_path) = moved(p, from, path) {
+                                *p = new_path;
+                            }
+                        }
+                        Operation::Delete { .. } => {}
+                    }
+                }
+                // renamed back to where it started
+                self.entries.retain(|entry| {
+                    in_flight.contains(&entry.seq)
+                        || !matches!(&entry.op, Operation::Rename { from, path } if from == path)
+                });
+                // not on the server yet, or already folded into an earlier rename
+                if remote_id.is_none() || chained {
+                    return vec![];
+                }
+            }
+            Operation::Delete { path, .. } => {
+                let discarded = self.discard(path);
+                self.append(op);
+                return discarded;
+            }
+        }
+        self.append(op);
+        vec![]
+    }
+
+    fn append(&mut self, op: Operation) {
+        self.entries.push(Entry {
+            seq: self.next_seq,
+            op,
+        });
+        self.next_seq += 1;
+    }
+
+    /// Drops waiting work on `path` or inside it, which was deleted, and
+    /// returns it.
+    fn discard(&mut self, path: &str) -> Vec<Entry> {
+        let in_flight = &self.in_flight;
+        let (kept, discarded) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|entry| {
+                in_flight.contains(&entry.seq)
+                    || matches!(entry.op, Operation::Delete { .. })
+                    || !is_inside(entry.op.path(), path)
+            });
+        self.entries = kept;
+        discarded
+    }
+}
+
+impl Journal {
+    pub fn open(data_dir: &Path) -> Self {
+        let entries = load(data_dir);
+        let next_seq = entries.iter().map(|entry| entry.seq + 1).max().unwrap_or(0);
+        Self {
+            file: data_dir.join(JOURNAL_FILE),
+            state: Mutex::new(State {
+                entries,
+                next_seq,
+                in_flight: vec![],
+            }),
+            replaying: AtomicBool::new(false),
+            wake: Notify::new(),
+        }
+    }
+
+    pub fn entries(&self) -> Vec<Entry> {
+        self.state.lock().unwrap().entries.clone()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().entries.is_empty()
+    }
+
+    /// Written to a temp file and renamed over, so a crash keeps either the
+    /// old journal or the new one.
+    fn save(&self, state: &State) {
+        if let Err(e) = self.write(state) {
+            println!("Failed to save {}: {e}", self.file.display());
+        }
+    }
+
+    fn write(&self, state: &State) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(&state.entries)?;
+        let temp = self.file.with_extension("json.tmp");
+        let mut file = std::fs::File::create(&temp)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &self.file)?;
+        // the rename itself only lasts once the directory is synced
+        #[cfg(unix)]
+        std::fs::File::open(self.file.parent().unwrap())?.sync_all()?;
+        Ok(())
+    }
+
+    fn push(&self, op: Operation, remote_id: Option<&str>) -> Vec<Entry> {
+        let mut state = self.state.lock().unwrap();
+        let discarded = state.push(op, remote_id);
+        self.save(&state);
+        discarded
+    }
+
+    fn discard(&self, path: &str) -> Vec<Entry> {
+        let mut state = self.state.lock().unwrap();
+        let discarded = state.discard(path);
+        self.save(&state);
+        discarded
+    }
+
+    /// The next entry, or all the uploads at the front at once, since the
+    /// scheduler runs those side by side.
+    fn start_next(&self) -> Vec<Entry> {
+        let mut state = self.state.lock().unwrap();
+        let is_upload = |entry: &&Entry| matches!(entry.op, Operation::Upload { .. });
+        let batch: Vec<Entry> = match state.entries.first() {
+            Some(first) if is_upload(&first) => state
+                .entries
+                .iter()
+                .take_while(is_upload)
+                .cloned()
+                .collect(),
+            first => first.cloned().into_iter().collect(),
+        };
+        state.in_flight = batch.iter().map(|entry| entry.seq).collect();
+        batch
+    }
+
+    fn finish(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight.retain(|&in_flight| in_flight != seq);
+        state.entries.retain(|entry| entry.seq != seq);
+        self.save(&state);
+    }
+
+    fn postpone(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight.retain(|&in_flight| in_flight != seq);
+    }
+}
+
+/// Queues `op` and wakes the replay task. `remote_id` is the id the node
+/// already has on the server, if any.
+pub(crate) fn enqueue(ctx: &SyncContext, op: Operation, remote_id: Option<&str>) {
+    println!("queued {op:?}");
+    let discarded = ctx.journal.push(op, remote_id);
+    cancel_uploads(ctx, discarded);
+    emit_pending(ctx);
+    ctx.journal.wake.notify_one();
+}
+
+/// Tells the UI what the pairs of all profiles have queued, this one's having
+/// changed.
+fn emit_pending(ctx: &SyncContext) {
+    let pairs: Vec<String> = CONFIG
+        .lock()
+        .unwrap()
+        .all_pairs()
+        .into_iter()
+        .map(|pair| pair.id)
+        .collect();
+    let operations: Vec<PendingOperation> = {
+        let mut pending = PENDING.lock().unwrap();
+        pending.insert(ctx.pair_id.clone(), ctx.journal.entries());
+        pending.retain(|pair, _| pairs.contains(pair));
+        pending
+            .iter()
+            .flat_map(|(pair, entries)| {
+                entries.iter().map(|entry| PendingOperation {
+                    pair: pair.clone(),
+                    entry: entry.clone(),
+                })
+            })
+            .collect()
+    };
+    ctx.emit("pending_operations", operations);
+}
+
+/// Forgets queued work for a deleted path that never reached the server.
+pub(crate) fn discard(ctx: &SyncContext, path: &str) {
+    let discarded = ctx.journal.discard(path);
+    cancel_uploads(ctx, discarded);
+    emit_pending(ctx);
+}
+
+/// Uploads that were waiting to resume show as cancelled once dropped.
+fn cancel_uploads(ctx: &SyncContext, discarded: Vec<Entry>) {
+    for entry in discarded {
+        if let Operation::Upload { path } = entry.op {
+            transfer::cancel_upload(ctx, &path);
+        }
+    }
+}
+
+/// Replays the journal whenever something is queued, and every so often
+/// while something is stuck in it.
+pub(crate) async fn run(ctx: &SyncContext) {
+    while !ctx.cancel.is_cancelled() {
+        replay(ctx).await;
+        let retry_after = if ctx.journal.is_empty() {
+            Duration::MAX
+        } else {
+            RETRY_INTERVAL
+        };
+        tokio::select! {
+            _ = ctx.journal.wake.notified() => {},
+            _ = tokio::time::sleep(retry_after) => {},
+            _ = ctx.cancel.cancelled() => {},
+        }
+    }
+}
+
+/// Applies the queued operations in order, stopping at the first one that
+/// can't reach the server. Uploads next to each other go together. Returns at
+/// once if a replay is already running.
+pub(crate) async fn replay(ctx: &SyncContext) {
+    if ctx.journal.replaying.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    loop {
+        if !ctx.proceed().await {
+            break;
+        }
+        let batch = ctx.journal.start_next();
+        if batch.is_empty() {
+            break;
+        }
+        let results = join_all(batch.iter().map(|entry| async {
+            let result = apply(ctx, &entry.op).await;
+            match &result {
+                Ok(()) => ctx.journal.finish(entry.seq),
+                Err(Failure::Retry(e)) => {
+                    println!("{:?} will be retried: {e}", entry.op);
+                    ctx.journal.postpone(entry.seq);
+                }
+                Err(Failure::Drop(e)) => {
+                    println!("Dropping {:?}: {e}", entry.op);
+                    ctx.journal.finish(entry.seq);
+                }
+            }
+            emit_pending(ctx);
+            result
+        }))
+        .await;
+        if results
+            .iter()
+            .any(|result| matches!(result, Err(Failure::Retry(_))))
+        {
+            break;
+        }
+    }
+    ctx.journal.replaying.store(false, Ordering::SeqCst);
+}
+
+enum Failure {
+    /// The server couldn't be reached, keep it for later.
+    Retry(String),
+    /// The server refused it, retrying won't help.
+    Drop(String),
+}
+
+impl From<BackendError> for Failure {
+    fn from(e: BackendError) -> Self {
+        match e {
+            BackendError::Transport(_) | BackendError::Unauthorized => {
+                Failure::Retry(e.to_string())
+            }
+            BackendError::Status(status) if status >= 500 => Failure::Retry(e.to_string()),
+            _ => Failure::Drop(e.to_string()),
+        }
+    }
+}
+
+async fn apply(ctx: &SyncContext, op: &Operation) -> Result<(), Failure> {
+    let node = |path: &str| {
+        ctx.tree
+            .lock()
+            .unwrap()
+            .find(path)
+            .map(|node| (node.id.clone(), node.parent_id.clone()))
+    };
+    match op {
+        Operation::Mkdir { path } => {
+            let Some((id, parent_id)) = node(path) else {
+                return Ok(());
+            };
+            if id.lock().unwrap().is_some() {
+                return Ok(());
+            }
+            let Some(parent_id) = parent_id.lock().unwrap().clone() else {
+                return Err(Failure::Drop("parent not uploaded".to_string()));
+            };
+            let name = Path::new(path).file_name().unwrap().to_string_lossy();
+            let new_id = ctx.backend.create_folder(&name, &parent_id).await?;
+            id.lock().unwrap().replace(new_id);
+        }
+        Operation::Upload { path } => {
+            let Some((id, parent_id)) = node(path) else {
+                transfer::cancel_upload(ctx, path);
+                return Ok(());
+            };
+            // a failed upload shows in the transfers, where it can be retried
+            transfer::upload(ctx, id, parent_id, path)
+                .await
+                .map_err(|e| match e {
+                    TransferError::Offline(reason) => Failure::Retry(reason),
+                    TransferError::Failed(reason) => Failure::Drop(reason),
+                })?;
+        }
+        Operation::Rename { from, path } => {
+            let Some((id, parent_id)) = node(path) else {
+                return Ok(());
+            };
+            let Some(id) = id.lock().unwrap().clone() else {
+                return Ok(());
+            };
+            let parent_id = parent_id.lock().unwrap().clone();
+            ctx.backend
+                .rename(
+                    &id,
+                    parent_id.as_deref(),
+                    &ctx.remote_path(from),
+                    &ctx.remote_path(path),
+                )
+                .await?;
+        }
+        Operation::Delete { id, path } => {
+            match ctx.backend.delete(id, &ctx.remote_path(path)).await {
+                Err(BackendError::NotFound) => {}
+                result => result?,
+            }
+            return Ok(());
+        }
+    }
+    ctx.index
+        .set_synced(&ctx.tree.lock().unwrap(), op.path())
+        .unwrap();
+    Ok(())
+}
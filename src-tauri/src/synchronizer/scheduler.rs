@@ -0,0 +1,160 @@
+//! Caps how many uploads and downloads run at once. Transfers over the limit
+//! wait their turn in arrival order, or smallest first when configured, so a
+//! few large files don't hold up many small ones.
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+use crate::types::{TransferLimits, TransferType};
+
+pub(crate) struct Scheduler {
+    uploads: Arc<Lane>,
+    downloads: Arc<Lane>,
+}
+
+/// The uploads or the downloads.
+struct Lane {
+    limit: usize,
+    small_files_first: bool,
+    state: Mutex<LaneState>,
+}
+
+#[derive(Default)]
+struct LaneState {
+    running: usize,
+    next_seq: u64,
+    waiting: BinaryHeap<Waiter>,
+}
+
+/// The greatest key goes first: the smallest file when sizes count, then the
+/// one that has waited longest.
+struct Waiter {
+    key: (Reverse<u64>, Reverse<u64>),
+    turn: oneshot::Sender<()>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// A running transfer's slot, handed to the next one in line when dropped.
+pub(crate) struct Permit(Arc<Lane>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// A place in line. Gives the slot back if it came through after the wait
+/// was abandoned.
+struct Ticket {
+    turn: Option<oneshot::Receiver<()>>,
+    lane: Arc<Lane>,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if let Some(mut turn) = self.turn.take() {
+            turn.close();
+            if turn.try_recv().is_ok() {
+                self.lane.release();
+            }
+        }
+    }
+}
+
+impl Lane {
+    fn new(limit: usize, small_files_first: bool) -> Arc<Self> {
+        Arc::new(Self {
+            limit: limit.max(1),
+            small_files_first,
+            state: Mutex::new(LaneState::default()),
+        })
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.waiting.pop() {
+            // the slot goes straight to the next one, unless it gave up waiting
+            if waiter.turn.send(()).is_ok() {
+                return;
+            }
+        }
+        state.running -= 1;
+    }
+}
+
+impl Scheduler {
+    pub fn new(limits: &TransferLimits) -> Self {
+        Self {
+            uploads: Lane::new(limits.max_uploads, limits.small_files_first),
+            downloads: Lane::new(limits.max_downloads, limits.small_files_first),
+        }
+    }
+
+    fn lane(&self, r#type: TransferType) -> &Arc<Lane> {
+        match r#type {
+            TransferType::Upload => &self.uploads,
+            TransferType::Download => &self.downloads,
+        }
+    }
+
+    /// A permit if a transfer may run right away, with nothing else waiting.
+    pub fn try_acquire(&self, r#type: TransferType) -> Option<Permit> {
+        let lane = self.lane(r#type);
+        let mut state = lane.state.lock().unwrap();
+        if state.running < lane.limit && state.waiting.is_empty() {
+            state.running += 1;
+            return Some(Permit(lane.clone()));
+        }
+        None
+    }
+
+    /// Waits until a transfer of `size` bytes may run. It runs for as long
+    /// as the permit is held.
+    pub async fn acquire(&self, r#type: TransferType, size: u64) -> Permit {
+        let lane = self.lane(r#type);
+        let turn = {
+            let mut state = lane.state.lock().unwrap();
+            if state.running < lane.limit && state.waiting.is_empty() {
+                state.running += 1;
+                return Permit(lane.clone());
+            }
+            let (tx, rx) = oneshot::channel();
+            let size = if lane.small_files_first { size } else { 0 };
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiting.push(Waiter {
+                key: (Reverse(size), Reverse(seq)),
+                turn: tx,
+            });
+            rx
+        };
+        let mut ticket = Ticket {
+            turn: Some(turn),
+            lane: lane.clone(),
+        };
+        // the sender only goes away after handing over the slot
+        let _ = ticket.turn.as_mut().unwrap().await;
+        ticket.turn = None;
+        Permit(lane.clone())
+    }
+}
@@ -0,0 +1,1445 @@
+//! Runs the synchronizer against the fake server and a temp folder.
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde_json::json;
+
+use crate::control;
+use crate::synchronizer::api::HttpBackend;
+use crate::synchronizer::backend::{BackendError, RemoteBackend};
+use crate::synchronizer::fake_server::{FakeServer, CERTIFICATE, PASSWORD, USERNAME};
+use crate::synchronizer::EventSink;
+use crate::synchronizer::{filter, fstree, journal, transfer};
+use crate::types::{
+    BandwidthLimits, ConnectionState, Profile, RetryPolicy, SelectiveSync, SyncPair, Token,
+    TransferLimits, TransferState,
+};
+use crate::CONFIG;
+
+/// The synchronizer keeps its state in globals, so the tests take turns.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+#[derive(Default)]
+struct RecordedEvents(Mutex<Vec<(String, serde_json::Value)>>);
+
+impl EventSink for RecordedEvents {
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        self.0.lock().unwrap().push((event.to_string(), payload));
+    }
+}
+
+impl RecordedEvents {
+    fn contains(&self, event: &str, payload: serde_json::Value) -> bool {
+        self.count(event, payload) > 0
+    }
+
+    /// When the event was first emitted, relative to the others.
+    fn position(&self, event: &str, payload: serde_json::Value) -> Option<usize> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|(e, p)| e == event && *p == payload)
+    }
+
+    fn count(&self, event: &str, payload: serde_json::Value) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(e, p)| e == event && *p == payload)
+            .count()
+    }
+}
+
+struct Harness {
+    server: FakeServer,
+    events: Arc<RecordedEvents>,
+    root: tempfile::TempDir,
+    data_dir: tempfile::TempDir,
+}
+
+impl Harness {
+    async fn start() -> Self {
+        let server = FakeServer::start().await;
+        let root = tempfile::tempdir().unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+
+        let tokens = HttpBackend::new(&server.url, None)
+            .auth(USERNAME, PASSWORD)
+            .await
+            .unwrap();
+        // a failed test may have left it paused
+        super::resume();
+        {
+            let mut config = CONFIG.lock().unwrap();
+            config.server_url = server.url.clone();
+            config.folder_path = root.path().to_string_lossy().to_string();
+            config.username = Some(USERNAME.to_string());
+            config.retry = RetryPolicy {
+                max_attempts: 2,
+                initial_delay_secs: 0,
+                max_delay_secs: 0,
+            };
+            // one at a time, so the order they run in shows
+            config.transfers = TransferLimits {
+                max_uploads: 1,
+                max_downloads: 1,
+                small_files_first: true,
+            };
+            config.bandwidth = BandwidthLimits::default();
+            config.selective_sync = SelectiveSync::default();
+            config.sync_pairs = None;
+            config.profiles = vec![];
+            config.token = Some(Token {
+                value: tokens.token,
+                created_at: SystemTime::now(),
+            });
+        }
+        let events = Arc::new(RecordedEvents::default());
+        start_sync(&events, data_dir.path());
+        eventually("connected", || events.contains("is_connected", true.into())).await;
+        // let the initial listing settle before making changes
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        Self {
+            server,
+            events,
+            root,
+            data_dir,
+        }
+    }
+
+    /// Starts the synchronizer over, telling `events` from then on.
+    fn restart(&self, events: &Arc<RecordedEvents>) {
+        start_sync(events, self.data_dir.path());
+    }
+
+    fn local(&self, path: &str) -> PathBuf {
+        self.root.path().join(path)
+    }
+
+    /// As transfers name it.
+    fn transfer_path(&self, path: &str) -> String {
+        self.local(path).to_string_lossy().to_string()
+    }
+
+    fn read_local(&self, path: &str) -> Option<Vec<u8>> {
+        std::fs::read(self.local(path)).ok()
+    }
+
+    fn write_local(&self, path: &str, content: &str) {
+        std::fs::write(self.local(path), content).unwrap();
+    }
+
+    /// Takes the server offline and waits for the client to notice.
+    async fn disconnect(&self) {
+        self.server.go_offline();
+        eventually("disconnected", || {
+            self.events.contains("is_connected", false.into())
+        })
+        .await;
+    }
+
+    /// Runs `change` on the server while the client is disconnected.
+    async fn while_offline(&self, change: impl FnOnce(&FakeServer)) {
+        self.disconnect().await;
+        change(&self.server);
+        self.server.go_online();
+    }
+
+    fn pending_operations(&self) -> Vec<journal::Entry> {
+        journal::load(self.data_dir.path())
+    }
+}
+
+fn start_sync(events: &Arc<RecordedEvents>, data_dir: &Path) {
+    let config = CONFIG.lock().unwrap().clone();
+    super::start(
+        events.clone(),
+        data_dir.to_path_buf(),
+        &config.profile_name,
+        Arc::new(
+            HttpBackend::from_profile(&config.main_profile())
+                .heartbeat(Duration::from_millis(200), Duration::from_millis(500)),
+        ),
+    );
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        super::stop();
+    }
+}
+
+/// Runs `test` on its own runtime, shut down before the next test starts so no
+/// synchronizer task outlives its test.
+fn sync_test<F: Future<Output = ()>>(test: impl FnOnce(Harness) -> F) {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        test(Harness::start().await).await;
+        super::shutdown().await;
+    });
+}
+
+async fn eventually(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(15);
+    while !condition() {
+        if Instant::now() > deadline {
+            panic!("timed out waiting for {what}");
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[test]
+fn local_file_is_uploaded() {
+    sync_test(|harness| async move {
+        harness.write_local("a.txt", "hello");
+        eventually("upload", || {
+            harness.server.read("a.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn local_folder_is_created_remotely() {
+    sync_test(|harness| async move {
+        std::fs::create_dir(harness.local("docs")).unwrap();
+        eventually("folder", || harness.server.is_folder("docs")).await;
+        harness.write_local("docs/b.txt", "inside");
+        eventually("upload into folder", || {
+            harness.server.read("docs/b.txt") == Some(b"inside".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn local_modification_is_uploaded() {
+    sync_test(|harness| async move {
+        harness.write_local("a.txt", "one");
+        eventually("upload", || {
+            harness.server.read("a.txt") == Some(b"one".to_vec())
+        })
+        .await;
+        harness.write_local("a.txt", "two");
+        eventually("new version", || {
+            harness.server.read("a.txt") == Some(b"two".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn local_deletion_is_deleted_remotely() {
+    sync_test(|harness| async move {
+        harness.write_local("a.txt", "hello");
+        eventually("upload", || harness.server.exists("a.txt")).await;
+        std::fs::remove_file(harness.local("a.txt")).unwrap();
+        eventually("remote delete", || !harness.server.exists("a.txt")).await;
+    });
+}
+
+#[test]
+fn local_rename_is_renamed_remotely() {
+    sync_test(|harness| async move {
+        harness.write_local("a.txt", "hello");
+        eventually("upload", || harness.server.exists("a.txt")).await;
+        // give the upload time to save the id the rename is sent for
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        std::fs::rename(harness.local("a.txt"), harness.local("b.txt")).unwrap();
+        eventually("remote rename", || {
+            !harness.server.exists("a.txt")
+                && harness.server.read("b.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn remote_file_is_downloaded() {
+    sync_test(|harness| async move {
+        harness.server.write("docs/a.txt", b"hello");
+        eventually("download", || {
+            harness.read_local("docs/a.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn remote_modification_is_downloaded() {
+    sync_test(|harness| async move {
+        harness.server.write("a.txt", b"one");
+        eventually("download", || {
+            harness.read_local("a.txt") == Some(b"one".to_vec())
+        })
+        .await;
+        harness.server.write("a.txt", b"two");
+        eventually("new version", || {
+            harness.read_local("a.txt") == Some(b"two".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn remote_deletion_is_deleted_locally() {
+    sync_test(|harness| async move {
+        harness.server.write("a.txt", b"hello");
+        eventually("download", || harness.local("a.txt").exists()).await;
+        harness.server.remove("a.txt");
+        eventually("local delete", || !harness.local("a.txt").exists()).await;
+    });
+}
+
+#[test]
+fn remote_rename_is_renamed_locally() {
+    sync_test(|harness| async move {
+        harness.server.write("a.txt", b"hello");
+        eventually("download", || harness.local("a.txt").exists()).await;
+        harness.server.rename("a.txt", "b.txt");
+        eventually("local rename", || {
+            !harness.local("a.txt").exists()
+                && harness.read_local("b.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn remote_changes_are_caught_up_after_reconnect() {
+    sync_test(|harness| async move {
+        harness.server.write("docs/a.txt", b"one");
+        eventually("download", || harness.local("docs/a.txt").exists()).await;
+        harness
+            .while_offline(|server| {
+                server.write("docs/a.txt", b"two");
+                server.rename("docs", "papers");
+                server.write("b.txt", b"new");
+            })
+            .await;
+        eventually("catch-up", || {
+            harness.read_local("papers/a.txt") == Some(b"two".to_vec())
+                && harness.read_local("b.txt") == Some(b"new".to_vec())
+                && !harness.local("docs").exists()
+        })
+        .await;
+        assert_eq!(harness.server.listings(), 1, "caught up from the cursor");
+    });
+}
+
+#[test]
+fn forgotten_cursor_falls_back_to_the_whole_tree() {
+    sync_test(|harness| async move {
+        harness
+            .while_offline(|server| {
+                server.write("a.txt", b"hello");
+                server.forget_changes();
+            })
+            .await;
+        eventually("download", || {
+            harness.read_local("a.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+        assert_eq!(harness.server.listings(), 2, "listed the whole tree again");
+    });
+}
+
+#[tokio::test]
+async fn login_and_keep_alive() {
+    let server = FakeServer::start().await;
+    let backend = HttpBackend::new(&server.url, None);
+    assert!(matches!(
+        backend.auth(USERNAME, "wrong").await,
+        Err(BackendError::Unauthorized)
+    ));
+    let tokens = backend.auth(USERNAME, PASSWORD).await.unwrap();
+    let refreshed = backend.refresh_auth(&tokens.refresh_token).await.unwrap();
+    assert_ne!(refreshed.token, tokens.token);
+    assert!(matches!(
+        backend.refresh_auth(&tokens.refresh_token).await,
+        Err(BackendError::Unauthorized)
+    ));
+}
+
+#[test]
+fn new_token_reaches_the_running_synchronizer() {
+    sync_test(|harness| async move {
+        let listings = harness.server.listings();
+        // logging in again is refused the old token
+        let tokens = HttpBackend::new(&harness.server.url, None)
+            .auth(USERNAME, PASSWORD)
+            .await
+            .unwrap();
+        let profile = CONFIG.lock().unwrap().profile_name.clone();
+        assert!(super::set_token(&profile, &tokens.token));
+        harness.write_local("a.txt", "hello");
+        eventually("upload", || {
+            harness.server.read("a.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+        assert_eq!(harness.server.listings(), listings, "kept running");
+
+        super::stop();
+        assert!(!super::set_token(&profile, &tokens.token));
+    });
+}
+
+#[tokio::test]
+async fn misplaced_range_is_not_taken_for_the_file() {
+    use futures_util::TryStreamExt;
+
+    let server = FakeServer::start().await;
+    let tokens = HttpBackend::new(&server.url, None)
+        .auth(USERNAME, PASSWORD)
+        .await
+        .unwrap();
+    let backend = HttpBackend::new(&server.url, Some(tokens.token));
+    server.write("a.txt", b"0123456789");
+    let id = server.id("a.txt").unwrap();
+    let etag = backend.download(&id, 0, None).await.unwrap().etag;
+
+    let resumed = backend.download(&id, 4, etag.as_deref()).await.unwrap();
+    assert!(resumed.resumed);
+    let body: Vec<u8> = resumed
+        .body
+        .map_ok(|bytes| bytes.to_vec())
+        .try_concat()
+        .await
+        .unwrap();
+    assert_eq!(body, b"456789");
+
+    // a range from the wrong offset is dropped for the whole file
+    server.misplace_ranges(true);
+    let whole = backend.download(&id, 4, etag.as_deref()).await.unwrap();
+    assert!(!whole.resumed);
+    let body: Vec<u8> = whole
+        .body
+        .map_ok(|bytes| bytes.to_vec())
+        .try_concat()
+        .await
+        .unwrap();
+    assert_eq!(body, b"0123456789");
+}
+
+#[tokio::test]
+async fn self_signed_certificate_needs_trusting() {
+    let server = FakeServer::start_tls().await;
+    let untrusted = Profile {
+        name: "Default".to_string(),
+        server_url: server.url.clone(),
+        username: Some(USERNAME.to_string()),
+        password: Some(PASSWORD.to_string()),
+        token: None,
+        refresh_token: None,
+        ca_bundle: None,
+        accept_invalid_certs: false,
+        active: true,
+        sync_pairs: vec![],
+    };
+    let with_ca_bundle = Profile {
+        ca_bundle: Some(CERTIFICATE.to_string()),
+        ..untrusted.clone()
+    };
+    let accepting_anything = Profile {
+        accept_invalid_certs: true,
+        ..untrusted.clone()
+    };
+    // the untrusted one goes last, reusing a token so only the certificate is in the way
+    let mut token = String::new();
+    for (profile, trusted) in [
+        (with_ca_bundle, true),
+        (accepting_anything, true),
+        (untrusted, false),
+    ] {
+        let login = HttpBackend::from_profile(&profile)
+            .auth(USERNAME, PASSWORD)
+            .await;
+        assert_eq!(login.is_ok(), trusted);
+        if let Ok(tokens) = login {
+            token = tokens.token;
+        }
+        let backend = HttpBackend::from_profile(&Profile {
+            token: Some(Token {
+                value: token.clone(),
+                created_at: SystemTime::now(),
+            }),
+            ..profile
+        });
+        assert_eq!(backend.watch_tree().await.is_ok(), trusted);
+    }
+}
+
+#[test]
+fn dead_connection_is_detected() {
+    sync_test(|harness| async move {
+        harness.server.freeze();
+        eventually("reconnecting", || {
+            harness
+                .events
+                .contains("connection_state", "reconnecting".into())
+        })
+        .await;
+        harness.server.write("a.txt", b"hello");
+        harness.server.thaw();
+        eventually("download", || {
+            harness.read_local("a.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn local_changes_made_offline_are_replayed() {
+    sync_test(|harness| async move {
+        harness.disconnect().await;
+        std::fs::create_dir(harness.local("docs")).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        harness.write_local("docs/a.txt", "written offline");
+        eventually("queued", || harness.pending_operations().len() == 2).await;
+        harness.server.go_online();
+        eventually("replay", || {
+            harness.server.read("docs/a.txt") == Some(b"written offline".to_vec())
+        })
+        .await;
+        eventually("empty journal", || harness.pending_operations().is_empty()).await;
+    });
+}
+
+#[test]
+fn offline_creation_and_deletion_cancel_out() {
+    sync_test(|harness| async move {
+        harness.disconnect().await;
+        harness.write_local("scratch.txt", "short-lived");
+        eventually("queued", || !harness.pending_operations().is_empty()).await;
+        std::fs::remove_file(harness.local("scratch.txt")).unwrap();
+        eventually("cancelled", || harness.pending_operations().is_empty()).await;
+        harness.server.go_online();
+        eventually("reconnected", || {
+            harness.events.count("is_connected", true.into()) == 2
+        })
+        .await;
+        assert!(!harness.server.exists("scratch.txt"));
+    });
+}
+
+#[test]
+fn failed_upload_can_be_retried() {
+    sync_test(|harness| async move {
+        harness.server.reject_uploads(true);
+        harness.write_local("a.txt", "hello");
+        let path = harness.transfer_path("a.txt");
+        eventually("retrying", || {
+            harness.events.contains(
+                "transfer",
+                json!({"type": "upload", "state": "retrying", "attempt": 1, "progress": 0, "path": path}),
+            )
+        })
+        .await;
+        eventually("failed", || {
+            super::TRANSFERS
+                .lock()
+                .unwrap()
+                .get(&PathBuf::from(&path))
+                .is_some_and(|transfer| matches!(transfer.state, TransferState::Failed { .. }))
+        })
+        .await;
+        assert!(harness.pending_operations().is_empty());
+
+        harness.server.reject_uploads(false);
+        super::retry_transfer(&path).unwrap();
+        eventually("upload", || {
+            harness.server.read("a.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+
+        super::stop();
+        assert!(super::RETRIES.lock().unwrap().is_empty());
+    });
+}
+
+#[test]
+fn download_that_cant_be_put_in_place_fails() {
+    sync_test(|harness| async move {
+        // a folder where the download is staged makes moving it into place fail
+        let staging = harness.local(&format!(".a.txt{}", filter::DOWNLOAD_SUFFIX));
+        std::fs::create_dir(&staging).unwrap();
+        harness.server.write("a.txt", b"hello");
+        let path = harness.transfer_path("a.txt");
+        eventually("failed", || {
+            super::TRANSFERS
+                .lock()
+                .unwrap()
+                .get(&PathBuf::from(&path))
+                .is_some_and(|transfer| matches!(transfer.state, TransferState::Failed { .. }))
+        })
+        .await;
+        assert!(!harness.local("a.txt").exists());
+        let temp_files = std::fs::read_dir(harness.data_dir.path().join("temp"))
+            .unwrap()
+            .count();
+        assert_eq!(temp_files, 2, "the download and its etag are kept");
+
+        std::fs::remove_dir(&staging).unwrap();
+        super::retry_transfer(&path).unwrap();
+        eventually("download", || {
+            harness.read_local("a.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn download_that_cant_be_written_fails() {
+    sync_test(|harness| async move {
+        // a folder in place of the temp file makes creating it fail
+        let temp_dir = harness.data_dir.path().join("temp");
+        let temp_name =
+            fstree::hash_bytes(format!("a.txt{}", fstree::hash_bytes(b"hello")).as_bytes());
+        std::fs::create_dir_all(temp_dir.join(&temp_name)).unwrap();
+        harness.server.write("a.txt", b"hello");
+        let path = harness.transfer_path("a.txt");
+        eventually("failed", || {
+            super::TRANSFERS
+                .lock()
+                .unwrap()
+                .get(&PathBuf::from(&path))
+                .is_some_and(|transfer| matches!(transfer.state, TransferState::Failed { .. }))
+        })
+        .await;
+
+        std::fs::remove_dir(temp_dir.join(&temp_name)).unwrap();
+        super::retry_transfer(&path).unwrap();
+        eventually("download", || {
+            harness.read_local("a.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+    });
+}
+
+/// Pauses and resumes like the daemon, without saving the config.
+#[cfg(unix)]
+struct TestController;
+
+#[cfg(unix)]
+#[async_trait::async_trait]
+impl control::Controller for TestController {
+    async fn sync(&self) {}
+
+    async fn set_paused(&self, paused: bool) {
+        if paused {
+            super::pause();
+        } else {
+            super::resume();
+        }
+    }
+
+    async fn logout(&self) {}
+}
+
+#[cfg(unix)]
+#[test]
+fn control_socket_reports_and_pauses() {
+    sync_test(|harness| async move {
+        let socket = harness.data_dir.path().join(control::SOCKET_FILE);
+        tokio::spawn(control::serve(socket.clone(), Arc::new(TestController)));
+        eventually("socket", || socket.exists()).await;
+        let status = || async {
+            let reply = control::send(&socket, control::Command::Status)
+                .await
+                .unwrap();
+            serde_json::from_value::<control::Status>(reply).unwrap()
+        };
+
+        let before = status().await;
+        assert!(!before.paused);
+        let folders = &before.profiles[0].folders;
+        assert_eq!(folders[0].path, harness.root.path().to_string_lossy());
+        assert_eq!(folders[0].connection, ConnectionState::Connected);
+
+        control::send(&socket, control::Command::Pause)
+            .await
+            .unwrap();
+        assert!(status().await.paused);
+        harness.write_local("a.txt", "hello");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!harness.server.exists("a.txt"));
+
+        control::send(&socket, control::Command::Resume)
+            .await
+            .unwrap();
+        assert!(!status().await.paused);
+        eventually("upload", || {
+            harness.server.read("a.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn transfer_limits_are_for_the_whole_app() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let limits = TransferLimits {
+        max_uploads: 1,
+        max_downloads: 1,
+        small_files_first: true,
+    };
+    let first = super::shared_scheduler(&limits);
+    assert!(Arc::ptr_eq(&first, &super::shared_scheduler(&limits)));
+    let raised = TransferLimits {
+        max_uploads: 2,
+        ..limits
+    };
+    assert!(!Arc::ptr_eq(&first, &super::shared_scheduler(&raised)));
+}
+
+#[test]
+fn queued_uploads_run_smallest_first() {
+    sync_test(|harness| async move {
+        harness.disconnect().await;
+        // queued in this order, the first takes the only slot
+        for (count, (path, content)) in [
+            ("a.txt", "a".repeat(64 * 1024)),
+            ("b.txt", "b".repeat(64 * 1024)),
+            ("c.txt", "c".to_string()),
+        ]
+        .iter()
+        .enumerate()
+        {
+            harness.write_local(path, content);
+            eventually("queued", || harness.pending_operations().len() == count + 1).await;
+        }
+        harness.server.go_online();
+        let completed = |path: &str| {
+            harness.events.position(
+                "transfer",
+                json!({"type": "upload", "state": "completed", "progress": 100, "path": harness.transfer_path(path)}),
+            )
+        };
+        eventually("uploads", || {
+            ["a.txt", "b.txt", "c.txt"]
+                .iter()
+                .all(|path| completed(path).is_some())
+        })
+        .await;
+        assert!(completed("c.txt") < completed("b.txt"));
+    });
+}
+
+#[test]
+fn downloads_are_throttled() {
+    sync_test(|harness| async move {
+        CONFIG.lock().unwrap().bandwidth.download_kib_per_sec = Some(64);
+        let started = Instant::now();
+        // a second's worth goes at once, the rest takes two more
+        harness.server.write("a.bin", &[0; 192 * 1024]);
+        eventually("download", || harness.local("a.bin").exists()).await;
+        assert!(started.elapsed() > Duration::from_millis(1500));
+
+        // lifting the limit applies without a restart
+        CONFIG.lock().unwrap().bandwidth.download_kib_per_sec = None;
+        let started = Instant::now();
+        harness.server.write("b.bin", &[0; 192 * 1024]);
+        eventually("download", || harness.local("b.bin").exists()).await;
+        assert!(started.elapsed() < Duration::from_millis(1500));
+    });
+}
+
+#[test]
+fn paused_sync_picks_up_on_resume() {
+    sync_test(|harness| async move {
+        super::pause();
+        harness.write_local("a.txt", "local");
+        harness.server.write("b.txt", b"remote");
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(!harness.server.exists("a.txt"));
+        assert!(!harness.local("b.txt").exists());
+
+        super::resume();
+        eventually("sync", || {
+            harness.server.read("a.txt") == Some(b"local".to_vec())
+                && harness.read_local("b.txt") == Some(b"remote".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn restart_stops_the_previous_synchronizer() {
+    sync_test(|harness| async move {
+        let restarted = Arc::new(RecordedEvents::default());
+        harness.restart(&restarted);
+        eventually("reconnected", || {
+            restarted.contains("is_connected", true.into())
+        })
+        .await;
+        let before = harness.events.0.lock().unwrap().len();
+        assert!(harness.events.contains("is_connected", false.into()));
+
+        harness.write_local("a.txt", "hello");
+        eventually("upload", || {
+            harness.server.read("a.txt") == Some(b"hello".to_vec())
+        })
+        .await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // only the new one saw the change
+        assert_eq!(harness.events.0.lock().unwrap().len(), before);
+    });
+}
+
+#[test]
+fn corrupted_index_is_restored_from_backup() {
+    sync_test(|harness| async move {
+        harness.write_local("a.txt", "one");
+        eventually("upload", || harness.server.exists("a.txt")).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // the index is backed up whenever it opens
+        let restarted = Arc::new(RecordedEvents::default());
+        harness.restart(&restarted);
+        eventually("reconnected", || {
+            restarted.contains("is_connected", true.into())
+        })
+        .await;
+        super::shutdown().await;
+
+        let index = std::fs::read_dir(harness.data_dir.path().join("index"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "db"))
+            .unwrap();
+        std::fs::write(index, "not a database").unwrap();
+        std::fs::remove_file(harness.local("a.txt")).unwrap();
+        harness.restart(&restarted);
+        // deleted while stopped, which only the index tells
+        eventually("remote delete", || !harness.server.exists("a.txt")).await;
+        assert!(harness.read_local("a.txt").is_none());
+    });
+}
+
+#[test]
+fn deselected_folders_are_not_synced() {
+    sync_test(|harness| async move {
+        harness.server.write("keep/a.txt", b"a");
+        harness.server.write("skip/b.txt", b"b");
+        eventually("download", || {
+            harness.local("keep/a.txt").exists() && harness.local("skip/b.txt").exists()
+        })
+        .await;
+        let backend = HttpBackend::from_profile(&CONFIG.lock().unwrap().main_profile());
+        let folders = super::remote_folders(&backend, None).await.unwrap();
+        let names: Vec<&str> = folders.iter().map(|folder| folder.name.as_str()).collect();
+        assert_eq!(names, ["keep", "skip"]);
+
+        CONFIG.lock().unwrap().selective_sync = SelectiveSync {
+            included: vec!["skip/inner".to_string()],
+            excluded: vec!["skip".to_string()],
+        };
+        let restarted = Arc::new(RecordedEvents::default());
+        harness.restart(&restarted);
+        eventually("local copy removed", || {
+            !harness.local("skip/b.txt").exists()
+        })
+        .await;
+        harness.server.write("skip/c.txt", b"c");
+        harness.server.write("skip/inner/d.txt", b"d");
+        harness.server.write("keep/e.txt", b"e");
+        eventually("download", || {
+            harness.local("keep/e.txt").exists() && harness.local("skip/inner/d.txt").exists()
+        })
+        .await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!harness.local("skip/c.txt").exists());
+        // a deselected folder is only gone locally
+        assert_eq!(harness.server.read("skip/b.txt"), Some(b"b".to_vec()));
+    });
+}
+
+#[test]
+fn sync_pairs_run_side_by_side() {
+    sync_test(|harness| async move {
+        harness.server.write("shared/a.txt", b"a");
+        eventually("download", || harness.local("shared/a.txt").exists()).await;
+        let backend = HttpBackend::from_profile(&CONFIG.lock().unwrap().main_profile());
+        let shared = super::remote_folders(&backend, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|folder| folder.name == "shared")
+            .unwrap();
+
+        let other = tempfile::tempdir().unwrap();
+        {
+            let mut config = CONFIG.lock().unwrap();
+            let mut pairs = config.pairs();
+            pairs.push(SyncPair {
+                id: "shared".to_string(),
+                local_path: other.path().to_string_lossy().to_string(),
+                remote_folder_id: Some(shared.id),
+                remote_path: shared.path,
+                selective_sync: SelectiveSync::default(),
+            });
+            config.sync_pairs = Some(pairs);
+        }
+        let restarted = Arc::new(RecordedEvents::default());
+        harness.restart(&restarted);
+        eventually("download into the second pair", || {
+            std::fs::read(other.path().join("a.txt")).ok() == Some(b"a".to_vec())
+        })
+        .await;
+        assert!(restarted.contains("pair_state", json!({"id": "shared", "state": "connected"})));
+
+        std::fs::write(other.path().join("b.txt"), "b").unwrap();
+        eventually("upload into the remote folder", || {
+            harness.server.read("shared/b.txt") == Some(b"b".to_vec())
+        })
+        .await;
+        // and on to the first pair, which syncs the whole tree
+        eventually("download into the first pair", || {
+            harness.read_local("shared/b.txt") == Some(b"b".to_vec())
+        })
+        .await;
+        assert!(!harness.server.exists("b.txt"));
+
+        // the second pair follows its folder when it moves
+        let listings = harness.server.listings();
+        harness.server.rename("shared", "common");
+        harness.server.write("common/c.txt", b"c");
+        eventually("download after the move", || {
+            std::fs::read(other.path().join("c.txt")).ok() == Some(b"c".to_vec())
+        })
+        .await;
+        assert!(other.path().join("a.txt").exists());
+        assert_eq!(harness.server.listings(), listings);
+    });
+}
+
+#[test]
+fn remote_changes_apply_without_listing_again() {
+    sync_test(|harness| async move {
+        let listings = harness.server.listings();
+        harness.server.write("docs/a.txt", b"a");
+        harness.server.write("docs/b.txt", b"b");
+        eventually("download", || {
+            harness.read_local("docs/a.txt").is_some() && harness.read_local("docs/b.txt").is_some()
+        })
+        .await;
+        harness.server.write("docs/a.txt", b"changed");
+        let id = harness.server.id("docs/a.txt");
+        harness.server.rename("docs", "papers");
+        harness.server.remove("papers/b.txt");
+        eventually("changes", || {
+            harness.read_local("papers/a.txt") == Some(b"changed".to_vec())
+                && !harness.local("papers/b.txt").exists()
+                && !harness.local("docs").exists()
+        })
+        .await;
+        assert_eq!(harness.server.listings(), listings);
+
+        // the moved file kept its id, a local edit updates it
+        harness.write_local("papers/a.txt", "local");
+        eventually("upload", || {
+            harness.server.read("papers/a.txt") == Some(b"local".to_vec())
+        })
+        .await;
+        assert_eq!(harness.server.id("papers/a.txt"), id);
+    });
+}
+
+#[test]
+fn profiles_sync_with_their_own_servers() {
+    sync_test(|harness| async move {
+        let server = FakeServer::start().await;
+        let tokens = HttpBackend::new(&server.url, None)
+            .auth(USERNAME, PASSWORD)
+            .await
+            .unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let profile = Profile {
+            name: "Client".to_string(),
+            server_url: server.url.clone(),
+            username: Some(USERNAME.to_string()),
+            password: Some(PASSWORD.to_string()),
+            token: Some(Token {
+                value: tokens.token,
+                created_at: SystemTime::now(),
+            }),
+            refresh_token: None,
+            ca_bundle: None,
+            accept_invalid_certs: false,
+            active: true,
+            sync_pairs: vec![SyncPair {
+                id: "client".to_string(),
+                local_path: other.path().to_string_lossy().to_string(),
+                remote_folder_id: None,
+                remote_path: String::new(),
+                selective_sync: SelectiveSync::default(),
+            }],
+        };
+        CONFIG.lock().unwrap().profiles = vec![profile.clone()];
+        let events = Arc::new(RecordedEvents::default());
+        super::start(
+            events.clone(),
+            harness.data_dir.path().to_path_buf(),
+            "Client",
+            Arc::new(
+                HttpBackend::from_profile(&profile)
+                    .heartbeat(Duration::from_millis(200), Duration::from_millis(500)),
+            ),
+        );
+        eventually("client profile connected", || {
+            events.contains(
+                "profile_state",
+                json!({"name": "Client", "state": "connected"}),
+            )
+        })
+        .await;
+
+        server.write("b.txt", b"b");
+        eventually("download from the client server", || {
+            std::fs::read(other.path().join("b.txt")).ok() == Some(b"b".to_vec())
+        })
+        .await;
+        std::fs::write(other.path().join("c.txt"), "c").unwrap();
+        eventually("upload to the client server", || {
+            server.read("c.txt") == Some(b"c".to_vec())
+        })
+        .await;
+        // the main profile carried on meanwhile
+        harness.server.write("a.txt", b"a");
+        eventually("download from the main server", || {
+            harness.read_local("a.txt") == Some(b"a".to_vec())
+        })
+        .await;
+        assert!(!harness.server.exists("b.txt") && !harness.server.exists("c.txt"));
+        assert!(harness.read_local("b.txt").is_none());
+
+        super::stop_profile("Client");
+        eventually("client profile offline", || {
+            events.contains(
+                "profile_state",
+                json!({"name": "Client", "state": "offline"}),
+            )
+        })
+        .await;
+        server.write("d.txt", b"d");
+        harness.server.write("e.txt", b"e");
+        eventually("download from the main server", || {
+            harness.read_local("e.txt") == Some(b"e".to_vec())
+        })
+        .await;
+        assert!(!other.path().join("d.txt").exists());
+    });
+}
+
+#[test]
+fn missing_folder_is_reported() {
+    sync_test(|harness| async move {
+        let missing = harness.local("missing");
+        CONFIG.lock().unwrap().sync_pairs = Some(vec![SyncPair {
+            id: "missing".to_string(),
+            local_path: missing.to_string_lossy().to_string(),
+            remote_folder_id: None,
+            remote_path: String::new(),
+            selective_sync: SelectiveSync::default(),
+        }]);
+        let events = Arc::new(RecordedEvents::default());
+        harness.restart(&events);
+        let message = format!("{} doesn't exist or isn't a folder", missing.display());
+        eventually("error reported", || {
+            events.contains("pair_error", json!({"id": "missing", "message": message}))
+        })
+        .await;
+        assert!(!events.contains("pair_state", json!({"id": "missing", "state": "connected"})));
+    });
+}
+
+#[test]
+fn ids_are_restored_onto_a_fresh_tree() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(root.path().join("docs/old")).unwrap();
+    std::fs::write(root.path().join("docs/a.txt"), "a").unwrap();
+    let rules = filter::IgnoreRules::load(root.path(), &[], &SelectiveSync::default());
+    let saved = fstree::build_tree(root.path(), &rules).unwrap();
+    for (path, id) in [
+        ("", "root"),
+        ("docs", "1"),
+        ("docs/a.txt", "2"),
+        ("docs/old", "3"),
+    ] {
+        *saved.find(path).unwrap().id.lock().unwrap() = Some(id.to_string());
+    }
+
+    std::fs::remove_dir(root.path().join("docs/old")).unwrap();
+    std::fs::write(root.path().join("docs/b.txt"), "b").unwrap();
+    let mut tree = fstree::build_tree(root.path(), &rules).unwrap();
+    tree.restore_ids(&saved);
+
+    let id = |path: &str| tree.find(path).unwrap().id.lock().unwrap().clone();
+    assert_eq!(id(""), Some("root".to_string()));
+    assert_eq!(id("docs"), Some("1".to_string()));
+    assert_eq!(id("docs/a.txt"), Some("2".to_string()));
+    assert_eq!(id("docs/b.txt"), None);
+    // children follow their folder's id
+    let docs = tree.find("docs").unwrap();
+    assert!(Arc::ptr_eq(
+        &tree.find("docs/b.txt").unwrap().parent_id,
+        &docs.id
+    ));
+    *docs.id.lock().unwrap() = Some("4".to_string());
+    let parent_id = tree
+        .find("docs/a.txt")
+        .unwrap()
+        .parent_id
+        .lock()
+        .unwrap()
+        .clone();
+    assert_eq!(parent_id, Some("4".to_string()));
+}
+
+/// A tree from `(path, hash, id)` entries, folders ending in "/".
+fn tree(entries: &[(&str, &str, Option<&str>)]) -> fstree::Node {
+    let rows = entries
+        .iter()
+        .map(|(path, hash, id)| fstree::Row {
+            path: path.trim_end_matches('/').to_string(),
+            node_type: if path.ends_with('/') {
+                fstree::NodeType::Folder
+            } else {
+                fstree::NodeType::File
+            },
+            id: id.map(str::to_string),
+            parent_id: None,
+            hash: hash.to_string(),
+            size: 0,
+            mtime: 0,
+        })
+        .collect();
+    fstree::Node::from_rows("/root", rows)
+}
+
+fn describe(change: &fstree::Change) -> String {
+    match &change.change_type {
+        fstree::ChangeType::Renamed { from } => format!("Renamed {from} -> {}", change.path),
+        change_type => format!("{change_type:?} {}", change.path),
+    }
+}
+
+#[test]
+fn three_way_diff_sorts_out_overlapping_changes() {
+    let base = [
+        ("docs/", "", Some("1")),
+        ("docs/a.txt", "a", Some("2")),
+        ("b.txt", "b", Some("3")),
+    ];
+    let edited = |path: &str, hash: &'static str| {
+        let mut entries = base.to_vec();
+        entries.iter_mut().find(|e| e.0 == path).unwrap().1 = hash;
+        entries
+    };
+    let without = |path: &str| {
+        let mut entries = base.to_vec();
+        entries.retain(|e| !e.0.starts_with(path));
+        entries
+    };
+    let renamed_docs = vec![
+        ("papers/", "", Some("1")),
+        ("papers/a.txt", "a", Some("2")),
+        ("b.txt", "b", Some("3")),
+    ];
+    let new_file = [("c.txt", "c", None)];
+    struct Case {
+        name: &'static str,
+        base: Vec<(&'static str, &'static str, Option<&'static str>)>,
+        local: Vec<(&'static str, &'static str, Option<&'static str>)>,
+        remote: Vec<(&'static str, &'static str, Option<&'static str>)>,
+        pending: Vec<&'static str>,
+        local_changes: Vec<&'static str>,
+        remote_changes: Vec<&'static str>,
+        conflicts: Vec<&'static str>,
+    }
+    let cases = [
+        Case {
+            name: "both edit a file",
+            base: base.to_vec(),
+            local: edited("docs/a.txt", "x"),
+            remote: edited("docs/a.txt", "y"),
+            pending: vec![],
+            local_changes: vec![],
+            remote_changes: vec![],
+            conflicts: vec!["Modified docs/a.txt / Modified docs/a.txt"],
+        },
+        Case {
+            name: "both make the same edit",
+            base: base.to_vec(),
+            local: edited("docs/a.txt", "x"),
+            remote: edited("docs/a.txt", "x"),
+            pending: vec![],
+            local_changes: vec![],
+            remote_changes: vec![],
+            conflicts: vec![],
+        },
+        Case {
+            name: "local edit, remote delete",
+            base: base.to_vec(),
+            local: edited("b.txt", "x"),
+            remote: without("b.txt"),
+            pending: vec![],
+            local_changes: vec![],
+            remote_changes: vec![],
+            conflicts: vec!["Modified b.txt / Deleted b.txt"],
+        },
+        Case {
+            name: "local edit inside a folder deleted remotely",
+            base: base.to_vec(),
+            local: edited("docs/a.txt", "x"),
+            remote: without("docs"),
+            pending: vec![],
+            local_changes: vec![],
+            remote_changes: vec![],
+            conflicts: vec!["Modified docs/a.txt / Deleted docs"],
+        },
+        Case {
+            name: "local edit inside a folder renamed remotely",
+            base: base.to_vec(),
+            local: edited("docs/a.txt", "x"),
+            remote: renamed_docs.clone(),
+            pending: vec![],
+            local_changes: vec![],
+            remote_changes: vec![],
+            conflicts: vec!["Modified docs/a.txt / Renamed docs -> papers"],
+        },
+        Case {
+            name: "remote edit inside a folder deleted locally",
+            base: base.to_vec(),
+            local: without("docs"),
+            remote: edited("docs/a.txt", "x"),
+            pending: vec![],
+            local_changes: vec![],
+            remote_changes: vec![],
+            conflicts: vec!["Deleted docs / Modified docs/a.txt"],
+        },
+        Case {
+            name: "both delete a folder",
+            base: base.to_vec(),
+            local: without("docs"),
+            remote: without("docs"),
+            pending: vec![],
+            local_changes: vec![],
+            remote_changes: vec![],
+            conflicts: vec![],
+        },
+        Case {
+            name: "both rename a folder the same way",
+            base: base.to_vec(),
+            local: renamed_docs.clone(),
+            remote: renamed_docs.clone(),
+            pending: vec![],
+            local_changes: vec![],
+            remote_changes: vec![],
+            conflicts: vec![],
+        },
+        Case {
+            name: "edits to different files",
+            base: base.to_vec(),
+            local: edited("b.txt", "x"),
+            remote: edited("docs/a.txt", "y"),
+            pending: vec![],
+            local_changes: vec!["Modified b.txt"],
+            remote_changes: vec!["Modified docs/a.txt"],
+            conflicts: vec![],
+        },
+        Case {
+            name: "file never uploaded is not deleted remotely",
+            base: [base.as_slice(), &new_file].concat(),
+            local: [base.as_slice(), &new_file].concat(),
+            remote: base.to_vec(),
+            pending: vec!["c.txt"],
+            local_changes: vec!["Modified c.txt"],
+            remote_changes: vec![],
+            conflicts: vec![],
+        },
+        Case {
+            name: "pending edit inside a folder deleted remotely",
+            base: edited("docs/a.txt", "x"),
+            local: edited("docs/a.txt", "x"),
+            remote: without("docs"),
+            pending: vec!["docs/a.txt"],
+            local_changes: vec![],
+            remote_changes: vec![],
+            conflicts: vec!["Modified docs/a.txt / Deleted docs"],
+        },
+        Case {
+            name: "pending edit wins over a remote one",
+            base: edited("docs/a.txt", "x"),
+            local: edited("docs/a.txt", "x"),
+            remote: base.to_vec(),
+            pending: vec!["docs/a.txt"],
+            local_changes: vec!["Modified docs/a.txt"],
+            remote_changes: vec![],
+            conflicts: vec![],
+        },
+    ];
+    for case in cases {
+        let pending: Vec<String> = case.pending.iter().map(|p| p.to_string()).collect();
+        let merge = fstree::three_way_diff(
+            &tree(&case.base),
+            &tree(&case.local),
+            &tree(&case.remote),
+            &pending,
+        );
+        let sorted = |changes: &[fstree::Change]| {
+            let mut described: Vec<String> = changes.iter().map(describe).collect();
+            described.sort();
+            described
+        };
+        let conflicts: Vec<String> = merge
+            .conflicts
+            .iter()
+            .map(|c| format!("{} / {}", describe(&c.local), describe(&c.remote)))
+            .collect();
+        assert_eq!(sorted(&merge.local), case.local_changes, "{}", case.name);
+        assert_eq!(sorted(&merge.remote), case.remote_changes, "{}", case.name);
+        assert_eq!(conflicts, case.conflicts, "{}", case.name);
+    }
+}
+
+#[test]
+fn conflicted_copies_keep_the_extension() {
+    let date = chrono::Local::now().format("%Y-%m-%d");
+    assert_eq!(
+        super::conflicted_copy_path("docs/report.txt", "user"),
+        format!("docs/report (conflicted copy, user, {date}).txt")
+    );
+    assert_eq!(
+        super::conflicted_copy_path("Makefile", "user"),
+        format!("Makefile (conflicted copy, user, {date})")
+    );
+    assert_eq!(
+        super::conflicted_copy_path("archive.tar.gz", "user"),
+        format!("archive.tar (conflicted copy, user, {date}).gz")
+    );
+}
+
+#[test]
+fn folder_deleted_remotely_is_recreated_around_a_local_edit() {
+    sync_test(|harness| async move {
+        std::fs::create_dir(harness.local("docs")).unwrap();
+        eventually("folder", || harness.server.is_folder("docs")).await;
+        harness.write_local("docs/a.txt", "one");
+        harness.write_local("docs/b.txt", "b");
+        eventually("upload", || {
+            harness.server.read("docs/a.txt") == Some(b"one".to_vec())
+                && harness.server.read("docs/b.txt") == Some(b"b".to_vec())
+        })
+        .await;
+
+        harness.disconnect().await;
+        harness.write_local("docs/a.txt", "two");
+        eventually("edit queued", || {
+            harness.pending_operations().iter().any(|entry| {
+                matches!(&entry.op, journal::Operation::Upload { path } if path == "docs/a.txt")
+            })
+        })
+        .await;
+        harness.server.remove("docs");
+        harness.server.go_online();
+
+        eventually("folder recreated", || {
+            harness.server.read("docs/a.txt") == Some(b"two".to_vec())
+                && harness.server.read("docs/b.txt") == Some(b"b".to_vec())
+        })
+        .await;
+        assert_eq!(harness.read_local("docs/a.txt"), Some(b"two".to_vec()));
+    });
+}
+
+#[test]
+fn edited_syncignore_applies_right_away() {
+    sync_test(|harness| async move {
+        harness.write_local("a.log", "one");
+        eventually("upload", || {
+            harness.server.read("a.log") == Some(b"one".to_vec())
+        })
+        .await;
+
+        harness.write_local(".syncignore", "*.log\n");
+        eventually("ignore file uploaded", || {
+            harness.server.exists(".syncignore")
+        })
+        .await;
+        harness.write_local("a.log", "two");
+        harness.write_local("b.log", "b");
+        harness.write_local("c.txt", "c");
+        eventually("upload", || harness.server.exists("c.txt")).await;
+        // left alone on both sides
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(harness.server.read("a.log"), Some(b"one".to_vec()));
+        assert!(!harness.server.exists("b.log"));
+        assert_eq!(harness.read_local("a.log"), Some(b"two".to_vec()));
+
+        harness.write_local(".syncignore", "");
+        eventually("no longer ignored", || {
+            harness.server.read("a.log") == Some(b"two".to_vec())
+                && harness.server.read("b.log") == Some(b"b".to_vec())
+        })
+        .await;
+    });
+}
+
+#[test]
+fn hash_cache_is_kept_per_root_by_relative_path() {
+    let root = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(root.path().join("docs")).unwrap();
+    std::fs::write(root.path().join("docs/a.txt"), "a").unwrap();
+    let rules = filter::IgnoreRules::load(root.path(), &[], &SelectiveSync::default());
+    fstree::open_hash_cache(root.path(), data_dir.path());
+    fstree::build_tree(root.path(), &rules).unwrap();
+
+    let saved = std::fs::read_to_string(data_dir.path().join("hash_cache.json")).unwrap();
+    let saved: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&saved).unwrap();
+    let relative = Path::new("docs")
+        .join("a.txt")
+        .to_string_lossy()
+        .to_string();
+    assert_eq!(saved.keys().collect::<Vec<_>>(), vec![&relative]);
+}
+
+#[test]
+fn interrupted_upload_resumes_after_restart() {
+    sync_test(|harness| async move {
+        // a chunk and a byte
+        let content = vec![7u8; transfer::CHUNK_SIZE as usize + 1];
+        harness.server.fail_chunks_from(Some(1));
+        std::fs::write(harness.local("big.bin"), &content).unwrap();
+        let session = || {
+            let dir = harness.data_dir.path().join("uploads");
+            let file = std::fs::read_dir(dir).ok()?.next()?.ok()?.path();
+            let session: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(file).ok()?).ok()?;
+            Some(session)
+        };
+        eventually("first chunk sent", || {
+            session().is_some_and(|session| session["uploaded"] == json!([0]))
+        })
+        .await;
+
+        super::stop();
+        let restarted = Arc::new(RecordedEvents::default());
+        harness.server.fail_chunks_from(None);
+        harness.restart(&restarted);
+        eventually("upload", || {
+            harness.server.read("big.bin").as_ref() == Some(&content)
+        })
+        .await;
+        // the first chunk wasn't sent again
+        assert_eq!(harness.server.chunks_received(), 2);
+        eventually("session removed", || session().is_none()).await;
+    });
+}
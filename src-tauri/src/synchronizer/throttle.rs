@@ -0,0 +1,126 @@
+//! Caps the combined speed of all uploads and of all downloads. Each direction
+//! is a token bucket filled at the configured rate, read from the config for
+//! every chunk so a new limit or a schedule starting applies right away.
+use chrono::{Local, NaiveTime};
+use futures_util::{Stream, StreamExt};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::types::{BandwidthLimits, BandwidthSchedule, TransferType};
+use crate::CONFIG;
+
+/// How much can go out at once after a pause, in seconds at the full rate.
+const BURST_SECS: f64 = 1.0;
+
+struct Bucket {
+    /// Bytes that may go right away, negative while chunks are waiting.
+    tokens: f64,
+    filled_at: Option<Instant>,
+}
+
+impl Bucket {
+    const fn new() -> Self {
+        Self {
+            tokens: 0.0,
+            filled_at: None,
+        }
+    }
+
+    /// Takes `len` bytes out at `rate` bytes a second, and returns how long
+    /// they have to wait for it.
+    fn take(&mut self, len: usize, rate: u64, now: Instant) -> Duration {
+        let rate = rate as f64;
+        let elapsed = self
+            .filled_at
+            .map_or(BURST_SECS, |filled_at| (now - filled_at).as_secs_f64());
+        self.tokens = (self.tokens + elapsed * rate).min(rate * BURST_SECS);
+        self.filled_at = Some(now);
+        self.tokens -= len as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+static UPLOADS: Mutex<Bucket> = Mutex::new(Bucket::new());
+static DOWNLOADS: Mutex<Bucket> = Mutex::new(Bucket::new());
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M").ok()
+}
+
+fn covers(schedule: &BandwidthSchedule, now: NaiveTime) -> bool {
+    let (Some(start), Some(end)) = (parse_time(&schedule.start), parse_time(&schedule.end)) else {
+        return false;
+    };
+    if start <= end {
+        start <= now && now < end
+    } else {
+        now >= start || now < end
+    }
+}
+
+/// The cap on `r#type` at `now` in bytes a second, if there is one.
+fn rate(limits: &BandwidthLimits, r#type: &TransferType, now: NaiveTime) -> Option<u64> {
+    let (upload, download) = match limits.schedules.iter().find(|s| covers(s, now)) {
+        Some(schedule) => (schedule.upload_kib_per_sec, schedule.download_kib_per_sec),
+        None => (limits.upload_kib_per_sec, limits.download_kib_per_sec),
+    };
+    let kib_per_sec = match r#type {
+        TransferType::Upload => upload,
+        TransferType::Download => download,
+    };
+    kib_per_sec
+        .filter(|&kib_per_sec| kib_per_sec > 0)
+        .map(|kib_per_sec| kib_per_sec.saturating_mul(1024))
+}
+
+/// Rejects schedules with times that aren't "HH:MM".
+pub fn check_schedules(limits: &BandwidthLimits) -> Result<(), String> {
+    for schedule in &limits.schedules {
+        for time in [&schedule.start, &schedule.end] {
+            if parse_time(time).is_none() {
+                return Err(format!("{time} is not a time of day like 08:30"));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn wait(r#type: &TransferType, len: usize) {
+    let rate = rate(
+        &CONFIG.lock().unwrap().bandwidth,
+        r#type,
+        Local::now().time(),
+    );
+    let Some(rate) = rate else {
+        return;
+    };
+    let bucket = match r#type {
+        TransferType::Upload => &UPLOADS,
+        TransferType::Download => &DOWNLOADS,
+    };
+    let delay = bucket.lock().unwrap().take(len, rate, Instant::now());
+    tokio::time::sleep(delay).await;
+}
+
+/// Holds each chunk of `stream` back for as long as the cap on `r#type`
+/// requires.
+pub fn limit<S, T, E>(stream: S, r#type: TransferType) -> impl Stream<Item = Result<T, E>>
+where
+    S: Stream<Item = Result<T, E>>,
+    T: AsRef<[u8]>,
+{
+    stream.then(move |chunk| {
+        let r#type = r#type.clone();
+        async move {
+            if let Ok(bytes) = &chunk {
+                wait(&r#type, bytes.as_ref().len()).await;
+            }
+            chunk
+        }
+    })
+}
@@ -0,0 +1,42 @@
+//! Certificate settings shared by the REST client and the websocket.
+use native_tls::{Certificate, TlsConnector};
+use reqwest::Client;
+
+use crate::types::Profile;
+
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+/// Trusts the system roots plus the profile's CA bundle, or anything at all
+/// when `accept_invalid_certs` is set.
+pub fn connector(profile: &Profile) -> Result<TlsConnector, String> {
+    let mut builder = TlsConnector::builder();
+    if let Some(path) = profile.ca_bundle.as_deref().filter(|path| !path.is_empty()) {
+        for cert in load_bundle(path)? {
+            builder.add_root_certificate(cert);
+        }
+    }
+    builder.danger_accept_invalid_certs(profile.accept_invalid_certs);
+    builder.build().map_err(|e| e.to_string())
+}
+
+pub fn http_client(connector: TlsConnector) -> Client {
+    Client::builder()
+        .use_preconfigured_tls(connector)
+        .build()
+        .unwrap()
+}
+
+/// Every certificate in a PEM file, `Certificate::from_pem` only reads the first.
+fn load_bundle(path: &str) -> Result<Vec<Certificate>, String> {
+    let pem = std::fs::read_to_string(path).map_err(|e| format!("can't read {path}: {e}"))?;
+    let certs = pem
+        .split_inclusive(PEM_END)
+        .filter(|block| block.contains(PEM_END))
+        .map(|block| Certificate::from_pem(block.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate in {path}: {e}"))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {path}"));
+    }
+    Ok(certs)
+}
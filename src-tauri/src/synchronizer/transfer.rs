@@ -0,0 +1,689 @@
+use futures_util::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{Cursor, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use std::{fs, io::Write, path::PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::synchronizer::backend::{BackendError, NewUpload};
+use crate::synchronizer::filter::DOWNLOAD_SUFFIX;
+use crate::synchronizer::index::Status;
+use crate::synchronizer::journal::{self, Operation};
+use crate::synchronizer::scheduler::Permit;
+use crate::synchronizer::{fstree, EventSink, SyncContext, TRANSFERS};
+use crate::types::{RetryPolicy, Transfer, TransferState, TransferType};
+use crate::CONFIG;
+
+pub(crate) const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Progress of a chunked upload, persisted so it can resume after a restart.
+#[derive(Serialize, Deserialize)]
+struct UploadSession {
+    session_id: String,
+    path: String, // relative from root
+    hash: String,
+    size: u64,
+    chunk_size: u64,
+    uploaded: BTreeSet<u64>,
+}
+
+impl UploadSession {
+    fn load(file: &Path) -> Option<Self> {
+        let json = fs::read_to_string(file).ok()?;
+        serde_json::from_str(&json).ok()
+    }
+
+    fn save(&self, file: &Path) -> Result<(), String> {
+        fs::create_dir_all(file.parent().unwrap()).map_err(|e| e.to_string())?;
+        fs::write(file, serde_json::to_string(self).unwrap()).map_err(|e| e.to_string())
+    }
+
+    fn chunk_count(&self) -> u64 {
+        self.size.div_ceil(self.chunk_size)
+    }
+}
+
+fn upload_sessions_dir(ctx: &SyncContext) -> PathBuf {
+    ctx.data_dir.join("uploads")
+}
+
+/// Records the transfer and tells the UI about it.
+fn report(events: &dyn EventSink, key: PathBuf, transfer: Transfer) {
+    events.emit("transfer", serde_json::to_value(&transfer).unwrap());
+    TRANSFERS.lock().unwrap().insert(key, transfer);
+}
+
+/// Reports a change of state, keeping the progress of an unfinished transfer.
+fn report_state(events: &dyn EventSink, r#type: TransferType, path: &str, state: TransferState) {
+    let key = PathBuf::from(path);
+    let progress = match state {
+        TransferState::Completed => 100,
+        TransferState::Failed { .. } | TransferState::Cancelled => 0,
+        _ => TRANSFERS
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|transfer| !transfer.state.is_finished())
+            .map_or(0, |transfer| transfer.progress),
+    };
+    let transfer = Transfer {
+        r#type,
+        state,
+        progress,
+        path: path.to_string(),
+    };
+    report(events, key, transfer);
+}
+
+/// What the upload of `path` is known as in the transfers. The absolute path,
+/// as for downloads, tells apart files at the same place in different pairs.
+fn upload_path(ctx: &SyncContext, path: &str) -> String {
+    ctx.root_path.join(path).to_string_lossy().to_string()
+}
+
+/// Marks an upload that hasn't finished as cancelled, its file went away.
+pub(crate) fn cancel_upload(ctx: &SyncContext, path: &str) {
+    let path = upload_path(ctx, path);
+    let unfinished = TRANSFERS
+        .lock()
+        .unwrap()
+        .get(&PathBuf::from(&path))
+        .is_some_and(|transfer| !transfer.state.is_finished());
+    if unfinished {
+        report_state(
+            ctx.events.as_ref(),
+            TransferType::Upload,
+            &path,
+            TransferState::Cancelled,
+        );
+    }
+}
+
+/// Waits for the scheduler to let the transfer run, showing it as queued
+/// meanwhile.
+async fn wait_turn(
+    ctx: &SyncContext,
+    r#type: TransferType,
+    size: u64,
+    set_state: impl Fn(TransferState),
+) -> Result<Permit, TransferError> {
+    let permit = match ctx.scheduler.try_acquire(r#type.clone()) {
+        Some(permit) => permit,
+        None => {
+            set_state(TransferState::Queued);
+            tokio::select! {
+                permit = ctx.scheduler.acquire(r#type, size) => permit,
+                _ = ctx.cancel.cancelled() => return Err(TransferError::stopped()),
+            }
+        }
+    };
+    set_state(TransferState::Active);
+    Ok(permit)
+}
+
+/// Sleeps before another attempt, unless the synchronizer stops first.
+async fn wait_to_retry(
+    ctx: &SyncContext,
+    policy: &RetryPolicy,
+    attempt: u32,
+) -> Result<(), TransferError> {
+    tokio::select! {
+        _ = tokio::time::sleep(retry_delay(policy, attempt)) => Ok(()),
+        _ = ctx.cancel.cancelled() => Err(TransferError::stopped()),
+    }
+}
+
+/// Why a transfer stopped short.
+#[derive(Debug)]
+pub enum TransferError {
+    /// The server couldn't be reached or isn't taking requests right now, it
+    /// goes on once it is.
+    Offline(String),
+    /// Anything else, counted against the retry policy.
+    Failed(String),
+}
+
+impl TransferError {
+    /// The synchronizer is stopping, the transfer goes on after it restarts.
+    fn stopped() -> Self {
+        TransferError::Offline("sync stopped".to_string())
+    }
+
+    fn backend(context: &str, e: BackendError) -> Self {
+        let reason = format!("{context}: {e}");
+        match e {
+            BackendError::Transport(_) | BackendError::Unauthorized => {
+                TransferError::Offline(reason)
+            }
+            BackendError::Status(status) if status >= 500 => TransferError::Offline(reason),
+            _ => TransferError::Failed(reason),
+        }
+    }
+}
+
+impl From<String> for TransferError {
+    fn from(reason: String) -> Self {
+        TransferError::Failed(reason)
+    }
+}
+
+/// What it takes to start a failed transfer over.
+enum Retry {
+    Upload {
+        path: String,
+    },
+    Download {
+        path: String,
+        id: String,
+        hash: String,
+        size: u64,
+    },
+}
+
+/// Failed transfers by `Transfer::path`, for `retry`.
+static FAILED: LazyLock<Mutex<HashMap<String, Retry>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The initial delay doubled for every attempt after the first, up to the maximum.
+fn retry_delay(policy: &RetryPolicy, attempt: u32) -> Duration {
+    let delay = policy
+        .initial_delay_secs
+        .saturating_mul(2u64.saturating_pow(attempt.saturating_sub(1)));
+    Duration::from_secs(delay.min(policy.max_delay_secs))
+}
+
+/// Whether `path`, as in its `Transfer`, failed and can be retried.
+pub fn can_retry(path: &str) -> bool {
+    FAILED.lock().unwrap().contains_key(path)
+}
+
+/// Starts a failed transfer over: uploads go back in the journal, downloads
+/// run again right away.
+pub(crate) async fn retry(ctx: &SyncContext, path: &str) {
+    let Some(retry) = FAILED.lock().unwrap().remove(path) else {
+        return;
+    };
+    match retry {
+        Retry::Upload { path } => {
+            let id = ctx
+                .tree
+                .lock()
+                .unwrap()
+                .find(&path)
+                .map(|node| node.id.lock().unwrap().clone());
+            match id {
+                Some(id) => {
+                    report_state(
+                        ctx.events.as_ref(),
+                        TransferType::Upload,
+                        &upload_path(ctx, &path),
+                        TransferState::Queued,
+                    );
+                    journal::enqueue(ctx, Operation::Upload { path }, id.as_deref());
+                }
+                None => cancel_upload(ctx, &path),
+            }
+        }
+        Retry::Download {
+            path,
+            id,
+            hash,
+            size,
+        } => download(ctx, path, Arc::new(Mutex::new(Some(id))), hash, size).await,
+    }
+}
+
+/// Uploads the file at `destination` and stores its new id in `id`, retrying
+/// under the configured policy. Uploads are queued in the journal, which
+/// calls this.
+pub async fn upload(
+    ctx: &SyncContext,
+    id: Arc<Mutex<Option<String>>>,
+    parent_id: Arc<Mutex<Option<String>>>,
+    destination: &str,
+) -> Result<(), TransferError> {
+    let file_id = id.lock().unwrap().clone();
+    let parent_id = parent_id.lock().unwrap().clone();
+
+    let absolute_path = ctx.root_path.join(destination);
+    let transfer_path = upload_path(ctx, destination);
+    println!("Uploading {:?}", absolute_path);
+
+    let events = &ctx.events;
+    let policy = CONFIG.lock().unwrap().retry.clone();
+    let set_state =
+        |state| report_state(events.as_ref(), TransferType::Upload, &transfer_path, state);
+    let size = fs::metadata(&absolute_path).map_or(0, |metadata| metadata.len());
+    let mut attempt = 1;
+    let new_id = loop {
+        let permit = match wait_turn(ctx, TransferType::Upload, size, set_state).await {
+            Ok(permit) => permit,
+            Err(e) => {
+                set_state(TransferState::Queued);
+                return Err(e);
+            }
+        };
+        let resp = upload_chunks(
+            ctx,
+            &absolute_path,
+            destination,
+            file_id.clone(),
+            parent_id.clone(),
+        )
+        .await;
+        // waiting to retry leaves the slot to others
+        drop(permit);
+        match resp {
+            Ok(new_id) => break new_id,
+            Err(TransferError::Failed(reason)) if attempt < policy.max_attempts => {
+                println!("Upload of {destination} failed, retrying: {reason}");
+                set_state(TransferState::Retrying { attempt });
+                if let Err(e) = wait_to_retry(ctx, &policy, attempt).await {
+                    set_state(TransferState::Queued);
+                    return Err(e);
+                }
+                attempt += 1;
+            }
+            Err(TransferError::Failed(reason)) => {
+                println!("Upload of {destination} failed: {reason}");
+                FAILED.lock().unwrap().insert(
+                    transfer_path.clone(),
+                    Retry::Upload {
+                        path: destination.to_string(),
+                    },
+                );
+                set_state(TransferState::Failed {
+                    reason: reason.clone(),
+                });
+                return Err(TransferError::Failed(reason));
+            }
+            Err(TransferError::Offline(reason)) => {
+                println!("Upload of {destination} interrupted, it will resume later: {reason}");
+                set_state(TransferState::Queued);
+                return Err(TransferError::Offline(reason));
+            }
+        }
+    };
+    set_state(TransferState::Completed);
+    id.lock().unwrap().replace(new_id);
+    println!("id is {}", id.lock().unwrap().clone().unwrap());
+    Ok(())
+}
+
+/// Sends the file in numbered chunks, skipping the ones a previous attempt already
+/// delivered, and returns the id of the uploaded file.
+async fn upload_chunks(
+    ctx: &SyncContext,
+    absolute_path: &Path,
+    destination: &str,
+    file_id: Option<String>,
+    parent_id: Option<String>,
+) -> Result<String, TransferError> {
+    let mut file = tokio::fs::File::open(absolute_path)
+        .await
+        .map_err(|e| format!("can't open file: {e}"))?;
+    let size = file.metadata().await.map_err(|e| e.to_string())?.len();
+    let path = absolute_path.to_path_buf();
+    // reading through a large file would hold up the runtime
+    let hash = tokio::task::spawn_blocking(move || fstree::hash_file(&path))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    let backend = ctx.backend.as_ref();
+    let events = &ctx.events;
+    let session_file = upload_sessions_dir(ctx).join(fstree::hash_bytes(destination.as_bytes()));
+    // the server dropped the session, start over next time
+    let expired = |e: BackendError| {
+        if let BackendError::NotFound = e {
+            let _ = fs::remove_file(&session_file);
+        }
+        e
+    };
+
+    let mut session = match UploadSession::load(&session_file) {
+        Some(session) if session.hash == hash && session.size == size => session,
+        _ => {
+            let file_name = absolute_path.file_name().unwrap().to_str().unwrap();
+            let session_id = backend
+                .start_upload(&NewUpload {
+                    file_name: file_name.to_string(),
+                    size,
+                    hash: hash.clone(),
+                    chunk_size: CHUNK_SIZE,
+                    element_id: file_id,
+                    parent_id,
+                })
+                .await
+                .map_err(|e| TransferError::backend("failed to start session", e))?;
+            UploadSession {
+                session_id,
+                path: destination.to_string(),
+                hash,
+                size,
+                chunk_size: CHUNK_SIZE,
+                uploaded: BTreeSet::new(),
+            }
+        }
+    };
+    session.save(&session_file)?;
+    let session_id = session.session_id.clone();
+
+    for index in 0..session.chunk_count() {
+        if session.uploaded.contains(&index) {
+            continue;
+        }
+        // the chunks sent so far stay in the session
+        if !ctx.proceed().await {
+            return Err(TransferError::stopped());
+        }
+        let offset = index * session.chunk_size;
+        let mut chunk = vec![0; session.chunk_size.min(size - offset) as usize];
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|e| e.to_string())?;
+        file.read_exact(&mut chunk)
+            .await
+            .map_err(|e| e.to_string())?;
+        let chunk_hash = fstree::hash_bytes(&chunk);
+        let chunk_len = chunk.len() as u64;
+
+        let _destination = upload_path(ctx, destination);
+        let _events = events.clone();
+        let mut total = session.uploaded.len() as u64 * session.chunk_size;
+        let byte_stream = ReaderStream::new(Cursor::new(chunk)).inspect_ok(move |bytes| {
+            total += bytes.len() as u64;
+            let progress = ((total as f64 / size as f64) * 100.0) as u8;
+            report(
+                _events.as_ref(),
+                _destination.clone().into(),
+                Transfer {
+                    progress: progress as u32,
+                    state: TransferState::Active,
+                    r#type: TransferType::Upload,
+                    path: _destination.clone(),
+                },
+            );
+        });
+        backend
+            .upload_chunk(
+                &session_id,
+                index,
+                &chunk_hash,
+                chunk_len,
+                byte_stream.boxed(),
+            )
+            .await
+            .map_err(|e| TransferError::backend(&format!("chunk {index} rejected"), expired(e)))?;
+        session.uploaded.insert(index);
+        session.save(&session_file)?;
+    }
+
+    let id = backend
+        .finish_upload(&session_id)
+        .await
+        .map_err(|e| TransferError::backend("failed to complete upload", expired(e)))?;
+    let _ = fs::remove_file(&session_file);
+    Ok(id)
+}
+
+/// Queues uploads left unfinished by a crash, they continue from the chunks
+/// already sent.
+pub fn resume_uploads(ctx: &SyncContext) {
+    let Ok(entries) = fs::read_dir(upload_sessions_dir(ctx)) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Some(session) = UploadSession::load(&entry.path()) else {
+            continue;
+        };
+        let id = ctx
+            .tree
+            .lock()
+            .unwrap()
+            .find(&session.path)
+            .map(|node| node.id.lock().unwrap().clone());
+        match id {
+            Some(id) => {
+                let upload = Operation::Upload { path: session.path };
+                journal::enqueue(ctx, upload, id.as_deref());
+            }
+            // the file is gone, nothing left to resume
+            None => {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+/// Downloads with a task running, waiting for their turn included.
+static DOWNLOADING: LazyLock<Mutex<HashSet<PathBuf>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Takes a download off `DOWNLOADING` when its task ends.
+struct Downloading(PathBuf);
+
+impl Drop for Downloading {
+    fn drop(&mut self) {
+        DOWNLOADING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Downloads the `size` bytes of the remote file `id` to `path`, when the
+/// scheduler lets it.
+pub async fn download(
+    ctx: &SyncContext,
+    path: String,
+    id: Arc<Mutex<Option<String>>>,
+    hash: String,
+    size: u64,
+) {
+    let id = id.lock().unwrap().clone();
+    let temp_dir = ctx.data_dir.join("temp");
+    fs::create_dir_all(&temp_dir).unwrap();
+    let mut bytes = path.as_bytes().to_vec();
+    bytes.extend(hash.as_bytes());
+    let temp_name = fstree::hash_bytes(&bytes);
+    let temp_file_path = temp_dir.join(&temp_name);
+    // the validator of the partial temp file, to make sure a resumed range is the same file
+    let etag_path = temp_dir.join(format!("{temp_name}.etag"));
+    println!("Downloading {} to {}", path, temp_file_path.display());
+    let Some(id) = id else {
+        return;
+    };
+    let destination = ctx.root_path.join(&path);
+
+    let transfer_path = destination.to_string_lossy().to_string();
+    if !DOWNLOADING.lock().unwrap().insert(destination.clone()) {
+        return;
+    }
+    let _downloading = Downloading(destination.clone());
+
+    let events = ctx.events.as_ref();
+    let policy = CONFIG.lock().unwrap().retry.clone();
+    let set_state = |state| report_state(events, TransferType::Download, &transfer_path, state);
+    let fetch = || fetch_to_temp(ctx, &id, &temp_file_path, &etag_path, &destination);
+    let mut attempt = 1;
+    let result = loop {
+        let permit = match wait_turn(ctx, TransferType::Download, size, set_state).await {
+            Ok(permit) => permit,
+            Err(e) => break Err(e),
+        };
+        let mut downloaded_hash = fetch().await;
+        if downloaded_hash.as_ref().is_ok_and(|h| *h != hash) {
+            // the partial file may have been stale or corrupt, start over once
+            println!("Hash mismatch for {path}, downloading again");
+            let _ = fs::remove_file(&temp_file_path);
+            let _ = fs::remove_file(&etag_path);
+            downloaded_hash = fetch().await;
+        }
+        drop(permit);
+        let error = match downloaded_hash {
+            Ok(downloaded_hash) if downloaded_hash == hash => break Ok(()),
+            Ok(_) => {
+                let quarantine_dir = ctx.data_dir.join("quarantine");
+                println!(
+                    "Hash mismatch for {path}, moving it to {}",
+                    quarantine_dir.display()
+                );
+                let _ = fs::create_dir_all(&quarantine_dir);
+                let _ = fs::rename(&temp_file_path, quarantine_dir.join(&temp_name));
+                let _ = fs::remove_file(&etag_path);
+                TransferError::Failed("the downloaded file doesn't match its hash".to_string())
+            }
+            Err(e) => e,
+        };
+        match error {
+            TransferError::Failed(reason) if attempt < policy.max_attempts => {
+                println!("Download of {path} failed, retrying: {reason}");
+                set_state(TransferState::Retrying { attempt });
+                if let Err(e) = wait_to_retry(ctx, &policy, attempt).await {
+                    break Err(e);
+                }
+                attempt += 1;
+            }
+            error => break Err(error),
+        }
+    };
+    // the temp file and its etag stay until it's in place, a retry starts from them
+    let result = result.and_then(|()| {
+        destination
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| replace_atomically(&temp_file_path, &destination))
+            .map_err(|e| TransferError::Failed(format!("can't move the download into place: {e}")))
+    });
+    match result {
+        Ok(()) => {}
+        // keep the partial file, catching up after reconnecting resumes from it
+        Err(TransferError::Offline(reason)) => {
+            println!("Download of {path} interrupted, it will resume later: {reason}");
+            set_state(TransferState::Queued);
+            return;
+        }
+        Err(TransferError::Failed(reason)) => {
+            println!("Download of {path} failed: {reason}");
+            FAILED.lock().unwrap().insert(
+                transfer_path.clone(),
+                Retry::Download {
+                    path,
+                    id,
+                    hash,
+                    size,
+                },
+            );
+            set_state(TransferState::Failed { reason });
+            return;
+        }
+    }
+
+    let _ = fs::remove_file(&temp_file_path);
+    let _ = fs::remove_file(&etag_path);
+    // Add to local tree
+    if let Ok(node) = fstree::build_node(&ctx.root_path, &destination, &ctx.rules) {
+        let mut tree = ctx.tree.lock().unwrap();
+        tree.add_node(node).unwrap();
+        if let Some(node) = tree.find(&path) {
+            *node.id.lock().unwrap() = Some(id);
+        }
+        ctx.index.put(&tree, &path, Status::Synced).unwrap();
+    }
+
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+
+    set_state(TransferState::Completed);
+}
+
+/// Downloads into the temp file, resuming a partial one if the server allows it,
+/// and returns the SHA-256 of the complete file.
+async fn fetch_to_temp(
+    ctx: &SyncContext,
+    id: &str,
+    temp_file_path: &Path,
+    etag_path: &Path,
+    destination: &Path,
+) -> Result<String, TransferError> {
+    let partial_size = fs::metadata(temp_file_path).map_or(0, |metadata| metadata.len());
+    let etag = fs::read_to_string(etag_path).ok();
+    let download = ctx
+        .backend
+        .download(id, partial_size, etag.as_deref())
+        .await
+        .map_err(|e| TransferError::backend("failed to start download", e))?;
+
+    let offset = if download.resumed { partial_size } else { 0 };
+    let total_size = offset + download.size;
+    match download.etag {
+        Some(etag) => fs::write(etag_path, etag)
+            .map_err(|e| format!("can't save the download's etag: {e}"))?,
+        None => {
+            let _ = fs::remove_file(etag_path);
+        }
+    }
+
+    let (mut file, mut hasher) = if download.resumed {
+        let path = temp_file_path.to_path_buf();
+        // reading through a large file would hold up the runtime
+        let hasher = tokio::task::spawn_blocking(move || {
+            let mut hasher = Sha256::new();
+            fs::File::open(path)
+                .and_then(|mut partial| std::io::copy(&mut partial, &mut hasher))
+                .map(|_| hasher)
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("can't read the partial download: {e}"))?;
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .open(temp_file_path)
+            .map_err(|e| format!("can't open the partial download: {e}"))?;
+        (file, hasher)
+    } else {
+        let file = fs::File::create(temp_file_path)
+            .map_err(|e| format!("can't create the temp file: {e}"))?;
+        (file, Sha256::new())
+    };
+    let mut downloaded: u64 = offset;
+    let mut stream = download.body;
+
+    while let Some(chunk_result) = stream.next().await {
+        // stops reading, so the server holds off too
+        if !ctx.proceed().await {
+            return Err(TransferError::stopped());
+        }
+        let chunk = chunk_result.map_err(|e| TransferError::backend("download interrupted", e))?;
+        file.write_all(&chunk)
+            .map_err(|e| format!("can't write the download: {e}"))?;
+        hasher.update(&chunk);
+        downloaded += chunk.len() as u64;
+
+        let progress = (downloaded as f64 / total_size as f64) * 100.0;
+
+        let transfer = Transfer {
+            progress: progress as u32,
+            state: TransferState::Active,
+            r#type: TransferType::Download,
+            path: destination.to_string_lossy().to_string(),
+        };
+        report(ctx.events.as_ref(), destination.to_path_buf(), transfer);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Copies `source` next to `destination` and renames it over, so a crash never
+/// leaves a truncated file behind.
+fn replace_atomically(source: &Path, destination: &Path) -> std::io::Result<()> {
+    let file_name = destination.file_name().unwrap().to_string_lossy();
+    let staging = destination.with_file_name(format!(".{file_name}{DOWNLOAD_SUFFIX}"));
+    let result = fs::copy(source, &staging)
+        .and_then(|_| fs::File::open(&staging)?.sync_all())
+        .and_then(|_| fs::rename(&staging, destination));
+    if result.is_err() {
+        let _ = fs::remove_file(&staging);
+    }
+    result
+}
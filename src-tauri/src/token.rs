@@ -1,104 +1,139 @@
 use std::{
-    sync::Mutex,
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
     time::{Duration, SystemTime},
 };
 use tauri::AppHandle;
 use tokio::sync::watch;
 
-use crate::{logout, types::Token, update_config, CONFIG};
+use crate::{logout, save_profile, start_sync};
+use file_transfer::synchronizer::{
+    self,
+    api::HttpBackend,
+    backend::{BackendError, BackendResult, RemoteBackend, Tokens},
+};
+use file_transfer::{
+    types::{Profile, Token},
+    CONFIG,
+};
 const REFRESH_TOKEN_EXPIRES: u64 = 24 * 60 * 60; // 1day
 const TOKEN_EXPIRES: u64 = 15 * 60; // half hour
-static TOKEN_WATCH_STOP: Mutex<Option<watch::Sender<bool>>> = Mutex::new(None);
+/// How long to wait before trying again to renew a token, doubling each time.
+const RETRY_DELAY_BASE: Duration = Duration::from_secs(30);
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(5 * 60);
+/// Stops the token refresher of each profile, by profile name.
+static TOKEN_WATCH_STOP: LazyLock<Mutex<HashMap<String, watch::Sender<bool>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 
-pub fn stop() {
-    if let Some(sender) = TOKEN_WATCH_STOP.lock().unwrap().as_mut() {
+pub fn stop(profile: &str) {
+    if let Some(sender) = TOKEN_WATCH_STOP.lock().unwrap().remove(profile) {
         let _ = sender.send(true);
     }
 }
-pub async fn watch_tokens(app: AppHandle) {
+
+pub fn is_watching(profile: &str) -> bool {
+    TOKEN_WATCH_STOP
+        .lock()
+        .unwrap()
+        .get(profile)
+        .is_some_and(|sender| !sender.is_closed())
+}
+
+/// Refreshes the session when possible, logs in again otherwise.
+async fn renew(profile: &Profile, refresh_token: &str) -> BackendResult<Tokens> {
+    let backend = HttpBackend::from_profile(profile);
+    if let Ok(tokens) = backend.refresh_auth(refresh_token).await {
+        return Ok(tokens);
+    }
+    let (Some(username), Some(password)) = (&profile.username, &profile.password) else {
+        return Err(BackendError::Unauthorized);
+    };
+    backend.auth(username, password).await
+}
+
+/// Keeps the tokens of `profile` fresh, handing each new one to its
+/// synchronizers, and logs out of it once the refresh token expires.
+pub async fn watch_tokens(app: AppHandle, profile: String) {
     let (tx, mut rx) = watch::channel(false);
-    if let Some(sender) = TOKEN_WATCH_STOP.lock().unwrap().as_mut() {
+    if let Some(sender) = TOKEN_WATCH_STOP.lock().unwrap().insert(profile.clone(), tx) {
         let _ = sender.send(true);
     }
-    TOKEN_WATCH_STOP.lock().unwrap().replace(tx);
+    let saved_refresh_token = |profile: &str| {
+        CONFIG
+            .lock()
+            .unwrap()
+            .profile(profile)
+            .and_then(|profile| profile.refresh_token)
+    };
     let _app = app.clone();
+    let _profile = profile.clone();
     let refresh_token_task = async move {
         loop {
-            let refresh_token = { CONFIG.lock().unwrap().refresh_token.clone() };
-            let elsaped_time = refresh_token
-                .unwrap()
-                .created_at
-                .elapsed()
-                .unwrap()
-                .as_secs();
+            let Some(refresh_token) = saved_refresh_token(&_profile) else {
+                return;
+            };
+            let elsaped_time = refresh_token.created_at.elapsed().unwrap().as_secs();
             let sleep_time = REFRESH_TOKEN_EXPIRES.saturating_sub(elsaped_time);
             tokio::time::sleep(Duration::from_secs(sleep_time)).await;
-            let refresh_token = { CONFIG.lock().unwrap().refresh_token.clone() };
-            let elsaped_time = refresh_token
-                .unwrap()
-                .created_at
-                .elapsed()
-                .unwrap()
-                .as_secs();
+            let Some(refresh_token) = saved_refresh_token(&_profile) else {
+                return;
+            };
+            let elsaped_time = refresh_token.created_at.elapsed().unwrap().as_secs();
             let sleep_time = REFRESH_TOKEN_EXPIRES.saturating_sub(elsaped_time);
             if sleep_time == 0 {
                 break;
             }
         }
-        logout(_app.clone()).await;
+        println!("Refresh token of {_profile} expired;logging out");
+        logout(_app.clone(), Some(_profile)).await;
     };
 
     let token_task = async move {
+        let mut retry_delay = RETRY_DELAY_BASE;
         loop {
-            let mut config = { CONFIG.lock().unwrap().clone() };
-            if config.token.is_none() || config.refresh_token.is_none() {
+            let Some(saved) = CONFIG.lock().unwrap().profile(&profile) else {
                 break;
-            }
-            let token = config.token.as_ref().unwrap();
-            let refresh_token = config.refresh_token.as_ref().unwrap();
+            };
+            let (Some(token), Some(refresh_token)) = (saved.token, saved.refresh_token) else {
+                break;
+            };
             let elsaped_time = token.created_at.elapsed().unwrap().as_secs();
             let sleep_time = TOKEN_EXPIRES.saturating_sub(elsaped_time);
             tokio::time::sleep(Duration::from_secs(sleep_time)).await;
-            println!("REFRESHING TOKEN");
+            println!("REFRESHING TOKEN of {profile}");
 
-            let client = reqwest::Client::new();
-
-            let server = config.server_url.to_owned();
-            let resp = client
-                .post(format!("{server}/users/auth/keep-alive"))
-                .header("x-refresh-token", &refresh_token.value)
-                .send()
-                .await
-                .unwrap();
-            let token = resp
-                .headers()
-                .get("authorization")
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-            let refresh_token = resp
-                .headers()
-                .get("x-refresh-token")
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
+            // the settings may have changed meanwhile
+            let Some(mut saved) = CONFIG.lock().unwrap().profile(&profile) else {
+                break;
+            };
+            let tokens = match renew(&saved, &refresh_token.value).await {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    println!("Failed to refresh the token of {profile}: {e}");
+                    tokio::time::sleep(retry_delay).await;
+                    retry_delay = (retry_delay * 2).min(RETRY_DELAY_MAX);
+                    continue;
+                }
+            };
+            retry_delay = RETRY_DELAY_BASE;
 
-            config.token.replace(Token {
-                value: token,
+            saved.token.replace(Token {
+                value: tokens.token.clone(),
                 created_at: SystemTime::now(),
             });
 
-            config.refresh_token.replace(Token {
-                value: refresh_token,
+            saved.refresh_token.replace(Token {
+                value: tokens.refresh_token,
                 created_at: SystemTime::now(),
             });
 
-            let _ = update_config(app.clone(), config, true).await;
+            save_profile(&app, saved);
+            if !synchronizer::set_token(&profile, &tokens.token) {
+                start_sync(app.clone(), &profile);
+            }
         }
     };
     tokio::select! {_ = rx.changed()=>{},
-    _ = refresh_token_task=>{println!("Refresh token expired;logging out")},
+    _ = refresh_token_task=>{},
     _= token_task=>{}};
 }
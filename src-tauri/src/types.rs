@@ -1,6 +1,5 @@
 use std::time::SystemTime;
 
-use crate::synchronizer::fstree;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -18,6 +17,144 @@ pub struct Config {
     pub token: Option<Token>,
     pub refresh_token: Option<Token>,
     pub is_configured: bool,
+    #[serde(default = "default_ignore_patterns")]
+    pub ignore_patterns: Vec<String>,
+    /// PEM file with extra CAs to trust, for servers behind a private CA.
+    #[serde(default)]
+    pub ca_bundle: Option<String>,
+    /// Skips certificate validation, only for test servers with self-signed
+    /// certificates.
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub transfers: TransferLimits,
+    #[serde(default)]
+    pub bandwidth: BandwidthLimits,
+    /// Sync stays paused across restarts until resumed.
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub selective_sync: SelectiveSync,
+    /// The folders synced, each on its own. None for configs from before
+    /// there could be several, which sync `folder_path` as in `pairs`.
+    #[serde(default)]
+    pub sync_pairs: Option<Vec<SyncPair>>,
+    /// Name of the profile the server, login and folders above make up.
+    #[serde(default = "default_profile_name")]
+    pub profile_name: String,
+    #[serde(default = "default_true")]
+    pub profile_active: bool,
+    /// Other servers synced alongside, each with its own login and folders.
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+}
+
+/// Id of the pair `folder_path` becomes, its state stays where it was.
+pub const DEFAULT_PAIR_ID: &str = "default";
+pub const DEFAULT_PROFILE_NAME: &str = "Default";
+
+impl Config {
+    /// The folders to sync, `folder_path` with `selective_sync` when there is
+    /// no list yet.
+    pub fn pairs(&self) -> Vec<SyncPair> {
+        match &self.sync_pairs {
+            Some(pairs) => pairs.clone(),
+            None if self.folder_path.is_empty() => vec![],
+            None => vec![SyncPair {
+                id: DEFAULT_PAIR_ID.to_string(),
+                local_path: self.folder_path.clone(),
+                remote_folder_id: None,
+                remote_path: String::new(),
+                selective_sync: self.selective_sync.clone(),
+            }],
+        }
+    }
+
+    /// The profile made of the top-level fields.
+    pub fn main_profile(&self) -> Profile {
+        Profile {
+            name: self.profile_name.clone(),
+            server_url: self.server_url.clone(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            token: self.token.clone(),
+            refresh_token: self.refresh_token.clone(),
+            ca_bundle: self.ca_bundle.clone(),
+            accept_invalid_certs: self.accept_invalid_certs,
+            active: self.profile_active,
+            sync_pairs: self.pairs(),
+        }
+    }
+
+    /// Every profile, the main one first.
+    pub fn profiles(&self) -> Vec<Profile> {
+        std::iter::once(self.main_profile())
+            .chain(self.profiles.iter().cloned())
+            .collect()
+    }
+
+    pub fn profile(&self, name: &str) -> Option<Profile> {
+        self.profiles()
+            .into_iter()
+            .find(|profile| profile.name == name)
+    }
+
+    /// Writes back a profile by its name, into the top-level fields for the
+    /// main one. False when there is no profile by that name.
+    pub fn set_profile(&mut self, profile: Profile) -> bool {
+        if profile.name == self.profile_name {
+            self.server_url = profile.server_url;
+            self.username = profile.username;
+            self.password = profile.password;
+            self.token = profile.token;
+            self.refresh_token = profile.refresh_token;
+            self.ca_bundle = profile.ca_bundle;
+            self.accept_invalid_certs = profile.accept_invalid_certs;
+            self.profile_active = profile.active;
+            self.sync_pairs = Some(profile.sync_pairs);
+            return true;
+        }
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(saved) => {
+                *saved = profile;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The sync pairs of all profiles, active or not.
+    pub fn all_pairs(&self) -> Vec<SyncPair> {
+        self.profiles()
+            .into_iter()
+            .flat_map(|profile| profile.sync_pairs)
+            .collect()
+    }
+}
+
+fn default_profile_name() -> String {
+    DEFAULT_PROFILE_NAME.to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_ignore_patterns() -> Vec<String> {
+    [
+        ".git/",
+        "node_modules/",
+        ".DS_Store",
+        "Thumbs.db",
+        "*.swp",
+        "*~",
+        "~$*",
+    ]
+    .iter()
+    .map(|pattern| pattern.to_string())
+    .collect()
 }
 impl Default for Config {
     fn default() -> Self {
@@ -29,6 +166,18 @@ impl Default for Config {
             token: None,
             refresh_token: None,
             is_configured: false,
+            ignore_patterns: default_ignore_patterns(),
+            ca_bundle: None,
+            accept_invalid_certs: false,
+            retry: RetryPolicy::default(),
+            transfers: TransferLimits::default(),
+            bandwidth: BandwidthLimits::default(),
+            paused: false,
+            selective_sync: SelectiveSync::default(),
+            sync_pairs: None,
+            profile_name: default_profile_name(),
+            profile_active: true,
+            profiles: vec![],
         }
     }
 }
@@ -36,6 +185,7 @@ impl Default for Config {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Transfer {
     pub r#type: TransferType,
+    #[serde(flatten)]
     pub state: TransferState,
     pub progress: u32,
     pub path: String,
@@ -46,17 +196,207 @@ pub enum TransferType {
     Upload,
     Download,
 }
+/// Flattened into `Transfer` as a "state" field, plus "reason" or "attempt".
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
-#[serde(rename_all = "lowercase")]
+#[serde(tag = "state", rename_all = "lowercase")]
 pub enum TransferState {
+    /// Waiting for its turn, or for the server to be reachable again.
+    Queued,
     Active,
+    /// Failed and waiting out the backoff before the next attempt.
+    Retrying {
+        attempt: u32,
+    },
+    /// Out of attempts, `retry_transfer` starts it over.
+    Failed {
+        reason: String,
+    },
+    /// Dropped because the file went away first.
+    Cancelled,
     Completed,
 }
 
+impl TransferState {
+    /// Nothing more will happen to it without the user.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            TransferState::Failed { .. } | TransferState::Cancelled | TransferState::Completed
+        )
+    }
+}
+
+/// How often and how patiently a failing transfer is retried.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetryPolicy {
+    /// Attempts in total, the first one included.
+    pub max_attempts: u32,
+    pub initial_delay_secs: u64,
+    pub max_delay_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay_secs: 2,
+            max_delay_secs: 300,
+        }
+    }
+}
+
+/// How many transfers run at once, the others wait queued.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TransferLimits {
+    pub max_uploads: usize,
+    pub max_downloads: usize,
+    /// Lets small files go ahead of large ones waiting in the queue, instead
+    /// of first come, first served.
+    pub small_files_first: bool,
+}
+
+impl Default for TransferLimits {
+    fn default() -> Self {
+        Self {
+            max_uploads: 3,
+            max_downloads: 4,
+            small_files_first: true,
+        }
+    }
+}
+
+/// Caps on the combined speed of all transfers, in KiB/s, none for unlimited.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BandwidthLimits {
+    pub upload_kib_per_sec: Option<u64>,
+    pub download_kib_per_sec: Option<u64>,
+    /// Other caps for part of the day, e.g. office hours. The first schedule
+    /// covering the current time replaces the caps above.
+    #[serde(default)]
+    pub schedules: Vec<BandwidthSchedule>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BandwidthSchedule {
+    /// Local time as "HH:MM". Ending before it starts runs past midnight.
+    pub start: String,
+    pub end: String,
+    pub upload_kib_per_sec: Option<u64>,
+    pub download_kib_per_sec: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    /// Lost the connection, or failed to get it back, and trying again.
+    Reconnecting,
+    /// Failed several times in a row, or stopped; retries continue with a
+    /// longer backoff while syncing.
+    Offline,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SocketResponse {
     pub message: String,
-    pub data: fstree::Node,
+    /// A `fstree::Node`, or a `backend::Delta` when `type` is "delta".
+    pub data: serde_json::Value,
+    /// Set on full trees by servers that also send deltas.
+    #[serde(default)]
+    pub cursor: Option<u64>,
     pub timestamp: u64,
     pub r#type: String,
 }
+
+/// Which remote folders are mirrored locally: all of them but the excluded
+/// ones, and the included ones inside those again. The deepest rule over a path
+/// wins. Paths are relative to the root, separated by "/".
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SelectiveSync {
+    pub included: Vec<String>,
+    pub excluded: Vec<String>,
+}
+
+/// A local folder kept in sync with a remote one, by a synchronizer of its own.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncPair {
+    /// Given when the pair is saved, names the pair's state in the data dir.
+    #[serde(default)]
+    pub id: String,
+    pub local_path: String,
+    /// None for the whole remote tree.
+    #[serde(default)]
+    pub remote_folder_id: Option<String>,
+    /// Where the remote folder was when chosen, for showing it.
+    #[serde(default)]
+    pub remote_path: String,
+    /// Paths are relative to the remote folder.
+    #[serde(default)]
+    pub selective_sync: SelectiveSync,
+}
+
+/// A server with its own login and folders, synced and logged in to on its
+/// own. Sync settings such as ignore patterns and limits are shared.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub server_url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub token: Option<Token>,
+    #[serde(default)]
+    pub refresh_token: Option<Token>,
+    #[serde(default)]
+    pub ca_bundle: Option<String>,
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    /// Inactive profiles are kept, but neither logged in nor synced.
+    #[serde(default = "default_true")]
+    pub active: bool,
+    #[serde(default)]
+    pub sync_pairs: Vec<SyncPair>,
+}
+
+impl Profile {
+    pub fn is_logged_in(&self) -> bool {
+        self.username.is_some()
+            && self.password.is_some()
+            && self.token.is_some()
+            && self.refresh_token.is_some()
+    }
+}
+
+/// A "profile_state" event, the best connection of a profile's sync pairs.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProfileState {
+    pub name: String,
+    pub state: ConnectionState,
+}
+
+/// A "pair_state" event, the connection of one sync pair.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PairState {
+    pub id: String,
+    pub state: ConnectionState,
+}
+
+/// A "pair_error" event, a sync pair that stopped or couldn't start.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PairError {
+    pub id: String,
+    pub message: String,
+}
+
+/// A remote folder with the folders in it, for choosing what to sync.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RemoteFolder {
+    pub id: String,
+    pub name: String,
+    /// Relative to the root, separated by "/".
+    pub path: String,
+    pub children: Vec<RemoteFolder>,
+}
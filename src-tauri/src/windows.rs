@@ -1,4 +1,4 @@
-use crate::CONFIG;
+use file_transfer::CONFIG;
 use tauri::{AppHandle, Manager};
 use tauri_plugin_positioner::{Position, WindowExt};
 #[tauri::command]
@@ -7,8 +7,8 @@ pub fn open_main_window(app: AppHandle) {
     if !config.is_configured {
         open_initial_configuration_window(app);
         return;
-    } else if config.username.is_none() || config.password.is_none() {
-        open_login_window(app);
+    } else if config.profile_active && (config.username.is_none() || config.password.is_none()) {
+        open_login_window(app, None);
         return;
     }
 
@@ -66,16 +66,25 @@ pub fn open_config_window(app: AppHandle) {
     });
 }
 
+/// Logs in to `profile`, the main one when None.
 #[tauri::command]
-pub fn open_login_window(app: AppHandle) {
+pub fn open_login_window(app: AppHandle, profile: Option<String>) {
     tauri::async_runtime::spawn(async move {
+        // one login at a time
         let login_window = app.get_window("Login");
         if login_window.is_some() {
             login_window.unwrap().set_focus().unwrap();
             return;
         }
-        tauri::WindowBuilder::new(&app, "Login", tauri::WindowUrl::App("/login".into()))
-            .title("Login")
+        let (url, title) = match profile {
+            Some(profile) => (
+                format!("/login?profile={}", urlencoding::encode(&profile)),
+                format!("Login: {profile}"),
+            ),
+            None => ("/login".to_string(), "Login".to_string()),
+        };
+        tauri::WindowBuilder::new(&app, "Login", tauri::WindowUrl::App(url.into()))
+            .title(title)
             .inner_size(600.0, 500.0)
             .min_inner_size(600.0, 500.0)
             .theme(Some(tauri::Theme::Light))
@@ -1,4 +1,4 @@
-import type { Config } from './types';
+import type { Config, ConnectionState } from './types';
 import { get_config } from './utils';
 import { invoke } from '@tauri-apps/api';
 
@@ -10,7 +10,43 @@ export let isConnected = {
 	set: (value: boolean) => (is_connected = value)
 };
 
+let connection_state = $state('connecting' as ConnectionState);
+
+export let connectionState = {
+	get: () => connection_state,
+	set: (value: ConnectionState) => (connection_state = value)
+};
+
+let pair_states = $state({} as { [id: string]: ConnectionState });
+
+export let pairStates = {
+	get: () => pair_states,
+	set: (id: string, value: ConnectionState) => (pair_states[id] = value)
+};
+
+let profile_states = $state({} as { [name: string]: ConnectionState });
+
+export let profileStates = {
+	get: () => profile_states,
+	set: (name: string, value: ConnectionState) => (profile_states[name] = value)
+};
+
+let sync_paused = $state(false);
+
+export let syncPaused = {
+	get: () => sync_paused,
+	set: (value: boolean) => (sync_paused = value)
+};
+
 invoke('check_connection').then((value) => (is_connected = value as boolean));
+invoke('get_connection_state').then((value) => (connection_state = value as ConnectionState));
+invoke('get_pair_states').then(
+	(value) => (pair_states = value as { [id: string]: ConnectionState })
+);
+invoke('get_profile_states').then(
+	(value) => (profile_states = value as { [name: string]: ConnectionState })
+);
+invoke('is_sync_paused').then((value) => (sync_paused = value as boolean));
 
 get_config().then((c) => {
 	for (const [key, value] of Object.entries(c as Config)) {
@@ -1,10 +1,17 @@
 export type Transfer = {
 	path: string;
 	progress: number;
-	state: 'active' | 'completed';
+	state: 'queued' | 'active' | 'retrying' | 'failed' | 'cancelled' | 'completed';
 	type: 'download' | 'upload';
+	// why a failed transfer failed
+	reason?: string;
+	// the attempt a retrying transfer is waiting after
+	attempt?: number;
 };
 
+// Nothing more happens to these without the user.
+export const finishedStates: Transfer['state'][] = ['failed', 'cancelled', 'completed'];
+
 export type Config = {
 	username: string;
 	password: string;
@@ -12,4 +19,103 @@ export type Config = {
 	folder_path: string;
 	token: string;
 	refresh_token: string;
+	ignore_patterns: string[];
+	ca_bundle: string | null;
+	accept_invalid_certs: boolean;
+	retry: RetryPolicy;
+	transfers: TransferLimits;
+	bandwidth: BandwidthLimits;
+	selective_sync: SelectiveSync;
+	sync_pairs: SyncPair[];
+	// the profile the server, login and folders above make up
+	profile_name: string;
+	profile_active: boolean;
+	// the other servers, each with its own login and folders
+	profiles: Profile[];
+};
+
+export type RetryPolicy = {
+	max_attempts: number;
+	initial_delay_secs: number;
+	max_delay_secs: number;
+};
+
+export type TransferLimits = {
+	max_uploads: number;
+	max_downloads: number;
+	small_files_first: boolean;
+};
+
+// KiB/s, null for unlimited
+export type BandwidthLimits = {
+	upload_kib_per_sec: number | null;
+	download_kib_per_sec: number | null;
+	schedules: BandwidthSchedule[];
+};
+
+// start and end are local times, "HH:MM"
+export type BandwidthSchedule = {
+	start: string;
+	end: string;
+	upload_kib_per_sec: number | null;
+	download_kib_per_sec: number | null;
+};
+
+// remote folder paths, "/"-separated; the deepest rule over a path wins
+export type SelectiveSync = {
+	included: string[];
+	excluded: string[];
+};
+
+// a local folder synced with a remote one, or with all files when remote_folder_id is null
+export type SyncPair = {
+	// empty until saved
+	id: string;
+	local_path: string;
+	remote_folder_id: string | null;
+	remote_path: string;
+	selective_sync: SelectiveSync;
+};
+
+// logged in to from the login window, on its own
+export type Profile = {
+	name: string;
+	server_url: string;
+	username: string | null;
+	password: string | null;
+	token: string | null;
+	refresh_token: string | null;
+	ca_bundle: string | null;
+	accept_invalid_certs: boolean;
+	// inactive profiles are neither logged in nor synced
+	active: boolean;
+	sync_pairs: SyncPair[];
+};
+
+export type ProfileState = {
+	name: string;
+	state: ConnectionState;
+};
+
+export type PairState = {
+	id: string;
+	state: ConnectionState;
+};
+
+export type RemoteFolder = {
+	id: string;
+	name: string;
+	path: string;
+	children: RemoteFolder[];
+};
+
+export type ConnectionState = 'connecting' | 'connected' | 'reconnecting' | 'offline';
+
+export type PendingOperation = {
+	// the sync pair it is queued in
+	pair: string;
+	seq: number;
+	op: 'mkdir' | 'upload' | 'rename' | 'delete';
+	path: string;
+	from?: string;
 };
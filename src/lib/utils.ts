@@ -1,18 +1,36 @@
 import { invoke } from '@tauri-apps/api';
 import { config } from './store.svelte';
+import type { RemoteFolder } from './types';
 
-export async function update_config() {
-	await invoke('update_config', { config, restart: true });
+// settings the synchronizer reads as it goes can skip the restart
+export async function update_config(restart = true) {
+	await invoke('update_config', { config, restart });
 }
 export async function get_config() {
 	return await invoke('get_config');
 }
+// folder paths are relative to the folder with folderId, or to the root; a null
+// profile is the main one
+export async function get_remote_folders(
+	folderId: string | null = null,
+	profile: string | null = null
+) {
+	return (await invoke('get_remote_folders', { profile, folderId })) as RemoteFolder[];
+}
 
-export async function login({ username, password }: { username: string; password: string }) {
-	return await invoke('login', { username, password });
+export async function login({
+	profile = null,
+	username,
+	password
+}: {
+	profile?: string | null;
+	username: string;
+	password: string;
+}) {
+	return await invoke('login', { profile, username, password });
 }
-export async function logout() {
-	return await invoke('logout');
+export async function logout(profile: string | null = null) {
+	return await invoke('logout', { profile });
 }
 export async function save_initial_config({
 	serverUrl,
@@ -25,8 +43,14 @@ export async function save_initial_config({
 }
 
 export async function open_folder() {
-	return await invoke('open_folder', { path: config.folder_path });
+	return await invoke('open_folder', { path: config.sync_pairs?.[0]?.local_path });
 }
 export async function force_sync() {
 	return await invoke('force_sync');
 }
+export async function pause_sync() {
+	return await invoke('pause_sync');
+}
+export async function resume_sync() {
+	return await invoke('resume_sync');
+}